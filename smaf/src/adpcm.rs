@@ -0,0 +1,115 @@
+// encode and decode yamaha adpcm-b
+// adapted to rust from https://github.com/superctr/adpcm/blob/master/ymb_codec.c (Unlicense)
+
+use alloc::vec::Vec;
+
+static STEP_TABLE: [u8; 8] = [57, 57, 57, 57, 77, 102, 128, 153];
+
+const SEARCH_LOOKAHEAD: usize = 4;
+
+#[derive(Clone, Copy)]
+struct DecodeContext {
+    history: i16,
+    step_size: u16,
+}
+
+impl Default for DecodeContext {
+    fn default() -> Self {
+        Self { history: 0, step_size: 127 }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum AdpcmQuality {
+    #[default]
+    Fast, // the nibble closest to each sample
+    Search, // the nibble giving the least error over the next few samples
+}
+
+fn ymb_step(step: u8, context: &mut DecodeContext) -> i16 {
+    let sign = step & 8;
+    let delta = step & 7;
+    let diff = ((1 + ((delta as u32) << 1)) * (context.step_size as u32)) >> 3;
+    let mut newval = context.history as i32;
+    let nstep = (((STEP_TABLE[delta as usize] as u32) * (context.step_size as u32)) >> 6) as u16;
+    if sign > 0 {
+        newval -= diff as i32;
+    } else {
+        newval += diff as i32;
+    }
+    context.step_size = u16::clamp(nstep, 127, 24576);
+    newval = i32::clamp(newval, -32768, 32767);
+    context.history = newval as i16;
+
+    newval as i16
+}
+
+// stereo data alternates left and right nibbles, each channel keeping its own state
+pub fn decode_adpcm(data: &[u8], channels: u8) -> Vec<i16> {
+    let mut result = Vec::with_capacity(data.len() * 2);
+    let mut contexts = [DecodeContext { history: 0, step_size: 127 }, DecodeContext { history: 0, step_size: 127 }];
+    let channels = (channels as usize).clamp(1, contexts.len());
+
+    for i in data {
+        for step in [i >> 4, i & 0x0f] {
+            let context = &mut contexts[result.len() % channels];
+            result.push(ymb_step(step, context));
+        }
+    }
+
+    result
+}
+
+// inverse of decode_adpcm, stereo samples are interleaved left first. an odd sample count is padded with a zero step.
+pub fn encode_adpcm(samples: &[i16], channels: u8, quality: AdpcmQuality) -> Vec<u8> {
+    let channels = (channels as usize).clamp(1, 2);
+    let mut contexts = [DecodeContext::default(); 2];
+    let mut nibbles = Vec::with_capacity(samples.len() + 1);
+
+    for (index, &sample) in samples.iter().enumerate() {
+        let channel = index % channels;
+        let context = &mut contexts[channel];
+        let nibble = match quality {
+            AdpcmQuality::Fast => ymb_encode_step(sample, context),
+            AdpcmQuality::Search => {
+                let upcoming = samples[index..].iter().step_by(channels).take(SEARCH_LOOKAHEAD);
+                search_step(upcoming, context)
+            }
+        };
+        ymb_step(nibble, context);
+        nibbles.push(nibble);
+    }
+    if nibbles.len() % 2 == 1 {
+        nibbles.push(0);
+    }
+
+    nibbles.chunks_exact(2).map(|x| (x[0] << 4) | x[1]).collect()
+}
+
+// the nibble whose decoded value is closest to the sample
+fn ymb_encode_step(sample: i16, context: &DecodeContext) -> u8 {
+    let diff = sample as i32 - context.history as i32;
+    let sign = if diff < 0 { 8 } else { 0 };
+    let delta = ((diff.unsigned_abs() << 2) / context.step_size as u32).min(7) as u8;
+
+    sign | delta
+}
+
+fn search_step<'a>(upcoming: impl Iterator<Item = &'a i16> + Clone, context: &DecodeContext) -> u8 {
+    let mut best = (u64::MAX, 0);
+    for nibble in 0..16 {
+        let mut context = *context;
+        let mut error = 0;
+        for (index, &sample) in upcoming.clone().enumerate() {
+            // the rest of the window follows the fast encoder
+            let step = if index == 0 { nibble } else { ymb_encode_step(sample, &context) };
+            let decoded = ymb_step(step, &mut context);
+            error += (decoded as i64 - sample as i64).unsigned_abs().pow(2);
+        }
+        if error < best.0 {
+            best = (error, nibble);
+        }
+    }
+
+    best.1
+}
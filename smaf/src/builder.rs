@@ -0,0 +1,410 @@
+use alloc::{format, vec::Vec};
+use core::mem;
+
+use crate::{
+    chunks::{
+        write_chunk, write_pcm_audio_track, write_score_track, ChannelStatus, ChannelType, PCMAudioSequenceData, PCMAudioSequenceEvent,
+        ScoreTrackSequenceEvent, SequenceData,
+    },
+    constants::{BaseBit, Channel, FormatType, PcmWaveFormat, SamplingRate},
+    smaf::write_smaf,
+    Result, SmafError,
+};
+
+const DEFAULT_CONTENTS_INFO: [u8; 5] = [0x00, 0x32, 0x01, 0x00, 0x00]; // class, type, code type, copy status, copy count
+const FIRST_HANDY_TRACK: u16 = 1;
+const FIRST_MOBILE_TRACK: u16 = 5;
+
+// fluent construction of smaf files. lengths, crc, channel status and end of sequence markers are filled in on build,
+// durations and gate times are in ticks of the track timebase. the first invalid value is reported by build.
+pub struct SmafBuilder {
+    contents_info: Vec<u8>,
+    tracks: Vec<u8>,
+    next_handy_track: u16,
+    next_mobile_track: u16,
+    next_pcm_track: u16,
+    error: Option<SmafError>,
+}
+
+impl Default for SmafBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SmafBuilder {
+    pub fn new() -> Self {
+        Self {
+            contents_info: DEFAULT_CONTENTS_INFO.to_vec(),
+            tracks: Vec::new(),
+            next_handy_track: FIRST_HANDY_TRACK,
+            next_mobile_track: FIRST_MOBILE_TRACK,
+            next_pcm_track: 0,
+            error: None,
+        }
+    }
+
+    pub fn contents_info(mut self, content_class: u8, content_type: u8, content_code_type: u8, copy_status: u8, copy_counts: u8) -> Self {
+        self.contents_info = Vec::from([content_class, content_type, content_code_type, copy_status, copy_counts]);
+        self
+    }
+
+    // handy phone standard tracks are numbered from 1, mobile standard tracks from 5
+    pub fn score_track(mut self, format_type: FormatType, timebase: u8, build: impl FnOnce(ScoreTrackBuilder) -> ScoreTrackBuilder) -> Self {
+        let track = build(ScoreTrackBuilder::new(format_type));
+        let number = match format_type {
+            FormatType::HandyPhoneStandard => &mut self.next_handy_track,
+            FormatType::MobileStandardCompress | FormatType::MobileStandardNoCompress => &mut self.next_mobile_track,
+        };
+        let track = take_track_number(number).and_then(|number| Ok((number, track.build(timebase)?)));
+
+        self.add_track(b"MTR", track)
+    }
+
+    pub fn pcm_track(
+        mut self,
+        format: PcmWaveFormat,
+        sampling_freq: SamplingRate,
+        base_bit: BaseBit,
+        timebase: u8,
+        build: impl FnOnce(PcmTrackBuilder) -> PcmTrackBuilder,
+    ) -> Self {
+        let track = build(PcmTrackBuilder::new());
+        let track =
+            take_track_number(&mut self.next_pcm_track).and_then(|number| Ok((number, track.build(format, sampling_freq, base_bit, timebase)?)));
+
+        self.add_track(b"ATR", track)
+    }
+
+    pub fn build(self) -> Result<Vec<u8>> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+
+        let mut chunks = Vec::new();
+        write_chunk(&mut chunks, b"CNTI", &self.contents_info);
+        chunks.extend_from_slice(&self.tracks);
+
+        Ok(write_smaf(&chunks))
+    }
+
+    fn add_track(mut self, tag: &[u8; 3], track: Result<(u8, Vec<u8>)>) -> Self {
+        match track {
+            Ok((number, data)) => write_chunk(&mut self.tracks, &[tag[0], tag[1], tag[2], number], &data),
+            Err(error) => {
+                self.error.get_or_insert(error);
+            }
+        }
+        self
+    }
+}
+
+fn take_track_number(next: &mut u16) -> Result<u8> {
+    let number = u8::try_from(*next).map_err(|_| SmafError::InvalidData(format!("track number {next}")))?;
+    *next += 1;
+
+    Ok(number)
+}
+
+pub struct ScoreTrackBuilder {
+    format_type: FormatType,
+    channel_types: Vec<(u8, ChannelType)>,
+    setup_data: Vec<u8>,
+    events: Vec<SequenceData>,
+    duration: u32, // before the next event
+}
+
+impl ScoreTrackBuilder {
+    fn new(format_type: FormatType) -> Self {
+        Self {
+            format_type,
+            channel_types: Vec::new(),
+            setup_data: Vec::new(),
+            events: Vec::new(),
+            duration: 0,
+        }
+    }
+
+    // channels without an explicit type are melody when used and no care otherwise
+    pub fn channel_type(mut self, channel: u8, channel_type: ChannelType) -> Self {
+        self.channel_types.push((channel, channel_type));
+        self
+    }
+
+    // raw Mtsu content
+    pub fn setup_data(mut self, data: &[u8]) -> Self {
+        self.setup_data.extend_from_slice(data);
+        self
+    }
+
+    pub fn wait(mut self, duration: u32) -> Self {
+        self.duration += duration;
+        self
+    }
+
+    // handy phone standard channels are 0..=3 and notes 1..=48 without velocity, mobile standard channels are 0..=15
+    pub fn note(self, channel: u8, note: u8, velocity: u8, gate_time: u32) -> Self {
+        self.event(ScoreTrackSequenceEvent::NoteMessage {
+            channel,
+            note,
+            velocity: Some(velocity),
+            gate_time,
+        })
+    }
+
+    pub fn program(self, channel: u8, program: u8) -> Self {
+        self.event(ScoreTrackSequenceEvent::ProgramChange { channel, program })
+    }
+
+    pub fn bank_select(self, channel: u8, value: u8) -> Self {
+        self.event(ScoreTrackSequenceEvent::BankSelect { channel, value })
+    }
+
+    // mobile standard only
+    pub fn control_change(self, channel: u8, control: u8, value: u8) -> Self {
+        self.event(ScoreTrackSequenceEvent::ControlChange { channel, control, value })
+    }
+
+    // handy phone standard only, 0..=4 up and 0x81..=0x84 down
+    pub fn octave_shift(self, channel: u8, value: u8) -> Self {
+        self.event(ScoreTrackSequenceEvent::OctaveShift { channel, value })
+    }
+
+    pub fn modulation(self, channel: u8, value: u8) -> Self {
+        self.event(ScoreTrackSequenceEvent::Modulation { channel, value })
+    }
+
+    pub fn pitch_bend(self, channel: u8, value: u16) -> Self {
+        self.event(ScoreTrackSequenceEvent::PitchBend { channel, value })
+    }
+
+    pub fn volume(self, channel: u8, value: u8) -> Self {
+        self.event(ScoreTrackSequenceEvent::Volume { channel, value })
+    }
+
+    pub fn pan(self, channel: u8, value: u8) -> Self {
+        self.event(ScoreTrackSequenceEvent::Pan { channel, value })
+    }
+
+    pub fn expression(self, channel: u8, value: u8) -> Self {
+        self.event(ScoreTrackSequenceEvent::Expression { channel, value })
+    }
+
+    // without the leading f0, mobile standard data ends with f7
+    pub fn exclusive(self, data: &[u8]) -> Self {
+        self.event(ScoreTrackSequenceEvent::Exclusive(data.to_vec()))
+    }
+
+    fn event(mut self, event: ScoreTrackSequenceEvent) -> Self {
+        let duration = mem::take(&mut self.duration);
+        self.events.push(SequenceData { duration, event });
+        self
+    }
+
+    fn build(mut self, timebase: u8) -> Result<Vec<u8>> {
+        // a trailing wait keeps the track going until its end
+        if self.duration > 0 {
+            self = self.event(ScoreTrackSequenceEvent::Nop);
+        }
+
+        let channel_count = if self.format_type == FormatType::HandyPhoneStandard { 4 } else { 16 };
+        let mut channel_types = [None; 16];
+        for &(channel, channel_type) in &self.channel_types {
+            check_channel(channel, channel_count)?;
+            channel_types[channel as usize] = Some(channel_type);
+        }
+        let mut used_channels = [false; 16];
+        for event in &self.events {
+            if let Some(channel) = check_event(self.format_type, &event.event)? {
+                check_channel(channel, channel_count)?;
+                used_channels[channel as usize] = true;
+            }
+        }
+
+        let statuses = (0..channel_count as usize)
+            .map(|channel| {
+                let channel_type = match channel_types[channel] {
+                    Some(channel_type) => channel_type,
+                    None if used_channels[channel] => ChannelType::Melody,
+                    None => ChannelType::NoCare,
+                };
+
+                ChannelStatus {
+                    kcs: 0,
+                    vs: 0,
+                    led: 0,
+                    channel_type,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let (channel_status, sequence_data) = match self.format_type {
+            FormatType::HandyPhoneStandard => (
+                ChannelStatus::write_handy(&statuses).to_be_bytes().to_vec(),
+                SequenceData::write_handy(&self.events),
+            ),
+            FormatType::MobileStandardCompress => (
+                statuses.iter().map(ChannelStatus::write_mobile).collect(),
+                SequenceData::write_mobile_compressed(&self.events),
+            ),
+            FormatType::MobileStandardNoCompress => (
+                statuses.iter().map(ChannelStatus::write_mobile).collect(),
+                SequenceData::write_mobile(&self.events),
+            ),
+        };
+
+        write_score_track(self.format_type, timebase, timebase, &channel_status, &self.setup_data, &sequence_data)
+    }
+}
+
+fn check_channel(channel: u8, channel_count: u8) -> Result<()> {
+    if channel >= channel_count {
+        return Err(SmafError::InvalidData(format!("channel {channel}")));
+    }
+
+    Ok(())
+}
+
+// the sequence writers mask or drop what has no form in the format, so it is caught here. returns the channel of the event.
+fn check_event(format_type: FormatType, event: &ScoreTrackSequenceEvent) -> Result<Option<u8>> {
+    let handy = format_type == FormatType::HandyPhoneStandard;
+    let invalid = |what: &str, value: u16| SmafError::InvalidData(format!("{what} {value}"));
+    let check_value = |what: &str, value: u8| if value > 0x7f { Err(invalid(what, value as u16)) } else { Ok(()) };
+
+    match *event {
+        ScoreTrackSequenceEvent::NoteMessage { channel, note, velocity, .. } => {
+            if handy && !(1..=48).contains(&note) {
+                return Err(invalid("handy phone standard note", note as u16));
+            }
+            check_value("note", note)?;
+            if !handy {
+                check_value("velocity", velocity.unwrap_or(0))?;
+            }
+            Ok(Some(channel))
+        }
+        ScoreTrackSequenceEvent::ControlChange { channel, control, value } => {
+            if handy {
+                return Err(invalid("handy phone standard control", control as u16));
+            }
+            check_value("control", control)?;
+            check_value("control value", value)?;
+            Ok(Some(channel))
+        }
+        ScoreTrackSequenceEvent::OctaveShift { channel, value } => {
+            if !handy {
+                return Err(invalid("mobile standard octave shift", value as u16));
+            }
+            if !matches!(value, 0x00..=0x04 | 0x81..=0x84) {
+                return Err(invalid("octave shift", value as u16));
+            }
+            Ok(Some(channel))
+        }
+        // bit 7 of a handy phone standard bank select forces rhythm
+        ScoreTrackSequenceEvent::BankSelect { channel, value } => {
+            if !handy {
+                check_value("bank", value)?;
+            }
+            Ok(Some(channel))
+        }
+        ScoreTrackSequenceEvent::PitchBend { channel, value } => {
+            if value > 0x3fff {
+                return Err(invalid("pitch bend", value));
+            }
+            Ok(Some(channel))
+        }
+        ScoreTrackSequenceEvent::ProgramChange { channel, program: value }
+        | ScoreTrackSequenceEvent::Modulation { channel, value }
+        | ScoreTrackSequenceEvent::Volume { channel, value }
+        | ScoreTrackSequenceEvent::Pan { channel, value }
+        | ScoreTrackSequenceEvent::Expression { channel, value } => {
+            check_value("value", value)?;
+            Ok(Some(channel))
+        }
+        ScoreTrackSequenceEvent::Exclusive(_) | ScoreTrackSequenceEvent::Nop => Ok(None),
+    }
+}
+
+pub struct PcmTrackBuilder {
+    channel: Channel,
+    waves: Vec<(u8, Vec<u8>)>,
+    events: Vec<PCMAudioSequenceData>,
+    duration: u32, // before the next event
+}
+
+impl PcmTrackBuilder {
+    fn new() -> Self {
+        Self {
+            channel: Channel::Mono,
+            waves: Vec::new(),
+            events: Vec::new(),
+            duration: 0,
+        }
+    }
+
+    pub fn channel(mut self, channel: Channel) -> Self {
+        self.channel = channel;
+        self
+    }
+
+    // encoded samples in the format of the track, wave numbers are 1..=63
+    pub fn wave_data(mut self, wave_number: u8, data: &[u8]) -> Self {
+        self.waves.push((wave_number, data.to_vec()));
+        self
+    }
+
+    pub fn wait(mut self, duration: u32) -> Self {
+        self.duration += duration;
+        self
+    }
+
+    pub fn wave(self, channel: u8, wave_number: u8, gate_time: u32) -> Self {
+        self.event(PCMAudioSequenceEvent::WaveMessage {
+            channel,
+            wave_number,
+            gate_time,
+        })
+    }
+
+    pub fn pitch_bend(self, channel: u8, value: u8) -> Self {
+        self.event(PCMAudioSequenceEvent::PitchBend { channel, value })
+    }
+
+    pub fn volume(self, channel: u8, value: u8) -> Self {
+        self.event(PCMAudioSequenceEvent::Volume { channel, value })
+    }
+
+    pub fn pan(self, channel: u8, value: u8) -> Self {
+        self.event(PCMAudioSequenceEvent::Pan { channel, value })
+    }
+
+    pub fn expression(self, channel: u8, value: u8) -> Self {
+        self.event(PCMAudioSequenceEvent::Expression { channel, value })
+    }
+
+    pub fn exclusive(self, data: &[u8]) -> Self {
+        self.event(PCMAudioSequenceEvent::Exclusive(data.to_vec()))
+    }
+
+    fn event(mut self, event: PCMAudioSequenceEvent) -> Self {
+        let duration = mem::take(&mut self.duration);
+        self.events.push(PCMAudioSequenceData { duration, event });
+        self
+    }
+
+    fn build(mut self, format: PcmWaveFormat, sampling_freq: SamplingRate, base_bit: BaseBit, timebase: u8) -> Result<Vec<u8>> {
+        if self.duration > 0 {
+            self = self.event(PCMAudioSequenceEvent::Nop);
+        }
+
+        let mut chunks = Vec::new();
+        write_chunk(&mut chunks, b"Atsq", &PCMAudioSequenceData::write(&self.events)?);
+        for (wave_number, data) in &self.waves {
+            if !(1..=63).contains(wave_number) {
+                return Err(SmafError::InvalidData(format!("wave number {wave_number}")));
+            }
+            write_chunk(&mut chunks, &[b'A', b'w', b'a', *wave_number], data);
+        }
+
+        write_pcm_audio_track(self.channel, format, sampling_freq, base_bit, timebase, timebase, &chunks)
+    }
+}
@@ -3,20 +3,33 @@ mod optional_data;
 mod pcm_audio_track;
 mod score_track;
 
+use alloc::{format, string::String, vec::Vec};
+
 use nom::{number::complete::u8, IResult};
 
+use crate::{Result, SmafError};
+
+const TIMEBASES: [(u8, u8); 8] = [(0, 1), (1, 2), (2, 4), (3, 5), (0x10, 10), (0x11, 20), (0x12, 40), (0x13, 50)];
+
 pub fn parse_timebase(raw: u8) -> u8 {
-    match raw {
-        0 => 1,
-        1 => 2,
-        2 => 4,
-        3 => 5,
-        0x10 => 10,
-        0x11 => 20,
-        0x12 => 40,
-        0x13 => 50,
-        _ => panic!("Invalid timebase"),
-    }
+    TIMEBASES
+        .iter()
+        .find(|(code, _)| *code == raw)
+        .map(|(_, milliseconds)| *milliseconds)
+        .expect("Invalid timebase")
+}
+
+// timebase code for a timebase in ms, none if smaf can't express it
+pub fn encode_timebase(milliseconds: u8) -> Option<u8> {
+    TIMEBASES.iter().find(|(_, x)| *x == milliseconds).map(|(code, _)| *code)
+}
+
+pub(crate) fn invalid_timebase(milliseconds: u8) -> SmafError {
+    SmafError::InvalidData(format!("timebase {milliseconds} ms"))
+}
+
+pub fn timebases() -> impl Iterator<Item = u8> {
+    TIMEBASES.iter().map(|(_, milliseconds)| *milliseconds)
 }
 
 pub fn parse_variable_number(input: &[u8]) -> IResult<&[u8], u32> {
@@ -41,6 +54,18 @@ pub fn parse_variable_number(input: &[u8]) -> IResult<&[u8], u32> {
     Ok((data, result))
 }
 
+pub fn write_variable_number(result: &mut Vec<u8>, value: u32) {
+    let mut shift = 28;
+    while shift > 0 && (value >> shift) == 0 {
+        shift -= 7;
+    }
+    while shift > 0 {
+        result.push(0b1000_0000 | ((value >> shift) & 0b0111_1111) as u8);
+        shift -= 7;
+    }
+    result.push((value & 0b0111_1111) as u8);
+}
+
 pub fn parse_handy_variable_number(input: &[u8]) -> IResult<&[u8], u32> {
     let (remaining, first) = u8(input)?;
     if first & 0b1000_0000 == 0 {
@@ -52,9 +77,46 @@ pub fn parse_handy_variable_number(input: &[u8]) -> IResult<&[u8], u32> {
     Ok((remaining, result))
 }
 
+pub const MAX_HANDY_VARIABLE_NUMBER: u32 = 0x407f;
+
+// values beyond MAX_HANDY_VARIABLE_NUMBER are clamped
+pub fn write_handy_variable_number(result: &mut Vec<u8>, value: u32) {
+    let value = value.min(MAX_HANDY_VARIABLE_NUMBER);
+    if value < 0x80 {
+        result.push(value as u8);
+    } else {
+        result.push(0b1000_0000 | ((value >> 7) - 1) as u8);
+        result.push((value & 0b0111_1111) as u8);
+    }
+}
+
+// tag, big endian length and data, the framing shared by every smaf chunk
+pub fn write_chunk(result: &mut Vec<u8>, tag: &[u8], data: &[u8]) {
+    result.extend_from_slice(tag);
+    result.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    result.extend_from_slice(data);
+}
+
+// inverse of write_chunk over a run of chunks, (tag, data) without looking into the data
+pub fn read_chunks(mut data: &[u8]) -> Result<Vec<(&[u8], &[u8])>> {
+    let mut result = Vec::new();
+    while data.len() >= 8 {
+        let length = u32::from_be_bytes([data[4], data[5], data[6], data[7]]) as usize;
+        let chunk = data
+            .get(8..8 + length)
+            .ok_or_else(|| SmafError::ParseError(String::from("truncated chunk")))?;
+        result.push((&data[..4], chunk));
+        data = &data[8 + length..];
+    }
+
+    Ok(result)
+}
+
 pub use self::{
     content_info::ContentsInfoChunk,
     optional_data::OptionalDataChunk,
-    pcm_audio_track::{PCMAudioSequenceData, PCMAudioSequenceEvent, PCMAudioTrack, PCMAudioTrackChunk},
-    score_track::{ChannelStatus, ChannelType, PCMDataChunk, ScoreTrack, ScoreTrackChunk, ScoreTrackSequenceEvent, SequenceData, WaveData},
+    pcm_audio_track::{write_pcm_audio_track, PCMAudioSequenceData, PCMAudioSequenceEvent, PCMAudioTrack, PCMAudioTrackChunk},
+    score_track::{
+        write_score_track, ChannelStatus, ChannelType, PCMDataChunk, ScoreTrack, ScoreTrackChunk, ScoreTrackSequenceEvent, SequenceData, WaveData,
+    },
 };
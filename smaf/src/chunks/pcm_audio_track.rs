@@ -1,4 +1,4 @@
-use alloc::vec::Vec;
+use alloc::{format, vec::Vec};
 use nom::{
     bytes::complete::take,
     combinator::{all_consuming, complete, flat_map, map_res},
@@ -10,8 +10,9 @@ use nom::{
 use nom_derive::Parse;
 
 use crate::{
-    chunks::{parse_timebase, parse_variable_number},
-    constants::{BaseBit, Channel, PcmWaveFormat},
+    chunks::{encode_timebase, invalid_timebase, parse_timebase, parse_variable_number, write_variable_number},
+    constants::{BaseBit, Channel, PcmWaveFormat, SamplingRate},
+    Result, SmafError,
 };
 
 const SHORT_PITCH_BEND_VALUES: [u8; 15] = [0x00, 0x08, 0x10, 0x18, 0x20, 0x28, 0x30, 0x38, 0x40, 0x48, 0x50, 0x58, 0x60, 0x68, 0x70];
@@ -162,6 +163,45 @@ impl PCMAudioSequenceData {
 
         Ok((data, result))
     }
+
+    // inverse of parse. channels are 0..=3, wave numbers 1..=63 as a zero first byte starts a control event,
+    // exclusives carry a one byte length.
+    pub fn write(events: &[Self]) -> Result<Vec<u8>> {
+        let mut result = Vec::new();
+        for event in events {
+            write_variable_number(&mut result, event.duration);
+
+            let control = |channel: u8, event_type: u8, value: u8| Ok::<_, SmafError>([0x00, (check_channel(channel)? << 6) | event_type, value]);
+            match &event.event {
+                PCMAudioSequenceEvent::WaveMessage {
+                    channel,
+                    wave_number,
+                    gate_time,
+                } => {
+                    if !(1..=63).contains(wave_number) {
+                        return Err(SmafError::InvalidData(format!("wave number {wave_number}")));
+                    }
+                    result.push((check_channel(*channel)? << 6) | wave_number);
+                    write_variable_number(&mut result, *gate_time);
+                }
+                PCMAudioSequenceEvent::PitchBend { channel, value } => result.extend_from_slice(&control(*channel, 0x34, *value)?),
+                PCMAudioSequenceEvent::Expression { channel, value } => result.extend_from_slice(&control(*channel, 0x36, *value)?),
+                PCMAudioSequenceEvent::Volume { channel, value } => result.extend_from_slice(&control(*channel, 0x37, *value)?),
+                PCMAudioSequenceEvent::Pan { channel, value } => result.extend_from_slice(&control(*channel, 0x3a, *value)?),
+                PCMAudioSequenceEvent::Exclusive(data) => {
+                    let length = u8::try_from(data.len()).map_err(|_| SmafError::InvalidData(format!("exclusive of {} bytes", data.len())))?;
+                    result.extend_from_slice(&[0xff, 0xf0, length]);
+                    result.extend_from_slice(data);
+                }
+                PCMAudioSequenceEvent::Nop => result.extend_from_slice(&[0xff, 0x00]),
+            }
+        }
+
+        // end of sequence
+        result.extend_from_slice(&[0x00, 0x00, 0x00, 0x00]);
+
+        Ok(result)
+    }
 }
 
 pub enum PCMAudioTrackChunk<'a> {
@@ -191,7 +231,7 @@ pub struct PCMAudioTrack<'a> {
     pub sequence_type: u8, // 0: stream sequence, 1: sub-sequence
     pub channel: Channel,
     pub format: PcmWaveFormat,
-    pub sampling_freq: u16, // in hz
+    pub sampling_freq: SamplingRate,
     pub base_bit: BaseBit,
     pub timebase_d: u8, // in ms
     pub timebase_g: u8, // in ms
@@ -206,18 +246,9 @@ impl<'a> Parse<&'a [u8]> for PCMAudioTrack<'a> {
             |(format_type, sequence_type, wave_type, timebase_d, timebase_g, chunks)| {
                 let channel = Channel::from(((wave_type & 0b1000_0000_0000_0000) >> 15) as u8);
                 let format = PcmWaveFormat::from(((wave_type & 0b0111_0000_0000_0000) >> 12) as u8);
-                let sampling_freq = (wave_type & 0b0000_1111_0000_0000) >> 8;
+                let sampling_freq = SamplingRate::from(((wave_type & 0b0000_1111_0000_0000) >> 8) as u8);
                 let base_bit = BaseBit::from(((wave_type & 0b0000_0000_1111_0000) >> 4) as u8);
 
-                let sampling_freq = match sampling_freq {
-                    0 => 4000,
-                    1 => 8000,
-                    2 => 11000,
-                    3 => 22050,
-                    4 => 44100,
-                    _ => panic!("Invalid sampling frequency {}", sampling_freq),
-                };
-
                 let timebase_d = parse_timebase(timebase_d);
                 let timebase_g = parse_timebase(timebase_g);
 
@@ -236,3 +267,35 @@ impl<'a> Parse<&'a [u8]> for PCMAudioTrack<'a> {
         )(data)
     }
 }
+
+// atr chunk body, chunks (Atsq, Awa*) already framed
+pub fn write_pcm_audio_track(
+    channel: Channel,
+    format: PcmWaveFormat,
+    sampling_freq: SamplingRate,
+    base_bit: BaseBit,
+    timebase_d: u8,
+    timebase_g: u8,
+    chunks: &[u8],
+) -> Result<Vec<u8>> {
+    let wave_type = ((channel as u16) << 15) | ((format as u16) << 12) | (((sampling_freq.to_u8() & 0x0f) as u16) << 8) | ((base_bit as u16) << 4);
+
+    let mut result = Vec::from([
+        0, // format type
+        0, // sequence type, stream sequence
+    ]);
+    result.extend_from_slice(&wave_type.to_be_bytes());
+    result.push(encode_timebase(timebase_d).ok_or_else(|| invalid_timebase(timebase_d))?);
+    result.push(encode_timebase(timebase_g).ok_or_else(|| invalid_timebase(timebase_g))?);
+    result.extend_from_slice(chunks);
+
+    Ok(result)
+}
+
+fn check_channel(channel: u8) -> Result<u8> {
+    if channel > 3 {
+        return Err(SmafError::InvalidData(format!("pcm channel {channel}")));
+    }
+
+    Ok(channel)
+}
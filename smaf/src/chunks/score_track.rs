@@ -1,4 +1,4 @@
-use alloc::vec::Vec;
+use alloc::{vec, vec::Vec};
 
 use nom::{
     bytes::complete::take,
@@ -11,8 +11,12 @@ use nom::{
 use nom_derive::{NomBE, Parse};
 
 use crate::{
-    chunks::{parse_handy_variable_number, parse_timebase, parse_variable_number},
+    chunks::{
+        encode_timebase, invalid_timebase, parse_handy_variable_number, parse_timebase, parse_variable_number, write_chunk,
+        write_handy_variable_number, write_variable_number, MAX_HANDY_VARIABLE_NUMBER,
+    },
     constants::{BaseBit, Channel, FormatType, StreamWaveFormat},
+    Result,
 };
 
 const SHORT_MOD_VALUES: [u8; 15] = [0x00, 0x00, 0x08, 0x10, 0x18, 0x20, 0x28, 0x30, 0x38, 0x40, 0x48, 0x50, 0x60, 0x70, 0x7f];
@@ -44,6 +48,20 @@ impl<'a> Parse<&'a [u8]> for WaveData<'a> {
     }
 }
 
+impl WaveData<'_> {
+    // Mwa chunk body
+    pub fn write(&self) -> Vec<u8> {
+        let wave_type = ((self.channel as u8) << 7) | ((self.format as u8) << 4) | self.base_bit as u8;
+
+        let mut result = Vec::with_capacity(self.wave_data.len() + 3);
+        result.push(wave_type);
+        result.extend_from_slice(&self.sampling_freq.to_be_bytes());
+        result.extend_from_slice(self.wave_data);
+
+        result
+    }
+}
+
 pub enum PCMDataChunk<'a> {
     WaveData(u8, WaveData<'a>),
 }
@@ -228,6 +246,65 @@ impl SequenceData {
         Ok((data, result))
     }
 
+    // inverse of parse_mobile, events without a mobile standard form are written as nops
+    pub fn write_mobile(events: &[Self]) -> Vec<u8> {
+        let mut result = Vec::new();
+        for event in events {
+            write_variable_number(&mut result, event.duration);
+
+            let control = |channel: u8, control: u8, value: u8| [0xb0 | (channel & 0x0f), control, value & 0x7f];
+            match &event.event {
+                ScoreTrackSequenceEvent::NoteMessage {
+                    channel,
+                    note,
+                    velocity,
+                    gate_time,
+                } => {
+                    match velocity {
+                        Some(velocity) => result.extend_from_slice(&[0x90 | (channel & 0x0f), note & 0x7f, velocity & 0x7f]),
+                        None => result.extend_from_slice(&[0x80 | (channel & 0x0f), note & 0x7f]),
+                    }
+                    write_variable_number(&mut result, *gate_time);
+                }
+                ScoreTrackSequenceEvent::ControlChange {
+                    channel,
+                    control: number,
+                    value,
+                } => result.extend_from_slice(&control(*channel, *number, *value)),
+                ScoreTrackSequenceEvent::ProgramChange { channel, program } => result.extend_from_slice(&[0xc0 | (channel & 0x0f), program & 0x7f]),
+                ScoreTrackSequenceEvent::BankSelect { channel, value } => result.extend_from_slice(&control(*channel, 0, *value)),
+                ScoreTrackSequenceEvent::Modulation { channel, value } => result.extend_from_slice(&control(*channel, 1, *value)),
+                ScoreTrackSequenceEvent::Volume { channel, value } => result.extend_from_slice(&control(*channel, 7, *value)),
+                ScoreTrackSequenceEvent::Pan { channel, value } => result.extend_from_slice(&control(*channel, 10, *value)),
+                ScoreTrackSequenceEvent::Expression { channel, value } => result.extend_from_slice(&control(*channel, 11, *value)),
+                ScoreTrackSequenceEvent::PitchBend { channel, value } => {
+                    result.extend_from_slice(&[0xe0 | (channel & 0x0f), (value & 0x7f) as u8, ((value >> 7) & 0x7f) as u8])
+                }
+                ScoreTrackSequenceEvent::Exclusive(data) => {
+                    result.push(0xf0);
+                    write_variable_number(&mut result, data.len() as u32);
+                    result.extend_from_slice(data);
+                }
+                ScoreTrackSequenceEvent::OctaveShift { .. } | ScoreTrackSequenceEvent::Nop => result.extend_from_slice(&[0xff, 0x00]),
+            }
+        }
+
+        // end of sequence
+        result.extend_from_slice(&[0x00, 0xff, 0x2f, 0x00]);
+
+        result
+    }
+
+    pub fn write_mobile_compressed(events: &[Self]) -> Vec<u8> {
+        let encoded = Self::write_mobile(events);
+
+        let mut result = Vec::new();
+        result.extend_from_slice(&(encoded.len() as u32).to_be_bytes());
+        result.extend_from_slice(&huffman_encode(&encoded));
+
+        result
+    }
+
     pub fn parse_handy(input: &[u8]) -> IResult<&[u8], Vec<Self>> {
         Self::parse_handy_like(input, false)
     }
@@ -236,6 +313,79 @@ impl SequenceData {
         Self::parse_handy_like(input, true)
     }
 
+    // inverse of parse_handy. channels are 0..=3 and notes 1..=48, events without a handy phone form are written as nops.
+    // durations beyond the two byte form are carried by extra nops, longer gate times are clamped.
+    pub fn write_handy(events: &[Self]) -> Vec<u8> {
+        let mut result = Vec::new();
+        for event in events {
+            let mut duration = event.duration;
+            while duration > MAX_HANDY_VARIABLE_NUMBER {
+                write_handy_variable_number(&mut result, MAX_HANDY_VARIABLE_NUMBER);
+                result.extend_from_slice(&[0xff, 0x00]);
+                duration -= MAX_HANDY_VARIABLE_NUMBER;
+            }
+            write_handy_variable_number(&mut result, duration);
+
+            let control = |channel: u8, event_type: u8| [0x00, ((channel & 0b11) << 6) | event_type];
+            match &event.event {
+                ScoreTrackSequenceEvent::NoteMessage {
+                    channel, note, gate_time, ..
+                } if (1..=48).contains(note) => {
+                    let octave = (note - 1) / 12;
+                    let voice = note - octave * 12;
+                    result.push(((channel & 0b11) << 6) | (octave << 4) | voice);
+                    write_handy_variable_number(&mut result, *gate_time);
+                }
+                ScoreTrackSequenceEvent::ProgramChange { channel, program } => {
+                    result.extend_from_slice(&control(*channel, 0x30));
+                    result.push(*program);
+                }
+                ScoreTrackSequenceEvent::BankSelect { channel, value } => {
+                    result.extend_from_slice(&control(*channel, 0x31));
+                    result.push(*value);
+                }
+                ScoreTrackSequenceEvent::OctaveShift { channel, value } => {
+                    result.extend_from_slice(&control(*channel, 0x32));
+                    result.push(*value);
+                }
+                ScoreTrackSequenceEvent::Modulation { channel, value } => {
+                    result.extend_from_slice(&control(*channel, 0x33));
+                    result.push(*value);
+                }
+                ScoreTrackSequenceEvent::PitchBend { channel, value } => {
+                    result.extend_from_slice(&control(*channel, 0x34));
+                    result.push(midi_to_pitch_bend_byte(*value));
+                }
+                ScoreTrackSequenceEvent::Expression { channel, value } => {
+                    result.extend_from_slice(&control(*channel, 0x36));
+                    result.push(*value);
+                }
+                ScoreTrackSequenceEvent::Volume { channel, value } => {
+                    result.extend_from_slice(&control(*channel, 0x37));
+                    result.push(*value);
+                }
+                ScoreTrackSequenceEvent::Pan { channel, value } => {
+                    result.extend_from_slice(&control(*channel, 0x3a));
+                    result.push(*value);
+                }
+                ScoreTrackSequenceEvent::Exclusive(data) => {
+                    let data = data.strip_suffix(&[0xf7]).unwrap_or(data);
+                    result.extend_from_slice(&[0xff, 0xf0]);
+                    result.extend_from_slice(data);
+                    result.push(0xf7);
+                }
+                ScoreTrackSequenceEvent::NoteMessage { .. } | ScoreTrackSequenceEvent::ControlChange { .. } | ScoreTrackSequenceEvent::Nop => {
+                    result.extend_from_slice(&[0xff, 0x00])
+                }
+            }
+        }
+
+        // end of sequence
+        result.extend_from_slice(&[0x00, 0x00, 0x00, 0x00]);
+
+        result
+    }
+
     fn parse_handy_like(input: &[u8], softbank: bool) -> IResult<&[u8], Vec<Self>> {
         let mut data = input;
         let mut result = Vec::new();
@@ -475,11 +625,107 @@ fn huffman_decode(decoded_len: usize, src: &[u8]) -> Option<Vec<u8>> {
     Some(decoded)
 }
 
+// the tree is written depth first, 1 for a node followed by its left and right children, 0 and 8 bits for a leaf.
+// codes take the left child on 0 and the right one on 1, as huffman_decode reads them.
+fn huffman_encode(data: &[u8]) -> Vec<u8> {
+    const N: usize = 256;
+
+    struct BitWriter {
+        data: Vec<u8>,
+        bit_offset: u8,
+    }
+
+    impl BitWriter {
+        fn bit_write(&mut self, bit: bool) {
+            if self.bit_offset == 0 {
+                self.data.push(0);
+            }
+            if bit {
+                *self.data.last_mut().unwrap() |= 0x80 >> self.bit_offset;
+            }
+            self.bit_offset = (self.bit_offset + 1) % 8;
+        }
+
+        fn bit_n_write(&mut self, value: usize, n: u8) {
+            for shift in (0..n).rev() {
+                self.bit_write((value >> shift) & 1 == 1);
+            }
+        }
+    }
+
+    fn write_tree(writer: &mut BitWriter, children: &[(usize, usize)], node: usize) {
+        if node < N {
+            writer.bit_write(false);
+            writer.bit_n_write(node, 8);
+        } else {
+            writer.bit_write(true);
+            write_tree(writer, children, children[node - N].0);
+            write_tree(writer, children, children[node - N].1);
+        }
+    }
+
+    fn assign_codes(children: &[(usize, usize)], node: usize, code: Vec<bool>, codes: &mut [Vec<bool>]) {
+        if node < N {
+            codes[node] = code;
+        } else {
+            let (left, right) = children[node - N];
+            let mut left_code = code.clone();
+            left_code.push(false);
+            assign_codes(children, left, left_code, codes);
+            let mut right_code = code;
+            right_code.push(true);
+            assign_codes(children, right, right_code, codes);
+        }
+    }
+
+    let mut frequencies = [0usize; N];
+    for &byte in data {
+        frequencies[byte as usize] += 1;
+    }
+
+    // (weight, node), nodes from N up are the internal ones
+    let mut pending = frequencies
+        .iter()
+        .enumerate()
+        .filter(|(_, &frequency)| frequency > 0)
+        .map(|(symbol, &frequency)| (frequency, symbol))
+        .collect::<Vec<_>>();
+    let mut children = Vec::new();
+    while pending.len() > 1 {
+        pending.sort_by(|left, right| right.cmp(left));
+        let (left_weight, left) = pending.pop().unwrap();
+        let (right_weight, right) = pending.pop().unwrap();
+        children.push((left, right));
+        pending.push((left_weight + right_weight, N + children.len() - 1));
+    }
+    let root = pending.first().map_or(0, |(_, node)| *node);
+
+    let mut codes = vec![Vec::new(); N];
+    assign_codes(&children, root, Vec::new(), &mut codes);
+
+    let mut writer = BitWriter {
+        data: Vec::new(),
+        bit_offset: 0,
+    };
+    write_tree(&mut writer, &children, root);
+    for &byte in data {
+        for &bit in &codes[byte as usize] {
+            writer.bit_write(bit);
+        }
+    }
+
+    writer.data
+}
+
 fn pitch_bend_byte_to_midi(value: u8) -> u16 {
     let offset = ((value as i32) - 128) * 64;
     (8192 + offset).clamp(0, 16383) as u16
 }
 
+fn midi_to_pitch_bend_byte(value: u16) -> u8 {
+    ((value.min(16383) as i32 - 8192) / 64 + 128).clamp(0, 255) as u8
+}
+
 #[allow(clippy::enum_variant_names)]
 pub enum ScoreTrackChunk<'a> {
     SetupData(&'a [u8]),
@@ -512,6 +758,7 @@ impl<'a> ScoreTrackChunk<'a> {
 }
 
 #[repr(u8)]
+#[derive(Clone, Copy)]
 pub enum ChannelType {
     NoCare = 0,
     Melody = 1,
@@ -529,6 +776,15 @@ impl ChannelType {
             _ => panic!("Invalid channel type"),
         }
     }
+
+    pub fn to_u8(&self) -> u8 {
+        match self {
+            ChannelType::NoCare => 0,
+            ChannelType::Melody => 1,
+            ChannelType::NoMelody => 2,
+            ChannelType::Rhythm => 3,
+        }
+    }
 }
 
 pub struct ChannelStatus {
@@ -550,6 +806,18 @@ impl ChannelStatus {
         Self { kcs, vs, led, channel_type }
     }
 
+    pub fn write_mobile(&self) -> u8 {
+        ((self.kcs & 0b11) << 6) | ((self.vs & 1) << 5) | ((self.led & 1) << 4) | self.channel_type.to_u8()
+    }
+
+    // four channels, the first one in the top bits
+    pub fn write_handy(statuses: &[Self]) -> u16 {
+        statuses.iter().take(4).enumerate().fold(0, |result, (index, status)| {
+            let data = ((status.kcs & 1) << 3) | ((status.vs & 1) << 2) | status.channel_type.to_u8();
+            result | ((data as u16) << ((3 - index) * 4))
+        })
+    }
+
     pub fn parse_handy(raw: u16) -> Vec<Self> {
         // Spec: Data#0 upper 4 bits = Ch0, lower 4 bits = Ch1
         //       Data#1 upper 4 bits = Ch2, lower 4 bits = Ch3
@@ -594,6 +862,30 @@ pub struct ScoreTrack<'a> {
     pub chunks: Vec<ScoreTrackChunk<'a>>,
 }
 
+// mtr chunk body, channel_status and sequence_data already encoded for the format type
+pub fn write_score_track(
+    format_type: FormatType,
+    timebase_d: u8,
+    timebase_g: u8,
+    channel_status: &[u8],
+    setup_data: &[u8],
+    sequence_data: &[u8],
+) -> Result<Vec<u8>> {
+    let mut result = Vec::from([
+        format_type as u8,
+        0, // sequence type, stream sequence
+        encode_timebase(timebase_d).ok_or_else(|| invalid_timebase(timebase_d))?,
+        encode_timebase(timebase_g).ok_or_else(|| invalid_timebase(timebase_g))?,
+    ]);
+    result.extend_from_slice(channel_status);
+    if !setup_data.is_empty() {
+        write_chunk(&mut result, b"Mtsu", setup_data);
+    }
+    write_chunk(&mut result, b"Mtsq", sequence_data);
+
+    Ok(result)
+}
+
 fn parse_channel_status(format_type: FormatType, data: &[u8]) -> IResult<&[u8], Vec<ChannelStatus>> {
     Ok(match format_type {
         FormatType::MobileStandardCompress | FormatType::MobileStandardNoCompress => {
@@ -78,6 +78,65 @@ impl From<u8> for BaseBit {
     }
 }
 
+// pcm audio track sampling frequency, codes without a rate in the spec are kept as is
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub enum SamplingRate {
+    Hz4000,
+    Hz8000,
+    Hz11025,
+    Hz22050,
+    Hz44100,
+    Unknown(u8),
+}
+
+impl SamplingRate {
+    pub fn from_hz(hz: u32) -> Option<Self> {
+        Some(match hz {
+            4000 => Self::Hz4000,
+            8000 => Self::Hz8000,
+            11025 => Self::Hz11025,
+            22050 => Self::Hz22050,
+            44100 => Self::Hz44100,
+            _ => return None,
+        })
+    }
+
+    pub fn hz(&self) -> Option<u32> {
+        match self {
+            Self::Hz4000 => Some(4000),
+            Self::Hz8000 => Some(8000),
+            Self::Hz11025 => Some(11025),
+            Self::Hz22050 => Some(22050),
+            Self::Hz44100 => Some(44100),
+            Self::Unknown(_) => None,
+        }
+    }
+
+    pub fn to_u8(&self) -> u8 {
+        match self {
+            Self::Hz4000 => 0,
+            Self::Hz8000 => 1,
+            Self::Hz11025 => 2,
+            Self::Hz22050 => 3,
+            Self::Hz44100 => 4,
+            Self::Unknown(x) => *x,
+        }
+    }
+}
+
+impl From<u8> for SamplingRate {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => Self::Hz4000,
+            1 => Self::Hz8000,
+            2 => Self::Hz11025,
+            3 => Self::Hz22050,
+            4 => Self::Hz44100,
+            x => Self::Unknown(x),
+        }
+    }
+}
+
 #[repr(u8)]
 #[derive(NomBE, Eq, PartialEq, Copy, Clone, Debug)]
 pub enum FormatType {
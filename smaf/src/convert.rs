@@ -0,0 +1,295 @@
+use alloc::{format, vec::Vec};
+
+use nom::combinator::all_consuming;
+use nom_derive::Parse;
+
+use crate::{
+    chunks::{
+        timebases, write_chunk, write_score_track, ChannelStatus, ChannelType, ScoreTrack, ScoreTrackChunk, ScoreTrackSequenceEvent, SequenceData,
+    },
+    constants::FormatType,
+    smaf::{read_smaf_chunks, write_smaf},
+    Result, SmafError,
+};
+
+const MOBILE_TRACK: u8 = 5;
+const MAX_HANDY_TRACKS: usize = 4; // 16 mobile channels hold 4 handy phone tracks
+const HANDY_NOTE_OFFSET: i16 = 36;
+const HANDY_MELODY_VELOCITY: u8 = 127;
+const RHYTHM_BANK: u8 = 0x7d;
+
+struct HandyChannel {
+    rhythm: bool, // typed or forced by a bank select
+    bank: u8,
+    octave_shift: i16,
+    program: u8,
+    volume: u8,
+    expression: u8,
+}
+
+impl HandyChannel {
+    fn is_rhythm(&self) -> bool {
+        self.rhythm || self.bank == RHYTHM_BANK
+    }
+
+    // expression scales the channel volume on handy phone devices
+    fn effective_volume(&self) -> u8 {
+        ((self.volume as u16 * self.expression as u16) / 127).min(127) as u8
+    }
+
+    fn drum_velocity(&self) -> u8 {
+        let divisor = if self.expression < 64 { 102 } else { 100 };
+        ((self.volume as u16 * self.expression as u16) / divisor).clamp(1, 127) as u8
+    }
+}
+
+// rewrites the handy phone standard score tracks of a file into one mobile standard track, other chunks are kept as is.
+// track n takes channels 4n..4n+3, notes get the +36 offset and octave shifts applied, rhythm channels play their program as
+// the key and volume/expression are folded the way handy phone devices do.
+pub fn hps_to_mobile(raw: &[u8]) -> Result<Vec<u8>> {
+    let mut chunks = Vec::new();
+    let mut handy_tracks = Vec::new();
+    let mut handy_position = None;
+    for (tag, data) in read_smaf_chunks(raw)? {
+        if tag.starts_with(b"MTR") {
+            let (_, track) = all_consuming(ScoreTrack::parse)(data).map_err(|e| SmafError::ParseError(format!("{e}")))?;
+            if track.format_type == FormatType::HandyPhoneStandard {
+                handy_position.get_or_insert(chunks.len());
+                handy_tracks.push(track);
+                continue;
+            }
+        }
+        write_chunk(&mut chunks, tag, data);
+    }
+
+    if let Some(position) = handy_position {
+        let mut track = Vec::new();
+        write_chunk(&mut track, &[b'M', b'T', b'R', MOBILE_TRACK], &merge_handy_tracks(&handy_tracks)?);
+        chunks.splice(position..position, track);
+    }
+
+    Ok(write_smaf(&chunks))
+}
+
+fn merge_handy_tracks(tracks: &[ScoreTrack]) -> Result<Vec<u8>> {
+    if tracks.len() > MAX_HANDY_TRACKS {
+        return Err(SmafError::InvalidData(format!("{} handy phone standard tracks", tracks.len())));
+    }
+
+    // the coarsest timebase every track's durations and gate times are a multiple of
+    let timebase = timebases()
+        .filter(|timebase| {
+            tracks
+                .iter()
+                .all(|track| track.timebase_d % timebase == 0 && track.timebase_g % timebase == 0)
+        })
+        .max()
+        .unwrap_or(1);
+
+    let mut events = Vec::new();
+    let mut channel_types = [ChannelType::NoCare.to_u8(); 16];
+    let mut setup_data = Vec::new();
+    let mut end = 0;
+
+    for (index, track) in tracks.iter().enumerate() {
+        let channel_offset = index as u8 * 4;
+        let forced_rhythm = forced_rhythm_channels(track);
+        let mut channels = track
+            .channel_status
+            .iter()
+            .zip(forced_rhythm)
+            .map(|(status, forced)| HandyChannel {
+                rhythm: forced || matches!(status.channel_type, ChannelType::Rhythm),
+                bank: 0,
+                octave_shift: 0,
+                program: 0,
+                volume: 100,
+                expression: 127,
+            })
+            .collect::<Vec<_>>();
+        for (local, channel) in channels.iter().enumerate() {
+            channel_types[channel_offset as usize + local] = if channel.rhythm {
+                ChannelType::Rhythm.to_u8()
+            } else {
+                ChannelType::Melody.to_u8()
+            };
+        }
+
+        for chunk in &track.chunks {
+            let sequence = match chunk {
+                ScoreTrackChunk::SetupData(data) => {
+                    setup_data.extend_from_slice(data);
+                    continue;
+                }
+                ScoreTrackChunk::SequenceData(sequence) => sequence,
+                _ => continue,
+            };
+
+            let mut now = 0;
+            for event in sequence {
+                now += event.duration as usize * track.timebase_d as usize;
+                if let Some(event) = map_handy_event(&event.event, &mut channels, channel_offset, track.timebase_g as u32, timebase as u32) {
+                    events.push((now, event));
+                }
+            }
+            end = end.max(now);
+        }
+    }
+
+    // stable, so events of the same time keep their track order
+    events.sort_by_key(|(time, _)| *time);
+    // keeps the trailing wait of the longest track
+    if events.last().map_or(0, |(time, _)| *time) < end {
+        events.push((end, ScoreTrackSequenceEvent::Nop));
+    }
+    let mut now = 0;
+    let sequence = events
+        .into_iter()
+        .map(|(time, event)| {
+            let duration = ((time - now) / timebase as usize) as u32;
+            now = time;
+            SequenceData { duration, event }
+        })
+        .collect::<Vec<_>>();
+
+    let channel_status = channel_types
+        .iter()
+        .map(|&channel_type| {
+            ChannelStatus {
+                kcs: 0,
+                vs: 0,
+                led: 0,
+                channel_type: ChannelType::from_u8(channel_type),
+            }
+            .write_mobile()
+        })
+        .collect::<Vec<_>>();
+
+    write_score_track(
+        FormatType::MobileStandardNoCompress,
+        timebase,
+        timebase,
+        &channel_status,
+        &setup_data,
+        &SequenceData::write_mobile(&sequence),
+    )
+}
+
+// like the player, a bank select with bit 7 or a program change in the rhythm bank makes the channel rhythm for the whole track
+fn forced_rhythm_channels(track: &ScoreTrack) -> [bool; 4] {
+    let mut forced = [false; 4];
+    let mut banks = [0; 4];
+    for chunk in &track.chunks {
+        let ScoreTrackChunk::SequenceData(sequence) = chunk else {
+            continue;
+        };
+
+        for event in sequence {
+            match event.event {
+                ScoreTrackSequenceEvent::BankSelect { channel, value } if (channel as usize) < 4 => {
+                    banks[channel as usize] = value & 0x7f;
+                    forced[channel as usize] |= value & 0x80 != 0;
+                }
+                ScoreTrackSequenceEvent::ProgramChange { channel, .. } if (channel as usize) < 4 => {
+                    forced[channel as usize] |= banks[channel as usize] == RHYTHM_BANK;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    forced
+}
+
+fn map_handy_event(
+    event: &ScoreTrackSequenceEvent,
+    channels: &mut [HandyChannel],
+    channel_offset: u8,
+    timebase_g: u32,
+    timebase: u32,
+) -> Option<ScoreTrackSequenceEvent> {
+    if let ScoreTrackSequenceEvent::Exclusive(data) = event {
+        let mut data = data.clone();
+        if data.last() != Some(&0xf7) {
+            data.push(0xf7);
+        }
+        return Some(ScoreTrackSequenceEvent::Exclusive(data));
+    }
+
+    let local = match *event {
+        ScoreTrackSequenceEvent::NoteMessage { channel, .. }
+        | ScoreTrackSequenceEvent::ControlChange { channel, .. }
+        | ScoreTrackSequenceEvent::ProgramChange { channel, .. }
+        | ScoreTrackSequenceEvent::BankSelect { channel, .. }
+        | ScoreTrackSequenceEvent::OctaveShift { channel, .. }
+        | ScoreTrackSequenceEvent::Modulation { channel, .. }
+        | ScoreTrackSequenceEvent::PitchBend { channel, .. }
+        | ScoreTrackSequenceEvent::Volume { channel, .. }
+        | ScoreTrackSequenceEvent::Pan { channel, .. }
+        | ScoreTrackSequenceEvent::Expression { channel, .. } => channel as usize,
+        ScoreTrackSequenceEvent::Exclusive(_) | ScoreTrackSequenceEvent::Nop => return None,
+    };
+    let state = channels.get_mut(local)?;
+    let channel = channel_offset + local as u8;
+
+    Some(match *event {
+        ScoreTrackSequenceEvent::NoteMessage { note, gate_time, .. } => {
+            let (note, velocity) = if state.is_rhythm() {
+                (state.program, state.drum_velocity())
+            } else {
+                let note = (note as i16 + HANDY_NOTE_OFFSET + state.octave_shift * 12).clamp(0, 127) as u8;
+                (note, HANDY_MELODY_VELOCITY)
+            };
+
+            ScoreTrackSequenceEvent::NoteMessage {
+                channel,
+                note,
+                velocity: Some(velocity),
+                gate_time: gate_time * timebase_g / timebase,
+            }
+        }
+        ScoreTrackSequenceEvent::ProgramChange { program, .. } => {
+            if state.is_rhythm() {
+                state.program = program.min(0x7f);
+                return None;
+            }
+            ScoreTrackSequenceEvent::ProgramChange { channel, program }
+        }
+        ScoreTrackSequenceEvent::BankSelect { value, .. } => {
+            state.bank = value & 0x7f;
+            ScoreTrackSequenceEvent::ControlChange {
+                channel,
+                control: 0,
+                value: if value & 0x80 != 0 { RHYTHM_BANK } else { state.bank },
+            }
+        }
+        ScoreTrackSequenceEvent::OctaveShift { value, .. } => {
+            state.octave_shift = match value {
+                0x00..=0x04 => value as i16,
+                0x81..=0x84 => -((value - 0x80) as i16),
+                _ => state.octave_shift,
+            };
+            return None;
+        }
+        ScoreTrackSequenceEvent::Volume { value, .. } => {
+            state.volume = value.min(0x7f);
+            let value = if state.is_rhythm() { 100 } else { state.effective_volume() };
+            ScoreTrackSequenceEvent::Volume { channel, value }
+        }
+        ScoreTrackSequenceEvent::Expression { value, .. } => {
+            state.expression = value.min(0x7f);
+            if state.is_rhythm() {
+                return None; // carried by the note velocity
+            }
+            ScoreTrackSequenceEvent::Volume {
+                channel,
+                value: state.effective_volume(),
+            }
+        }
+        ScoreTrackSequenceEvent::ControlChange { control, value, .. } => ScoreTrackSequenceEvent::ControlChange { channel, control, value },
+        ScoreTrackSequenceEvent::Modulation { value, .. } => ScoreTrackSequenceEvent::Modulation { channel, value },
+        ScoreTrackSequenceEvent::PitchBend { value, .. } => ScoreTrackSequenceEvent::PitchBend { channel, value },
+        ScoreTrackSequenceEvent::Pan { value, .. } => ScoreTrackSequenceEvent::Pan { channel, value },
+        ScoreTrackSequenceEvent::Exclusive(_) | ScoreTrackSequenceEvent::Nop => return None,
+    })
+}
@@ -1,8 +1,11 @@
 #![no_std]
 extern crate alloc;
 
+mod adpcm;
+mod builder;
 mod chunks;
 mod constants;
+mod convert;
 mod smaf;
 
 use alloc::string::String;
@@ -11,6 +14,7 @@ use core::result;
 #[derive(Debug)]
 pub enum SmafError {
     ParseError(String),
+    InvalidData(String), // values that can't be written, like an unsupported timebase
 }
 
 impl From<SmafError> for anyhow::Error {
@@ -22,10 +26,15 @@ impl From<SmafError> for anyhow::Error {
 pub type Result<T> = result::Result<T, SmafError>;
 
 pub use self::{
+    adpcm::{decode_adpcm, encode_adpcm, AdpcmQuality},
+    builder::{PcmTrackBuilder, ScoreTrackBuilder, SmafBuilder},
     chunks::{
-        parse_handy_variable_number, parse_variable_number, ChannelStatus, ChannelType, PCMAudioSequenceData, PCMAudioSequenceEvent, PCMAudioTrack,
-        PCMAudioTrackChunk, PCMDataChunk, ScoreTrack, ScoreTrackChunk, ScoreTrackSequenceEvent, SequenceData, WaveData,
+        encode_timebase, parse_handy_variable_number, parse_timebase, parse_variable_number, read_chunks, timebases, write_chunk,
+        write_handy_variable_number, write_pcm_audio_track, write_score_track, write_variable_number, ChannelStatus, ChannelType,
+        PCMAudioSequenceData, PCMAudioSequenceEvent, PCMAudioTrack, PCMAudioTrackChunk, PCMDataChunk, ScoreTrack, ScoreTrackChunk,
+        ScoreTrackSequenceEvent, SequenceData, WaveData, MAX_HANDY_VARIABLE_NUMBER,
     },
-    constants::{BaseBit, Channel, FormatType, PcmWaveFormat, StreamWaveFormat},
-    smaf::{Smaf, SmafChunk},
+    constants::{BaseBit, Channel, FormatType, PcmWaveFormat, SamplingRate, StreamWaveFormat},
+    convert::hps_to_mobile,
+    smaf::{read_smaf_chunks, write_smaf, Smaf, SmafChunk},
 };
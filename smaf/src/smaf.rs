@@ -1,4 +1,4 @@
-use alloc::{format, vec::Vec};
+use alloc::{format, string::String, vec::Vec};
 
 use nom::{
     bytes::complete::take,
@@ -11,7 +11,7 @@ use nom::{
 use nom_derive::{NomBE, Parse};
 
 use crate::{
-    chunks::{ContentsInfoChunk, OptionalDataChunk, PCMAudioTrack, ScoreTrack, SequenceData},
+    chunks::{read_chunks, ContentsInfoChunk, OptionalDataChunk, PCMAudioTrack, ScoreTrack, SequenceData},
     Result, SmafError,
 };
 
@@ -55,3 +55,42 @@ impl<'a> Smaf<'a> {
         Ok(Parse::parse(file).map_err(|e| SmafError::ParseError(format!("{e}")))?.1)
     }
 }
+
+// top level chunks of a file, for rewriting it without a full parse
+pub fn read_smaf_chunks(raw: &[u8]) -> Result<Vec<(&[u8], &[u8])>> {
+    let invalid = |message: &str| SmafError::ParseError(String::from(message));
+
+    if raw.get(..4) != Some(b"MMMD") || raw.len() < 10 {
+        return Err(invalid("missing MMMD"));
+    }
+    let length = u32::from_be_bytes([raw[4], raw[5], raw[6], raw[7]]) as usize;
+    let body = raw.get(8..8 + length.saturating_sub(2)).ok_or_else(|| invalid("truncated file"))?;
+
+    read_chunks(body)
+}
+
+// wraps already framed chunks into a file, the length counts the trailing crc
+pub fn write_smaf(chunks: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(chunks.len() + 10);
+    result.extend_from_slice(b"MMMD");
+    result.extend_from_slice(&(chunks.len() as u32 + 2).to_be_bytes());
+    result.extend_from_slice(chunks);
+
+    let crc = crc16(&result);
+    result.extend_from_slice(&crc.to_be_bytes());
+
+    result
+}
+
+// crc-16/ccitt over everything before the crc, inverted
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xffffu16;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+
+    !crc
+}
@@ -1,6 +1,8 @@
 use smaf::{
-    parse_handy_variable_number, parse_variable_number, BaseBit, Channel, FormatType, PCMAudioSequenceData, PCMAudioSequenceEvent,
-    PCMAudioTrackChunk, PCMDataChunk, PcmWaveFormat, ScoreTrackChunk, ScoreTrackSequenceEvent, SequenceData, Smaf, SmafChunk, StreamWaveFormat,
+    decode_adpcm, encode_adpcm, encode_timebase, hps_to_mobile, parse_handy_variable_number, parse_timebase, parse_variable_number, write_chunk,
+    write_handy_variable_number, write_score_track, write_smaf, write_variable_number, AdpcmQuality, BaseBit, Channel, ChannelStatus, ChannelType,
+    FormatType, PCMAudioSequenceData, PCMAudioSequenceEvent, PCMAudioTrack, PCMAudioTrackChunk, PCMDataChunk, PcmWaveFormat, SamplingRate,
+    ScoreTrackChunk, ScoreTrackSequenceEvent, SequenceData, Smaf, SmafBuilder, SmafChunk, StreamWaveFormat,
 };
 
 #[test]
@@ -57,7 +59,7 @@ fn test_wave_load() -> anyhow::Result<()> {
         assert_eq!(x.sequence_type, 0);
         assert_eq!(x.channel, Channel::Mono);
         assert_eq!(x.format, PcmWaveFormat::Adpcm);
-        assert_eq!(x.sampling_freq, 8000);
+        assert_eq!(x.sampling_freq, SamplingRate::Hz8000);
         assert_eq!(x.base_bit, BaseBit::Bit4);
         assert_eq!(x.timebase_d, 4);
         assert_eq!(x.timebase_g, 4);
@@ -250,3 +252,553 @@ fn test_pcm_short_pitch_bend() {
         .iter()
         .any(|e| matches!(e.event, PCMAudioSequenceEvent::PitchBend { channel: 0, value: 0x18 })));
 }
+
+#[test]
+fn test_variable_number_and_timebase_round_trip() {
+    for value in [0, 0x7f, 0x80, 0x3fff, 0x4000, 0x0fff_ffff] {
+        let mut data = Vec::new();
+        write_variable_number(&mut data, value);
+        assert_eq!(parse_variable_number(&data).unwrap(), (&[][..], value));
+    }
+
+    for timebase in [1, 2, 4, 5, 10, 20, 40, 50] {
+        assert_eq!(parse_timebase(encode_timebase(timebase).unwrap()), timebase);
+    }
+    assert_eq!(encode_timebase(3), None);
+}
+
+#[test]
+fn test_write_smaf_reproduces_file() {
+    let data = include_bytes!("../../test_data/midi.mmf");
+    let length = u32::from_be_bytes(data[4..8].try_into().unwrap()) as usize;
+
+    assert_eq!(write_smaf(&data[8..8 + length - 2]), data);
+}
+
+#[test]
+fn test_mobile_sequence_round_trip() {
+    let events = [
+        SequenceData {
+            duration: 0,
+            event: ScoreTrackSequenceEvent::ProgramChange { channel: 2, program: 40 },
+        },
+        SequenceData {
+            duration: 300,
+            event: ScoreTrackSequenceEvent::NoteMessage {
+                channel: 2,
+                note: 60,
+                velocity: Some(100),
+                gate_time: 200,
+            },
+        },
+        SequenceData {
+            duration: 5,
+            event: ScoreTrackSequenceEvent::Volume { channel: 3, value: 90 },
+        },
+        SequenceData {
+            duration: 0,
+            event: ScoreTrackSequenceEvent::PitchBend { channel: 3, value: 0x2345 },
+        },
+        SequenceData {
+            duration: 1,
+            event: ScoreTrackSequenceEvent::Exclusive(vec![0x43, 0x79, 0xf7]),
+        },
+    ];
+
+    let data = SequenceData::write_mobile(&events);
+    let (_, parsed) = SequenceData::parse_mobile(&data).unwrap();
+
+    assert_eq!(parsed.len(), events.len() + 1);
+    assert_eq!(parsed.iter().map(|x| x.duration).collect::<Vec<_>>(), [0, 300, 5, 0, 1, 0]);
+    assert!(matches!(
+        parsed[1].event,
+        ScoreTrackSequenceEvent::NoteMessage {
+            channel: 2,
+            note: 60,
+            velocity: Some(100),
+            gate_time: 200
+        }
+    ));
+    assert!(matches!(
+        parsed[2].event,
+        ScoreTrackSequenceEvent::ControlChange {
+            channel: 3,
+            control: 7,
+            value: 90
+        }
+    ));
+    assert!(matches!(
+        parsed[3].event,
+        ScoreTrackSequenceEvent::PitchBend { channel: 3, value: 0x2345 }
+    ));
+    assert!(matches!(parsed[4].event, ScoreTrackSequenceEvent::Exclusive(ref x) if x == &[0x43, 0x79, 0xf7]));
+}
+
+#[test]
+fn test_compressed_score_track_round_trip() -> anyhow::Result<()> {
+    let original = Smaf::parse(include_bytes!("../../test_data/midi.mmf"))?;
+    let Some(SmafChunk::ScoreTrack(_, track)) = original.chunks.iter().find(|x| matches!(x, SmafChunk::ScoreTrack(_, _))) else {
+        panic!("Expected ScoreTrack chunk");
+    };
+    let Some(ScoreTrackChunk::SequenceData(sequence)) = track.chunks.iter().find(|x| matches!(x, ScoreTrackChunk::SequenceData(_))) else {
+        panic!("Expected SequenceData chunk");
+    };
+
+    let channel_status = [ChannelStatus {
+        kcs: 0,
+        vs: 0,
+        led: 0,
+        channel_type: ChannelType::Melody,
+    }
+    .write_mobile(); 16];
+    let sequence_data = SequenceData::write_mobile_compressed(sequence);
+    let mut chunks = Vec::new();
+    write_chunk(
+        &mut chunks,
+        b"MTR\x05",
+        &write_score_track(FormatType::MobileStandardCompress, 4, 4, &channel_status, &[], &sequence_data)?,
+    );
+    let data = write_smaf(&chunks);
+
+    let file = Smaf::parse(&data)?;
+    let SmafChunk::ScoreTrack(5, track) = &file.chunks[0] else {
+        panic!("Expected ScoreTrack chunk");
+    };
+    assert_eq!(track.format_type, FormatType::MobileStandardCompress);
+    assert_eq!((track.timebase_d, track.timebase_g), (4, 4));
+    assert!(matches!(track.channel_status[15].channel_type, ChannelType::Melody));
+
+    let [ScoreTrackChunk::SequenceData(parsed)] = track.chunks.as_slice() else {
+        panic!("Expected SequenceData chunk");
+    };
+    assert!(sequence_data.len() < SequenceData::write_mobile(sequence).len());
+    assert_eq!(parsed.len(), sequence.len() + 1);
+    assert!(parsed.iter().zip(sequence).all(|(x, y)| x.duration == y.duration));
+
+    Ok(())
+}
+
+#[test]
+fn test_handy_sequence_round_trip() {
+    for value in [0, 0x7f, 0x80, 0x1234, 0x407f] {
+        let mut data = Vec::new();
+        write_handy_variable_number(&mut data, value);
+        assert_eq!(parse_handy_variable_number(&data).unwrap(), (&[][..], value));
+    }
+
+    let events = [
+        SequenceData {
+            duration: 0,
+            event: ScoreTrackSequenceEvent::OctaveShift { channel: 1, value: 0x81 },
+        },
+        SequenceData {
+            duration: 20000,
+            event: ScoreTrackSequenceEvent::NoteMessage {
+                channel: 1,
+                note: 12,
+                velocity: Some(100),
+                gate_time: 300,
+            },
+        },
+        SequenceData {
+            duration: 2,
+            event: ScoreTrackSequenceEvent::PitchBend { channel: 3, value: 0x3000 },
+        },
+        SequenceData {
+            duration: 0,
+            event: ScoreTrackSequenceEvent::Exclusive(vec![0x43, 0x03]),
+        },
+    ];
+
+    let data = SequenceData::write_handy(&events);
+    let (remaining, parsed) = SequenceData::parse_handy(&data).unwrap();
+
+    assert!(remaining.is_empty());
+    assert_eq!(parsed.iter().map(|x| x.duration).collect::<Vec<_>>(), [0, 0x407f, 20000 - 0x407f, 2, 0]);
+    assert!(matches!(
+        parsed[0].event,
+        ScoreTrackSequenceEvent::OctaveShift { channel: 1, value: 0x81 }
+    ));
+    assert!(matches!(parsed[1].event, ScoreTrackSequenceEvent::Nop));
+    assert!(matches!(
+        parsed[2].event,
+        ScoreTrackSequenceEvent::NoteMessage {
+            channel: 1,
+            note: 12,
+            velocity: None,
+            gate_time: 300
+        }
+    ));
+    assert!(matches!(
+        parsed[3].event,
+        ScoreTrackSequenceEvent::PitchBend { channel: 3, value: 0x3000 }
+    ));
+    assert!(matches!(parsed[4].event, ScoreTrackSequenceEvent::Exclusive(ref x) if x == &[0x43, 0x03]));
+
+    let statuses = [ChannelType::Melody, ChannelType::Rhythm, ChannelType::NoCare, ChannelType::Melody].map(|channel_type| ChannelStatus {
+        kcs: 0,
+        vs: 0,
+        led: 0,
+        channel_type,
+    });
+    let raw = ChannelStatus::write_handy(&statuses);
+    assert_eq!(raw, 0x1301);
+    assert!(ChannelStatus::parse_handy(raw)
+        .iter()
+        .zip(&statuses)
+        .all(|(x, y)| x.channel_type.to_u8() == y.channel_type.to_u8()));
+}
+
+#[test]
+fn test_hps_to_mobile() -> anyhow::Result<()> {
+    let handy_track = |channel_types: [ChannelType; 4], events: &[SequenceData]| {
+        let statuses = channel_types.map(|channel_type| ChannelStatus {
+            kcs: 0,
+            vs: 0,
+            led: 0,
+            channel_type,
+        });
+        write_score_track(
+            FormatType::HandyPhoneStandard,
+            20,
+            20,
+            &ChannelStatus::write_handy(&statuses).to_be_bytes(),
+            &[],
+            &SequenceData::write_handy(events),
+        )
+        .unwrap()
+    };
+    let melody = [
+        SequenceData {
+            duration: 0,
+            event: ScoreTrackSequenceEvent::OctaveShift { channel: 1, value: 0x81 },
+        },
+        SequenceData {
+            duration: 5,
+            event: ScoreTrackSequenceEvent::NoteMessage {
+                channel: 1,
+                note: 24,
+                velocity: None,
+                gate_time: 3,
+            },
+        },
+    ];
+    let rhythm = [
+        SequenceData {
+            duration: 0,
+            event: ScoreTrackSequenceEvent::ProgramChange { channel: 0, program: 38 },
+        },
+        SequenceData {
+            duration: 0,
+            event: ScoreTrackSequenceEvent::Expression { channel: 0, value: 127 },
+        },
+        SequenceData {
+            duration: 2,
+            event: ScoreTrackSequenceEvent::NoteMessage {
+                channel: 0,
+                note: 1,
+                velocity: None,
+                gate_time: 1,
+            },
+        },
+        // the rhythm track rests past the last melody note
+        SequenceData {
+            duration: 4,
+            event: ScoreTrackSequenceEvent::Nop,
+        },
+    ];
+
+    let mut chunks = Vec::new();
+    write_chunk(&mut chunks, b"CNTI", &[0x00, 0x32, 0x01, 0x00, 0x00]);
+    write_chunk(&mut chunks, b"MTR\x01", &handy_track([0; 4].map(|_| ChannelType::Melody), &melody));
+    write_chunk(
+        &mut chunks,
+        b"MTR\x02",
+        &handy_track(
+            [ChannelType::Rhythm, ChannelType::NoCare, ChannelType::NoCare, ChannelType::NoCare],
+            &rhythm,
+        ),
+    );
+
+    let converted = hps_to_mobile(&write_smaf(&chunks))?;
+    let file = Smaf::parse(&converted)?;
+
+    assert_eq!(file.chunks.len(), 2);
+    assert!(matches!(file.chunks[0], SmafChunk::ContentsInfo(_)));
+    let SmafChunk::ScoreTrack(5, track) = &file.chunks[1] else {
+        panic!("Expected ScoreTrack chunk");
+    };
+    assert_eq!(track.format_type, FormatType::MobileStandardNoCompress);
+    assert_eq!(track.timebase_d, 20);
+    assert_eq!(
+        track.channel_status.iter().map(|x| x.channel_type.to_u8()).collect::<Vec<_>>()[..8],
+        [1, 1, 1, 1, 3, 1, 1, 1]
+    );
+
+    let ScoreTrackChunk::SequenceData(sequence) = &track.chunks[0] else {
+        panic!("Expected SequenceData chunk");
+    };
+    assert_eq!(sequence.iter().map(|x| x.duration).collect::<Vec<_>>(), [2, 3, 1, 0]);
+    assert!(matches!(sequence[2].event, ScoreTrackSequenceEvent::Nop));
+    assert!(matches!(
+        sequence[0].event,
+        ScoreTrackSequenceEvent::NoteMessage {
+            channel: 4,
+            note: 38,
+            velocity: Some(127),
+            gate_time: 1
+        }
+    ));
+    assert!(matches!(
+        sequence[1].event,
+        ScoreTrackSequenceEvent::NoteMessage {
+            channel: 1,
+            note: 48,
+            velocity: Some(127),
+            gate_time: 3
+        }
+    ));
+
+    // 16 mobile channels hold no more than 4 handy phone tracks
+    for number in 3..=5u8 {
+        write_chunk(
+            &mut chunks,
+            &[b'M', b'T', b'R', number],
+            &handy_track([0; 4].map(|_| ChannelType::Melody), &melody),
+        );
+    }
+    assert!(hps_to_mobile(&write_smaf(&chunks)).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_builder() -> anyhow::Result<()> {
+    let data = SmafBuilder::new()
+        .contents_info(0x00, 0x33, 0x01, 0x01, 0x00)
+        .score_track(FormatType::MobileStandardNoCompress, 20, |track| {
+            track
+                .channel_type(9, ChannelType::Rhythm)
+                .program(0, 40)
+                .note(0, 60, 100, 10)
+                .wait(10)
+                .note(9, 36, 90, 5)
+                .exclusive(&[0x43, 0x79, 0x06, 0x7f, 0xf7])
+        })
+        .score_track(FormatType::HandyPhoneStandard, 10, |track| track.octave_shift(1, 1).note(1, 12, 0, 4))
+        .pcm_track(PcmWaveFormat::Adpcm, SamplingRate::Hz8000, BaseBit::Bit4, 1, |track| {
+            track.wave_data(1, &[0x12, 0x34]).volume(0, 100).wait(5).wave(0, 1, 4)
+        })
+        .build()?;
+
+    let file = Smaf::parse(&data)?;
+    assert_eq!(file.length as usize, data.len() - 8);
+    assert_eq!(file.chunks.len(), 4);
+
+    let SmafChunk::ContentsInfo(contents_info) = &file.chunks[0] else {
+        panic!("Expected ContentsInfo chunk");
+    };
+    assert_eq!(contents_info.content_type, 0x33);
+
+    let SmafChunk::ScoreTrack(5, track) = &file.chunks[1] else {
+        panic!("Expected ScoreTrack chunk");
+    };
+    assert_eq!(track.timebase_d, 20);
+    assert_eq!(
+        track.channel_status.iter().map(|x| x.channel_type.to_u8()).collect::<Vec<_>>(),
+        [1, 0, 0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0]
+    );
+    let ScoreTrackChunk::SequenceData(sequence) = &track.chunks[0] else {
+        panic!("Expected SequenceData chunk");
+    };
+    assert_eq!(sequence.iter().map(|x| x.duration).collect::<Vec<_>>(), [0, 0, 10, 0, 0]);
+    assert!(matches!(
+        sequence[2].event,
+        ScoreTrackSequenceEvent::NoteMessage {
+            channel: 9,
+            note: 36,
+            velocity: Some(90),
+            gate_time: 5
+        }
+    ));
+    assert!(matches!(sequence[3].event, ScoreTrackSequenceEvent::Exclusive(ref x) if x == &[0x43, 0x79, 0x06, 0x7f, 0xf7]));
+
+    let SmafChunk::ScoreTrack(1, track) = &file.chunks[2] else {
+        panic!("Expected ScoreTrack chunk");
+    };
+    assert_eq!(track.format_type, FormatType::HandyPhoneStandard);
+    assert_eq!(
+        track.channel_status.iter().map(|x| x.channel_type.to_u8()).collect::<Vec<_>>(),
+        [0, 1, 0, 0]
+    );
+    let ScoreTrackChunk::SequenceData(sequence) = &track.chunks[0] else {
+        panic!("Expected SequenceData chunk");
+    };
+    assert!(matches!(
+        sequence[1].event,
+        ScoreTrackSequenceEvent::NoteMessage {
+            channel: 1,
+            note: 12,
+            velocity: None,
+            gate_time: 4
+        }
+    ));
+
+    let SmafChunk::PCMAudioTrack(
+        0,
+        PCMAudioTrack {
+            format,
+            sampling_freq,
+            chunks,
+            ..
+        },
+    ) = &file.chunks[3]
+    else {
+        panic!("Expected PCMAudioTrack chunk");
+    };
+    assert_eq!((*format, *sampling_freq), (PcmWaveFormat::Adpcm, SamplingRate::Hz8000));
+    let PCMAudioTrackChunk::SequenceData(sequence) = &chunks[0] else {
+        panic!("Expected SequenceData chunk");
+    };
+    assert!(matches!(sequence[0].event, PCMAudioSequenceEvent::Volume { channel: 0, value: 100 }));
+    assert!(matches!(
+        sequence[1],
+        PCMAudioSequenceData {
+            duration: 5,
+            event: PCMAudioSequenceEvent::WaveMessage {
+                channel: 0,
+                wave_number: 1,
+                gate_time: 4
+            }
+        }
+    ));
+    assert!(matches!(chunks[1], PCMAudioTrackChunk::WaveData(1, &[0x12, 0x34])));
+
+    Ok(())
+}
+
+#[test]
+fn test_builder_keeps_trailing_wait() -> anyhow::Result<()> {
+    let data = SmafBuilder::new()
+        .score_track(FormatType::MobileStandardNoCompress, 20, |track| track.note(0, 60, 100, 5).wait(10))
+        .pcm_track(PcmWaveFormat::Adpcm, SamplingRate::Hz8000, BaseBit::Bit4, 1, |track| {
+            track.wave_data(1, &[0x00]).wave(0, 1, 1).wait(7)
+        })
+        .build()?;
+
+    let file = Smaf::parse(&data)?;
+    let SmafChunk::ScoreTrack(5, track) = &file.chunks[1] else {
+        panic!("Expected ScoreTrack chunk");
+    };
+    let ScoreTrackChunk::SequenceData(sequence) = &track.chunks[0] else {
+        panic!("Expected SequenceData chunk");
+    };
+    assert_eq!(sequence[1].duration, 10);
+    assert!(matches!(sequence[1].event, ScoreTrackSequenceEvent::Nop));
+
+    let SmafChunk::PCMAudioTrack(0, track) = &file.chunks[2] else {
+        panic!("Expected PCMAudioTrack chunk");
+    };
+    let PCMAudioTrackChunk::SequenceData(sequence) = &track.chunks[0] else {
+        panic!("Expected SequenceData chunk");
+    };
+    assert_eq!(sequence[1].duration, 7);
+    assert!(matches!(sequence[1].event, PCMAudioSequenceEvent::Nop));
+
+    Ok(())
+}
+
+#[test]
+fn test_builder_rejects_invalid_values() {
+    let pcm_track = |build: fn(smaf::PcmTrackBuilder) -> smaf::PcmTrackBuilder| {
+        SmafBuilder::new()
+            .pcm_track(PcmWaveFormat::Adpcm, SamplingRate::Hz8000, BaseBit::Bit4, 1, build)
+            .build()
+    };
+    assert!(pcm_track(|track| track.wave_data(1, &[0x00]).wave(0, 1, 0)).is_ok());
+    assert!(pcm_track(|track| track.wave(0, 0, 0)).is_err());
+    assert!(pcm_track(|track| track.wave(4, 1, 0)).is_err());
+    assert!(pcm_track(|track| track.wave_data(64, &[0x00])).is_err());
+    assert!(pcm_track(|track| track.exclusive(&[0x00; 256])).is_err());
+    assert!(pcm_track(|track| track.exclusive(&[0x00; 255])).is_ok());
+
+    let mobile = |build: fn(smaf::ScoreTrackBuilder) -> smaf::ScoreTrackBuilder| {
+        SmafBuilder::new().score_track(FormatType::MobileStandardNoCompress, 20, build).build()
+    };
+    assert!(mobile(|track| track.note(15, 127, 127, 1).control_change(0, 127, 127).pitch_bend(0, 0x3fff)).is_ok());
+    assert!(mobile(|track| track.note(16, 60, 100, 1)).is_err());
+    assert!(mobile(|track| track.channel_type(16, ChannelType::Rhythm)).is_err());
+    assert!(mobile(|track| track.note(0, 128, 100, 1)).is_err());
+    assert!(mobile(|track| track.note(0, 60, 128, 1)).is_err());
+    assert!(mobile(|track| track.control_change(0, 7, 128)).is_err());
+    assert!(mobile(|track| track.volume(0, 128)).is_err());
+    assert!(mobile(|track| track.bank_select(0, 0x80)).is_err());
+    assert!(mobile(|track| track.pitch_bend(0, 0x4000)).is_err());
+    assert!(mobile(|track| track.octave_shift(0, 1)).is_err());
+
+    let handy = |build: fn(smaf::ScoreTrackBuilder) -> smaf::ScoreTrackBuilder| {
+        SmafBuilder::new().score_track(FormatType::HandyPhoneStandard, 20, build).build()
+    };
+    assert!(handy(|track| track.note(3, 48, 0, 1).octave_shift(3, 0x84).bank_select(0, 0x80)).is_ok());
+    assert!(handy(|track| track.note(4, 24, 0, 1)).is_err());
+    assert!(handy(|track| track.note(0, 0, 0, 1)).is_err());
+    assert!(handy(|track| track.note(0, 49, 0, 1)).is_err());
+    assert!(handy(|track| track.octave_shift(0, 5)).is_err());
+    assert!(handy(|track| track.control_change(0, 7, 100)).is_err());
+
+    assert!(SmafBuilder::new()
+        .score_track(FormatType::MobileStandardNoCompress, 3, |track| track)
+        .build()
+        .is_err());
+    assert!(SmafBuilder::new()
+        .pcm_track(PcmWaveFormat::Adpcm, SamplingRate::Hz8000, BaseBit::Bit4, 3, |track| track)
+        .build()
+        .is_err());
+
+    assert!(write_score_track(FormatType::MobileStandardNoCompress, 3, 4, &[0; 16], &[], &[]).is_err());
+    assert!(write_score_track(FormatType::MobileStandardNoCompress, 4, 3, &[0; 16], &[], &[]).is_err());
+
+    let tracks = (0..256).fold(SmafBuilder::new(), |builder, _| {
+        builder.pcm_track(PcmWaveFormat::Adpcm, SamplingRate::Hz8000, BaseBit::Bit4, 1, |track| track)
+    });
+    assert!(tracks.build().is_ok());
+    let tracks = (0..257).fold(SmafBuilder::new(), |builder, _| {
+        builder.pcm_track(PcmWaveFormat::Adpcm, SamplingRate::Hz8000, BaseBit::Bit4, 1, |track| track)
+    });
+    assert!(tracks.build().is_err());
+}
+
+#[test]
+fn test_sampling_rate_codes() {
+    assert_eq!(SamplingRate::from(2).hz(), Some(11025));
+    assert_eq!(SamplingRate::from_hz(11025), Some(SamplingRate::Hz11025));
+    assert_eq!(SamplingRate::from_hz(11000), None);
+    assert_eq!(SamplingRate::from(9), SamplingRate::Unknown(9));
+    assert_eq!(SamplingRate::from(9).hz(), None);
+    assert!((0..16).all(|x| SamplingRate::from(x).to_u8() == x));
+}
+
+#[test]
+fn test_adpcm_round_trip() {
+    let samples = (0..2000)
+        .map(|x| ((x as f32 * 440.0 / 8000.0 * core::f32::consts::TAU).sin() * 12000.0) as i16)
+        .collect::<Vec<_>>();
+    let error = |decoded: &[i16]| {
+        let total = samples.iter().zip(decoded).map(|(x, y)| (*x as i64 - *y as i64).pow(2)).sum::<i64>();
+        ((total / samples.len() as i64) as f64).sqrt()
+    };
+
+    let fast = decode_adpcm(&encode_adpcm(&samples, 1, AdpcmQuality::Fast), 1);
+    let search = decode_adpcm(&encode_adpcm(&samples, 1, AdpcmQuality::Search), 1);
+    assert_eq!(fast.len(), samples.len());
+    // rms error within 5% of the amplitude, the search doing better
+    assert!(error(&fast) < 600.0, "{}", error(&fast));
+    assert!(error(&search) < error(&fast), "{} {}", error(&search), error(&fast));
+
+    // left and right keep separate state
+    let stereo = samples.iter().flat_map(|&x| [x, -x]).collect::<Vec<_>>();
+    let decoded = decode_adpcm(&encode_adpcm(&stereo, 2, AdpcmQuality::Fast), 2);
+    assert_eq!(decoded.iter().step_by(2).copied().collect::<Vec<_>>(), fast);
+
+    assert_eq!(encode_adpcm(&[0; 3], 1, AdpcmQuality::Fast).len(), 2);
+}
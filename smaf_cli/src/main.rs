@@ -1,15 +1,160 @@
 use core::time::Duration;
-use std::{env::args, fs};
+use std::{env::args, fmt::Write, fs, path::Path};
 
 use midir::{MidiOutput, MidiOutputConnection};
 use rodio::{buffer::SamplesBuffer, OutputStream, Sink};
 use tokio::time::sleep;
 
-use smaf_player::{parse_smaf, SmafEvent};
+use smaf::{AdpcmQuality, FormatType};
+use smaf_player::{
+    add_wave, events_to_hps, extract_waves, import_midi, parse_smaf, read_midi, render, write_midi, write_wav, EventSource, HpsReport, ImportOptions,
+    ImportReport, SmafEvent, TrackKind, WaveImportOptions, WaveTarget,
+};
+
+const DEFAULT_SAMPLE_RATE: u32 = 44100;
+const USAGE: &str = "Usage: smaf_cli <file>
+       smaf_cli render <file> <output.wav> [sample rate]
+       smaf_cli to-midi <file> <output.mid>
+       smaf_cli from-midi <file.mid> <output> [--compress | --hps]
+       smaf_cli add-wave <file> <input.wav> <output> [--stream] [--search]
+       smaf_cli extract-waves <file> <output directory>";
 
 #[tokio::main(flavor = "current_thread")]
 pub async fn main() {
-    let file = args().nth(1).expect("No file given");
+    let args = args().skip(1).collect::<Vec<_>>();
+
+    match args.as_slice() {
+        [command, input, output, rest @ ..] if command == "render" => {
+            let sample_rate = rest
+                .first()
+                .map(|x| x.parse().expect("Invalid sample rate"))
+                .unwrap_or(DEFAULT_SAMPLE_RATE);
+            render_file(input, output, sample_rate);
+        }
+        [command, input, output] if command == "to-midi" => convert_to_midi(input, output),
+        [command, input, output, rest @ ..] if command == "from-midi" => convert_from_midi(input, output, rest),
+        [command, input, wave, output, rest @ ..] if command == "add-wave" => add_wave_file(input, wave, output, rest),
+        [command, input, output] if command == "extract-waves" => extract_wave_files(input, output),
+        [file] => play_file(file).await,
+        _ => eprintln!("{USAGE}"),
+    }
+}
+
+// renders without any audio or midi device, for servers
+fn render_file(input: &str, output: &str, sample_rate: u32) {
+    let data = fs::read(input).expect("Failed to read file");
+    let samples = render(&data, sample_rate).expect("Failed to render file");
+
+    fs::write(output, write_wav(&samples, sample_rate, 2)).expect("Failed to write file");
+}
+
+fn convert_to_midi(input: &str, output: &str) {
+    let data = fs::read(input).expect("Failed to read file");
+    let events = parse_smaf(&data).expect("Failed to parse file");
+
+    fs::write(output, write_midi(&events)).expect("Failed to write file");
+}
+
+fn convert_from_midi(input: &str, output: &str, flags: &[String]) {
+    let data = fs::read(input).expect("Failed to read file");
+
+    let smaf = if flags.iter().any(|x| x == "--hps") {
+        let events = read_midi(&data).expect("Failed to read midi file");
+        let (smaf, report) = events_to_hps(&events, None).expect("Failed to convert file");
+        if report != HpsReport::default() {
+            eprintln!("{report:?}");
+        }
+        smaf
+    } else {
+        let options = ImportOptions {
+            format_type: if flags.iter().any(|x| x == "--compress") {
+                FormatType::MobileStandardCompress
+            } else {
+                FormatType::MobileStandardNoCompress
+            },
+            ..Default::default()
+        };
+        let (smaf, report) = import_midi(&data, &options).expect("Failed to convert file");
+        if report != ImportReport::default() {
+            eprintln!("{report:?}");
+        }
+        smaf
+    };
+
+    fs::write(output, smaf).expect("Failed to write file");
+}
+
+fn add_wave_file(input: &str, wave: &str, output: &str, flags: &[String]) {
+    let data = fs::read(input).expect("Failed to read file");
+    let wave = fs::read(wave).expect("Failed to read wave file");
+
+    let options = WaveImportOptions {
+        target: if flags.iter().any(|x| x == "--stream") {
+            WaveTarget::StreamWave
+        } else {
+            WaveTarget::PcmAudioTrack
+        },
+        quality: if flags.iter().any(|x| x == "--search") {
+            AdpcmQuality::Search
+        } else {
+            AdpcmQuality::Fast
+        },
+        ..Default::default()
+    };
+    let (smaf, number) = add_wave(&data, &wave, &options).expect("Failed to add wave");
+    match options.target {
+        WaveTarget::PcmAudioTrack => eprintln!("Added pcm audio track {number}"),
+        WaveTarget::StreamWave => eprintln!("Added stream wave {number}"),
+    }
+
+    fs::write(output, smaf).expect("Failed to write file");
+}
+
+// wave_<n>.wav per decoded wave in file order and a manifest.csv describing where each came from,
+// waves that could not be decoded are listed with the reason and no file
+fn extract_wave_files(input: &str, output: &str) {
+    let data = fs::read(input).expect("Failed to read file");
+    let waves = extract_waves(&data).expect("Failed to extract waves");
+
+    let output = Path::new(output);
+    fs::create_dir_all(output).expect("Failed to create directory");
+
+    let mut manifest = String::from("file,chunk,wave,channels,sampling_rate,samples,skipped\n");
+    for (index, wave) in waves.iter().enumerate() {
+        let name = if wave.skipped.is_some() {
+            String::new()
+        } else {
+            let name = format!("wave_{index:02}.wav");
+            fs::write(output.join(&name), write_wav(&wave.samples, wave.sampling_rate, wave.channels as _)).expect("Failed to write file");
+            name
+        };
+
+        let (chunk, wave_chunk) = match wave.kind {
+            TrackKind::PCMAudioTrack => ("ATR", "Awa"),
+            _ => ("MTR", "Mwa"),
+        };
+        writeln!(
+            manifest,
+            "{name},{chunk}{},{wave_chunk}{},{},{},{},{}",
+            wave.track,
+            wave.wave_number,
+            wave.channels,
+            wave.sampling_rate,
+            wave.samples.len() / wave.channels as usize,
+            // quoted, reasons may contain commas
+            wave.skipped
+                .as_ref()
+                .map_or(String::new(), |reason| format!("\"{}\"", reason.replace('"', "\"\""))),
+        )
+        .unwrap();
+    }
+
+    fs::write(output.join("manifest.csv"), manifest).expect("Failed to write manifest");
+    let skipped = waves.iter().filter(|wave| wave.skipped.is_some()).count();
+    eprintln!("Extracted {} waves, skipped {skipped}", waves.len() - skipped);
+}
+
+async fn play_file(file: &str) {
     let data = fs::read(file).expect("Failed to read file");
 
     let midi_out = MidiOutput::new("smaf_cli").unwrap();
@@ -20,16 +165,16 @@ pub async fn main() {
     let (_output_stream, stream_handle) = OutputStream::try_default().unwrap();
     let sink = Sink::try_new(&stream_handle).unwrap();
 
-    let events = parse_smaf(&data);
+    let events = parse_smaf(&data).expect("Failed to parse file");
 
     loop {
         play_events(&events, &mut midi_out, &sink).await;
     }
 }
 
-async fn play_events(events: &[(usize, SmafEvent)], midi_out: &mut MidiOutputConnection, sink: &Sink) {
+async fn play_events(events: &[(usize, EventSource, SmafEvent)], midi_out: &mut MidiOutputConnection, sink: &Sink) {
     let mut now = 0;
-    for (time, event) in events {
+    for (time, _, event) in events {
         sleep(Duration::from_millis(time.saturating_sub(now) as u64)).await;
 
         match event {
@@ -61,7 +206,7 @@ async fn play_events(events: &[(usize, SmafEvent)], midi_out: &mut MidiOutputCon
             SmafEvent::MidiSysEx(data) => {
                 midi_out.send(data).unwrap();
             }
-            SmafEvent::End => {}
+            SmafEvent::WaveStop | SmafEvent::NoteDropped { .. } | SmafEvent::End => {}
         }
 
         now = *time;
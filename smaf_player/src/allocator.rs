@@ -0,0 +1,361 @@
+use alloc::{collections::BTreeMap, collections::VecDeque, vec::Vec};
+
+use crate::{EventSource, SmafEvent, MIDI_DRUM_CHANNEL};
+
+// channel ids below 16 are midi channels owned by a single logical channel.
+// logical channels that did not get a midi channel of their own are numbered from here on,
+// and borrow idle midi channels while they play.
+pub(crate) const FIRST_VIRTUAL_CHANNEL: u8 = 16;
+
+#[derive(Clone, Eq, PartialEq)]
+struct ChannelState {
+    program: Option<u8>,
+    controls: [Option<u8>; 128],
+    pitch_bend: Option<u16>,
+}
+
+impl ChannelState {
+    fn new() -> Self {
+        Self {
+            program: None,
+            controls: [None; 128],
+            pitch_bend: None,
+        }
+    }
+
+    fn apply(&mut self, event: &SmafEvent) {
+        match *event {
+            SmafEvent::MidiProgramChange { program, .. } => self.program = Some(program),
+            SmafEvent::MidiControlChange { control, value, .. } => self.controls[(control & 0x7f) as usize] = Some(value),
+            SmafEvent::MidiPitchBend { value, .. } => self.pitch_bend = Some(value),
+            _ => {}
+        }
+    }
+
+    // events turning `current` into `self` on `channel`, bank select first so the program change picks it up
+    fn transition_from(&self, current: &ChannelState, channel: u8) -> Vec<SmafEvent> {
+        let mut result = Vec::new();
+        let control_change = |control: usize| {
+            let value = match (self.controls[control], current.controls[control]) {
+                (Some(target), current) if current != Some(target) => target,
+                (None, Some(_)) => default_control_value(control as u8)?,
+                _ => return None,
+            };
+            Some(SmafEvent::MidiControlChange {
+                channel,
+                control: control as u8,
+                value,
+            })
+        };
+
+        result.extend(control_change(0));
+        result.extend(control_change(32));
+        match (self.program, current.program) {
+            (Some(program), current) if current != Some(program) => result.push(SmafEvent::MidiProgramChange { channel, program }),
+            (None, Some(_)) => result.push(SmafEvent::MidiProgramChange { channel, program: 0 }),
+            _ => {}
+        }
+        result.extend((1..128).filter(|&control| control != 32).filter_map(control_change));
+        match (self.pitch_bend, current.pitch_bend) {
+            (Some(value), current) if current != Some(value) => result.push(SmafEvent::MidiPitchBend { channel, value }),
+            (None, Some(_)) => result.push(SmafEvent::MidiPitchBend { channel, value: 8192 }),
+            _ => {}
+        }
+
+        result
+    }
+}
+
+fn default_control_value(control: u8) -> Option<u8> {
+    match control {
+        0 | 1 | 32 | 64 => Some(0),
+        7 => Some(100),
+        10 => Some(64),
+        11 => Some(127),
+        _ => None,
+    }
+}
+
+struct RealChannel {
+    owner: u8,
+    state: ChannelState,
+    sounding: Vec<(u8, u8, EventSource)>, // (owner, note, source of the note on)
+    last_used: usize,
+}
+
+struct ChannelAllocator {
+    channels: Vec<RealChannel>,
+    states: BTreeMap<u8, ChannelState>,
+    bindings: BTreeMap<u8, u8>,
+    notes: BTreeMap<(u8, u8), VecDeque<Option<u8>>>,
+    result: Vec<(usize, EventSource, SmafEvent)>,
+}
+
+impl ChannelAllocator {
+    fn new(capacity: usize) -> Self {
+        Self {
+            channels: (0..16)
+                .map(|channel| RealChannel {
+                    owner: channel,
+                    state: ChannelState::new(),
+                    sounding: Vec::new(),
+                    last_used: 0,
+                })
+                .collect(),
+            states: BTreeMap::new(),
+            bindings: BTreeMap::new(),
+            notes: BTreeMap::new(),
+            result: Vec::with_capacity(capacity),
+        }
+    }
+
+    fn emit(&mut self, time: usize, source: EventSource, channel: u8, event: SmafEvent) {
+        let real = &mut self.channels[channel as usize];
+        real.state.apply(&event);
+        real.last_used = time;
+        self.result.push((time, source, event));
+    }
+
+    // midi channel the id currently plays on, taking it back or borrowing one if needed
+    fn acquire(&mut self, time: usize, source: EventSource, id: u8) -> Option<u8> {
+        if id < FIRST_VIRTUAL_CHANNEL {
+            if self.channels[id as usize].owner != id {
+                self.hand_over(time, source, id, id);
+            }
+            return Some(id);
+        }
+
+        if let Some(&channel) = self.bindings.get(&id) {
+            if self.channels[channel as usize].owner == id {
+                return Some(channel);
+            }
+        }
+
+        let state = self.states.entry(id).or_insert_with(ChannelState::new).clone();
+        let channel = self
+            .channels
+            .iter()
+            .enumerate()
+            .filter(|(channel, real)| *channel as u8 != MIDI_DRUM_CHANNEL && real.sounding.is_empty())
+            .min_by_key(|(_, real)| (real.state != state, real.last_used))
+            .map(|(channel, _)| channel as u8)?;
+
+        self.hand_over(time, source, channel, id);
+        self.bindings.insert(id, channel);
+        Some(channel)
+    }
+
+    // state replay is attributed to the event that needed the channel, cut notes keep their own source
+    fn hand_over(&mut self, time: usize, source: EventSource, channel: u8, id: u8) {
+        for (owner, note, note_source) in core::mem::take(&mut self.channels[channel as usize].sounding) {
+            if let Some(pending) = self.notes.get_mut(&(owner, note)) {
+                if let Some(slot) = pending.iter_mut().find(|slot| **slot == Some(channel)) {
+                    *slot = None;
+                }
+            }
+            self.emit(time, note_source, channel, SmafEvent::MidiNoteOff { channel, note, velocity: 0 });
+            self.result.push((time, note_source, SmafEvent::NoteDropped { channel: owner, note }));
+        }
+
+        let target = self.states.entry(id).or_insert_with(ChannelState::new).clone();
+        let transition = target.transition_from(&self.channels[channel as usize].state, channel);
+        for event in transition {
+            self.emit(time, source, channel, event);
+        }
+        self.channels[channel as usize].owner = id;
+    }
+
+    fn process(&mut self, time: usize, source: EventSource, event: SmafEvent) {
+        let id = match event {
+            SmafEvent::MidiNoteOn { channel, .. }
+            | SmafEvent::MidiNoteOff { channel, .. }
+            | SmafEvent::MidiProgramChange { channel, .. }
+            | SmafEvent::MidiControlChange { channel, .. }
+            | SmafEvent::MidiPitchBend { channel, .. } => channel,
+            _ => {
+                self.result.push((time, source, event));
+                return;
+            }
+        };
+
+        match event {
+            SmafEvent::MidiNoteOn { note, velocity, .. } => {
+                let Some(channel) = self.acquire(time, source, id) else {
+                    self.notes.entry((id, note)).or_default().push_back(None);
+                    self.result.push((time, source, SmafEvent::NoteDropped { channel: id, note }));
+                    return;
+                };
+                self.notes.entry((id, note)).or_default().push_back(Some(channel));
+                self.channels[channel as usize].sounding.push((id, note, source));
+                self.emit(time, source, channel, SmafEvent::MidiNoteOn { channel, note, velocity });
+            }
+            SmafEvent::MidiNoteOff { note, velocity, .. } => {
+                let channel = match self.notes.get_mut(&(id, note)).and_then(|pending| pending.pop_front()) {
+                    Some(Some(channel)) => channel,
+                    Some(None) => return,
+                    None if id < FIRST_VIRTUAL_CHANNEL => id,
+                    None => return,
+                };
+                let sounding = &mut self.channels[channel as usize].sounding;
+                if let Some(index) = sounding
+                    .iter()
+                    .position(|&(owner, sounding_note, _)| (owner, sounding_note) == (id, note))
+                {
+                    sounding.remove(index);
+                }
+                self.emit(time, source, channel, SmafEvent::MidiNoteOff { channel, note, velocity });
+            }
+            event => {
+                let channel = if id < FIRST_VIRTUAL_CHANNEL {
+                    self.acquire(time, source, id)
+                } else {
+                    // logical channels without a midi channel only pick up their state when they play
+                    self.bindings
+                        .get(&id)
+                        .copied()
+                        .filter(|&channel| self.channels[channel as usize].owner == id)
+                };
+                self.states.entry(id).or_insert_with(ChannelState::new).apply(&event);
+                let Some(channel) = channel else {
+                    return;
+                };
+
+                let event = match event {
+                    SmafEvent::MidiProgramChange { program, .. } => SmafEvent::MidiProgramChange { channel, program },
+                    SmafEvent::MidiControlChange { control, value, .. } => SmafEvent::MidiControlChange { channel, control, value },
+                    SmafEvent::MidiPitchBend { value, .. } => SmafEvent::MidiPitchBend { channel, value },
+                    event => event,
+                };
+                self.emit(time, source, channel, event);
+            }
+        }
+    }
+}
+
+// maps channel ids onto the 16 midi channels, reusing idle channels in time order.
+// `events` must be sorted by time; notes that find no idle channel are dropped and reported.
+pub(crate) fn allocate_channels(events: Vec<(usize, EventSource, SmafEvent)>) -> Vec<(usize, EventSource, SmafEvent)> {
+    let mut allocator = ChannelAllocator::new(events.len());
+    for (time, source, event) in events {
+        allocator.process(time, source, event);
+    }
+
+    allocator.result
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{vec, vec::Vec};
+
+    use super::allocate_channels;
+    use crate::{EventSource, SmafEvent, TrackKind};
+
+    fn allocate(events: Vec<(usize, SmafEvent)>) -> Vec<(usize, SmafEvent)> {
+        let source = EventSource {
+            kind: TrackKind::ScoreTrack,
+            track: 0,
+            channel: None,
+            event_index: None,
+        };
+        let events = events.into_iter().map(|(time, event)| (time, source, event)).collect();
+
+        allocate_channels(events).into_iter().map(|(time, _, event)| (time, event)).collect()
+    }
+
+    fn note(time: usize, channel: u8, note: u8, duration: usize) -> [(usize, SmafEvent); 2] {
+        [
+            (
+                time,
+                SmafEvent::MidiNoteOn {
+                    channel,
+                    note,
+                    velocity: 100,
+                },
+            ),
+            (time + duration, SmafEvent::MidiNoteOff { channel, note, velocity: 0 }),
+        ]
+    }
+
+    fn sorted(mut events: Vec<(usize, SmafEvent)>) -> Vec<(usize, SmafEvent)> {
+        events.sort_by_key(|(time, event)| (*time, matches!(event, SmafEvent::MidiNoteOn { .. })));
+        events
+    }
+
+    #[test]
+    fn keeps_owned_channels_untouched() {
+        let mut events = vec![(0, SmafEvent::MidiProgramChange { channel: 3, program: 40 })];
+        events.extend(note(0, 3, 60, 10));
+
+        let result = allocate(events);
+        assert_eq!(result.len(), 3);
+        assert!(matches!(result[0], (0, SmafEvent::MidiProgramChange { channel: 3, program: 40 })));
+        assert!(matches!(result[2], (10, SmafEvent::MidiNoteOff { channel: 3, note: 60, .. })));
+    }
+
+    #[test]
+    fn borrows_idle_channel_and_restores_its_state() {
+        let mut events = vec![
+            (0, SmafEvent::MidiProgramChange { channel: 0, program: 40 }),
+            (0, SmafEvent::MidiProgramChange { channel: 16, program: 41 }),
+        ];
+        for channel in 0..16 {
+            events.extend(note(0, channel, 60, 10));
+        }
+        events.extend(note(20, 16, 62, 10));
+        let result = allocate(sorted(events));
+
+        // the virtual channel plays on the least recently used midi channel with its own program
+        let borrowed = result
+            .iter()
+            .find_map(|(_, event)| match event {
+                SmafEvent::MidiNoteOn { channel, note: 62, .. } => Some(*channel),
+                _ => None,
+            })
+            .unwrap();
+        assert!(borrowed < 16 && borrowed != 9);
+        assert!(result
+            .iter()
+            .any(|(time, event)| *time == 20 && matches!(event, SmafEvent::MidiProgramChange { channel, program: 41 } if *channel == borrowed)));
+        assert!(!result.iter().any(|(_, event)| matches!(event, SmafEvent::NoteDropped { .. })));
+    }
+
+    #[test]
+    fn reuses_idle_channel_with_matching_state_without_setup() {
+        let mut events = vec![
+            (0, SmafEvent::MidiProgramChange { channel: 0, program: 40 }),
+            (0, SmafEvent::MidiProgramChange { channel: 1, program: 41 }),
+            (0, SmafEvent::MidiProgramChange { channel: 17, program: 41 }),
+        ];
+        events.extend(note(0, 0, 60, 10));
+        events.extend(note(0, 1, 60, 10));
+        events.extend(note(20, 17, 62, 10));
+        let result = allocate(sorted(events));
+
+        assert!(result
+            .iter()
+            .any(|(_, event)| matches!(event, SmafEvent::MidiNoteOn { channel: 1, note: 62, .. })));
+        assert_eq!(
+            result
+                .iter()
+                .filter(|(_, event)| matches!(event, SmafEvent::MidiProgramChange { .. }))
+                .count(),
+            2
+        );
+    }
+
+    #[test]
+    fn reports_notes_without_idle_channel() {
+        let mut events = Vec::new();
+        for channel in 0..16 {
+            events.extend(note(0, channel, 60, 100));
+        }
+        events.extend(note(10, 16, 62, 10));
+        let result = allocate(sorted(events));
+
+        assert!(result
+            .iter()
+            .any(|(time, event)| *time == 10 && matches!(event, SmafEvent::NoteDropped { channel: 16, note: 62 })));
+        assert!(!result.iter().any(|(_, event)| matches!(event, SmafEvent::MidiNoteOn { note: 62, .. })));
+        assert!(!result.iter().any(|(_, event)| matches!(event, SmafEvent::MidiNoteOff { note: 62, .. })));
+    }
+}
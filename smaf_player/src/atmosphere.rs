@@ -0,0 +1,58 @@
+use alloc::{vec, vec::Vec};
+
+// extra layers emulating the yamaha ma ambience voice (bank 0x7c/0x01, program 0x62) on gm synths
+#[derive(Copy, Clone, Debug)]
+pub struct AtmosphereLayer {
+    pub channel: u8,
+    pub program: u8,
+    pub velocity_percent: u8,
+    pub note_offset: i8,
+    pub pan: u8,
+    pub pitch_bend: u16,
+    pub gate_extension_ms: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct AtmosphereConfig {
+    pub enabled: bool,
+    pub source_gate_extension_ms: usize,
+    pub layers: Vec<AtmosphereLayer>,
+}
+
+impl AtmosphereConfig {
+    pub fn disabled() -> Self {
+        Self {
+            enabled: false,
+            ..Self::default()
+        }
+    }
+}
+
+impl Default for AtmosphereConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            source_gate_extension_ms: 120,
+            layers: vec![
+                AtmosphereLayer {
+                    channel: 15,
+                    program: 99,
+                    velocity_percent: 42,
+                    note_offset: 0,
+                    pan: 52,
+                    pitch_bend: 8192 - 170,
+                    gate_extension_ms: 220,
+                },
+                AtmosphereLayer {
+                    channel: 14,
+                    program: 94,
+                    velocity_percent: 30,
+                    note_offset: -12,
+                    pan: 76,
+                    pitch_bend: 8192 + 130,
+                    gate_extension_ms: 360,
+                },
+            ],
+        }
+    }
+}
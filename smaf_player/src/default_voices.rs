@@ -0,0 +1,229 @@
+// built-in fm voices in the spirit of the ma-3 rom set, for files that don't define their own.
+// melody voices live in bank 0x7c/0x00 (gm programs) and 0x7c/0x01 (ma variations), drums in 0x7d/0x00 keyed by note.
+
+use alloc::vec::Vec;
+
+use crate::{
+    fm::{FmOperator, FmVoice},
+    voice::FmVoiceBank,
+    MA_MELODY_BANK, MA_MELODY_VARIATIONS, MA_RHYTHM_BANK, MA_RHYTHM_KEYS,
+};
+
+// multiple, total level, attack rate, decay rate, sustain level, sustain rate, release rate, waveform
+type Operator = [u8; 8];
+
+struct Patch {
+    algorithm: u8,
+    feedback: u8,
+    vibrato: bool,
+    operators: &'static [Operator],
+}
+
+const fn patch(algorithm: u8, feedback: u8, vibrato: bool, operators: &'static [Operator]) -> Patch {
+    Patch {
+        algorithm,
+        feedback,
+        vibrato,
+        operators,
+    }
+}
+
+// one patch per gm instrument family
+static FAMILIES: [Patch; 16] = [
+    patch(0, 4, false, &[[1, 34, 15, 5, 6, 3, 7, 0], [1, 0, 15, 3, 6, 2, 7, 0]]), // piano
+    patch(0, 0, false, &[[7, 36, 15, 6, 8, 3, 6, 0], [2, 0, 15, 4, 10, 3, 5, 0]]), // chromatic percussion
+    patch(1, 2, true, &[[1, 8, 15, 0, 0, 0, 9, 0], [2, 14, 15, 0, 0, 0, 9, 0]]),  // organ
+    patch(0, 5, false, &[[1, 30, 15, 6, 7, 3, 7, 0], [1, 0, 15, 4, 8, 3, 6, 0]]), // guitar
+    patch(0, 5, false, &[[1, 26, 15, 7, 8, 4, 8, 0], [1, 0, 15, 4, 6, 3, 8, 0]]), // bass
+    patch(0, 3, true, &[[1, 32, 8, 2, 2, 0, 6, 0], [1, 0, 9, 1, 2, 0, 6, 0]]),    // strings
+    patch(
+        5,
+        3,
+        true,
+        &[
+            [1, 34, 9, 2, 2, 0, 6, 0],
+            [1, 2, 9, 1, 2, 0, 6, 0],
+            [1, 36, 9, 2, 2, 0, 6, 0],
+            [1, 4, 9, 1, 2, 0, 6, 0],
+        ],
+    ), // ensemble
+    patch(0, 6, true, &[[1, 26, 11, 3, 3, 1, 7, 0], [1, 0, 12, 2, 2, 1, 7, 0]]),  // brass
+    patch(0, 5, true, &[[1, 30, 12, 2, 2, 0, 8, 0], [1, 0, 13, 2, 2, 0, 8, 0]]),  // reed
+    patch(0, 0, true, &[[1, 44, 11, 1, 1, 0, 8, 0], [1, 0, 12, 1, 1, 0, 8, 0]]),  // pipe
+    patch(0, 7, false, &[[1, 22, 15, 0, 0, 0, 8, 0], [1, 0, 15, 0, 0, 0, 8, 0]]), // synth lead
+    patch(
+        5,
+        2,
+        true,
+        &[
+            [1, 36, 6, 1, 2, 0, 5, 0],
+            [1, 4, 5, 1, 2, 0, 5, 0],
+            [2, 40, 6, 1, 2, 0, 5, 0],
+            [1, 6, 5, 1, 2, 0, 5, 0],
+        ],
+    ), // synth pad
+    patch(
+        4,
+        4,
+        true,
+        &[
+            [3, 40, 10, 2, 4, 1, 5, 0],
+            [1, 36, 12, 3, 4, 1, 6, 0],
+            [2, 32, 13, 3, 4, 1, 6, 0],
+            [1, 0, 12, 2, 3, 1, 6, 0],
+        ],
+    ), // synth effects
+    patch(0, 4, false, &[[3, 32, 15, 6, 8, 4, 7, 0], [1, 0, 15, 5, 8, 3, 7, 0]]), // ethnic
+    patch(0, 2, false, &[[1, 30, 15, 9, 15, 8, 9, 0], [1, 0, 15, 7, 15, 6, 8, 0]]), // percussive
+    patch(0, 7, false, &[[5, 18, 13, 4, 6, 2, 7, 0], [1, 0, 12, 3, 6, 2, 7, 0]]), // sound effects
+];
+
+// per program tweaks of its family patch: modulator total level offset, modulator multiple, carrier waveform
+#[rustfmt::skip]
+static PROGRAMS: [(i8, u8, u8); 128] = [
+    (0, 1, 0), (-6, 1, 0), (-4, 2, 0), (-2, 1, 0), (2, 14, 0), (-2, 5, 0), (-8, 3, 0), (-6, 1, 1), // piano
+    (4, 7, 0), (0, 4, 0), (2, 9, 0), (0, 4, 2), (4, 2, 0), (2, 3, 0), (-2, 7, 0), (4, 5, 0), // chromatic percussion
+    (0, 1, 0), (-4, 3, 0), (-6, 1, 0), (-2, 1, 0), (4, 2, 0), (2, 1, 3), (0, 1, 1), (2, 3, 1), // organ
+    (0, 1, 0), (-2, 2, 0), (4, 3, 0), (2, 1, 0), (-4, 1, 1), (-10, 1, 0), (-14, 1, 0), (4, 5, 0), // guitar
+    (4, 1, 0), (0, 1, 0), (-2, 2, 0), (6, 1, 0), (-6, 3, 0), (-8, 3, 1), (-10, 1, 0), (-6, 1, 0), // bass
+    (0, 1, 0), (2, 1, 0), (0, 1, 0), (2, 1, 0), (4, 1, 0), (6, 3, 0), (2, 4, 0), (8, 1, 0), // strings
+    (0, 1, 0), (2, 1, 0), (-2, 1, 0), (0, 2, 0), (6, 1, 2), (4, 1, 2), (8, 2, 0), (-4, 5, 0), // ensemble
+    (-2, 1, 0), (0, 1, 0), (4, 1, 0), (2, 1, 0), (0, 1, 0), (-2, 1, 0), (-4, 1, 0), (-2, 2, 0), // brass
+    (0, 1, 0), (-2, 1, 0), (0, 1, 0), (2, 1, 0), (2, 2, 0), (4, 2, 0), (2, 3, 0), (6, 2, 0), // reed
+    (6, 1, 0), (4, 1, 0), (4, 2, 0), (8, 1, 0), (2, 1, 0), (10, 1, 0), (6, 2, 0), (4, 1, 1), // pipe
+    (0, 1, 6), (0, 1, 0), (-2, 1, 1), (-4, 2, 0), (0, 1, 2), (2, 1, 4), (-4, 1, 0), (-6, 1, 6), // synth lead
+    (0, 1, 0), (4, 2, 0), (2, 1, 1), (6, 1, 0), (4, 2, 0), (0, 3, 0), (2, 2, 0), (-2, 1, 0), // synth pad
+    (2, 3, 0), (0, 5, 0), (4, 1, 0), (2, 7, 0), (0, 3, 0), (2, 1, 0), (4, 2, 0), (0, 9, 0), // synth effects
+    (0, 3, 0), (2, 1, 0), (0, 5, 0), (4, 3, 0), (2, 7, 0), (0, 1, 0), (4, 2, 0), (0, 1, 1), // ethnic
+    (2, 7, 0), (0, 3, 0), (0, 1, 0), (4, 1, 0), (-4, 1, 0), (-2, 2, 0), (0, 1, 0), (-8, 15, 0), // percussive
+    (0, 13, 0), (2, 9, 0), (-4, 15, 0), (0, 3, 0), (0, 7, 0), (-6, 11, 0), (-8, 5, 0), (-10, 15, 0), // sound effects
+];
+
+// (modulator feedback, [modulator, carrier]) for each drum kind
+static DRUMS: [(u8, [Operator; 2]); 9] = [
+    (0, [[1, 30, 15, 9, 15, 9, 9, 0], [1, 0, 15, 7, 15, 7, 8, 0]]),         // kick
+    (7, [[12, 10, 15, 8, 15, 8, 9, 0], [1, 0, 15, 7, 15, 7, 9, 0]]),        // snare
+    (7, [[15, 0, 15, 11, 15, 11, 11, 0], [13, 6, 15, 11, 15, 11, 11, 0]]),  // closed hihat
+    (7, [[15, 0, 15, 7, 15, 7, 7, 0], [13, 6, 15, 7, 15, 7, 7, 0]]),        // open hihat
+    (0, [[1, 36, 15, 7, 15, 7, 7, 0], [1, 0, 15, 6, 15, 6, 7, 0]]),         // tom
+    (7, [[15, 4, 15, 5, 15, 5, 6, 0], [7, 4, 15, 5, 15, 5, 6, 0]]),         // cymbal
+    (0, [[3, 20, 15, 7, 15, 7, 8, 0], [1, 0, 15, 6, 15, 6, 7, 0]]),         // bell
+    (0, [[1, 40, 15, 10, 15, 10, 10, 0], [1, 0, 15, 10, 15, 10, 10, 0]]),   // wood
+    (7, [[15, 2, 15, 10, 15, 10, 10, 0], [11, 10, 15, 10, 15, 10, 10, 0]]), // shaker
+];
+
+const KICK: u8 = 0;
+const SNARE: u8 = 1;
+const CLOSED_HIHAT: u8 = 2;
+const OPEN_HIHAT: u8 = 3;
+const TOM: u8 = 4;
+const CYMBAL: u8 = 5;
+const BELL: u8 = 6;
+const WOOD: u8 = 7;
+const SHAKER: u8 = 8;
+
+// (drum note, drum kind, pitch played), gm drum map plus the gm2/xg extension keys
+#[rustfmt::skip]
+static DRUM_MAP: [(u8, u8, u8); 61] = [
+    (27, WOOD, 84), (28, SNARE, 70), (29, SHAKER, 60), (30, SHAKER, 55), (31, WOOD, 80), (32, WOOD, 90), (33, WOOD, 86), (34, BELL, 90),
+    (35, KICK, 33), (36, KICK, 36), (37, WOOD, 72), (38, SNARE, 60), (39, SNARE, 64), (40, SNARE, 62), (41, TOM, 41), (42, CLOSED_HIHAT, 90),
+    (43, TOM, 45), (44, CLOSED_HIHAT, 88), (45, TOM, 48), (46, OPEN_HIHAT, 90), (47, TOM, 52), (48, TOM, 55), (49, CYMBAL, 84), (50, TOM, 58),
+    (51, CYMBAL, 88), (52, CYMBAL, 80), (53, BELL, 84), (54, SHAKER, 96), (55, CYMBAL, 90), (56, BELL, 68), (57, CYMBAL, 86), (58, SHAKER, 70),
+    (59, CYMBAL, 86), (60, TOM, 72), (61, TOM, 67), (62, TOM, 65), (63, TOM, 65), (64, TOM, 60), (65, TOM, 70), (66, TOM, 64),
+    (67, BELL, 79), (68, BELL, 74), (69, SHAKER, 96), (70, SHAKER, 100), (71, BELL, 96), (72, BELL, 96), (73, SHAKER, 80), (74, SHAKER, 80),
+    (75, WOOD, 84), (76, WOOD, 79), (77, WOOD, 74), (78, TOM, 72), (79, TOM, 67), (80, BELL, 100), (81, BELL, 100), (82, SHAKER, 96),
+    (83, BELL, 96), (84, BELL, 98), (85, WOOD, 82), (86, TOM, 38), (87, TOM, 38),
+];
+
+fn operator(operator: &Operator) -> FmOperator {
+    let &[multiple, total_level, attack_rate, decay_rate, sustain_level, sustain_rate, release_rate, waveform] = operator;
+    FmOperator {
+        multiple,
+        total_level,
+        attack_rate,
+        decay_rate,
+        sustain_level,
+        sustain_rate,
+        release_rate,
+        waveform,
+        ..Default::default()
+    }
+}
+
+fn melody_voice(program: u8) -> FmVoice {
+    let patch = &FAMILIES[(program / 8) as usize];
+    let (level_offset, multiple, waveform) = PROGRAMS[program as usize];
+
+    let mut operators = patch.operators.iter().map(operator).collect::<Vec<_>>();
+    operators[0].total_level = (operators[0].total_level as i8 + level_offset).clamp(0, 63) as u8;
+    operators[0].multiple = multiple;
+    operators[0].feedback = patch.feedback;
+    let carrier = operators.len() - 1;
+    operators[carrier].waveform = waveform;
+    if patch.vibrato {
+        operators[carrier].vibrato = true;
+        operators[carrier].vibrato_depth = 1;
+    }
+
+    FmVoice {
+        algorithm: patch.algorithm,
+        operators,
+        lfo: 1,
+        ..FmVoice::default()
+    }
+}
+
+fn drum_voice(kind: u8, key: u8) -> FmVoice {
+    let (feedback, operators) = &DRUMS[kind as usize];
+    let mut operators = operators.iter().map(operator).collect::<Vec<_>>();
+    operators[0].feedback = *feedback;
+
+    FmVoice {
+        algorithm: 0,
+        operators,
+        drum_key: key,
+        ..FmVoice::default()
+    }
+}
+
+pub fn default_voice_bank() -> FmVoiceBank {
+    let mut bank = FmVoiceBank::new();
+
+    for program in 0..128 {
+        bank.insert(MA_MELODY_BANK, 0x00, program, melody_voice(program));
+    }
+    for &(ma_program, gm_program) in &MA_MELODY_VARIATIONS {
+        bank.insert(MA_MELODY_BANK, 0x01, ma_program, melody_voice(gm_program));
+    }
+
+    for &(note, kind, key) in &DRUM_MAP {
+        bank.insert(MA_RHYTHM_BANK, 0x00, note, drum_voice(kind, key));
+    }
+    for &(ma_key, gm_key) in &MA_RHYTHM_KEYS {
+        if let Some(&(_, kind, key)) = DRUM_MAP.iter().find(|(note, _, _)| *note == gm_key) {
+            bank.insert(MA_RHYTHM_BANK, 0x00, ma_key, drum_voice(kind, key));
+        }
+    }
+
+    bank
+}
+
+#[cfg(test)]
+mod tests {
+    use super::default_voice_bank;
+
+    #[test]
+    fn covers_gm_programs_and_drums() {
+        let bank = default_voice_bank();
+
+        for program in 0..128 {
+            let voice = bank.get(0x7c, 0x00, program).unwrap();
+            assert_eq!(voice.operators.len(), voice.operator_count());
+        }
+        for note in 35..=81 {
+            assert!(bank.get(0x7d, 0x00, note).is_some());
+        }
+        assert!(bank.get(0x7c, 0x01, 0x62).is_some());
+        assert_eq!(bank.get(0x7d, 0x00, 0x54).unwrap().drum_key, bank.get(0x7d, 0x00, 43).unwrap().drum_key);
+    }
+}
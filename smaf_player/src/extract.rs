@@ -0,0 +1,135 @@
+// decoded copies of the waves stored in a file, stream waves (Mwa) of score tracks and waves (Awa) of pcm audio tracks
+
+use alloc::{string::String, vec::Vec};
+
+use smaf::{PCMAudioTrackChunk, PCMDataChunk, ScoreTrackChunk, Smaf, SmafChunk};
+
+use crate::{
+    pcm::{channel_count, decode_pcm_audio_wave, decode_stream_wave},
+    PlayerError, Result, TrackKind,
+};
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ExtractedWave {
+    pub kind: TrackKind, // ScoreTrack for stream waves, PCMAudioTrack for pcm audio track waves
+    pub track: u8,
+    pub wave_number: u8,
+    pub channels: u8,
+    pub sampling_rate: u32,
+    pub samples: Vec<i16>,       // stereo samples are interleaved left first
+    pub skipped: Option<String>, // why the wave could not be decoded, samples are empty then
+}
+
+// in file order. waves without a decoder (twinvq, mp3 without the mp3 feature) or that fail to decode are listed as skipped,
+// use extract_mp3 for the raw mp3 streams
+pub fn extract_waves(file: &[u8]) -> Result<Vec<ExtractedWave>> {
+    let smaf = Smaf::parse(file)?;
+
+    let mut result = Vec::new();
+    for chunk in &smaf.chunks {
+        match chunk {
+            SmafChunk::ScoreTrack(track_number, track) => {
+                for chunk in &track.chunks {
+                    let ScoreTrackChunk::PCMData(waves) = chunk else {
+                        continue;
+                    };
+
+                    for PCMDataChunk::WaveData(wave_number, wave) in waves {
+                        result.push(ExtractedWave {
+                            kind: TrackKind::ScoreTrack,
+                            track: *track_number,
+                            wave_number: *wave_number,
+                            channels: channel_count(wave.channel),
+                            sampling_rate: wave.sampling_freq as _,
+                            samples: decode_stream_wave(wave.format, wave.base_bit, wave.channel, wave.wave_data),
+                            skipped: None,
+                        });
+                    }
+                }
+            }
+            SmafChunk::PCMAudioTrack(track_number, track) => {
+                for chunk in &track.chunks {
+                    let PCMAudioTrackChunk::WaveData(wave_number, data) = chunk else {
+                        continue;
+                    };
+
+                    let (channels, sampling_rate, samples, skipped) = match decode_pcm_audio_wave(track, data) {
+                        Ok((channels, sampling_rate, samples)) => (channels, sampling_rate, samples, None),
+                        Err(PlayerError::Unsupported(reason) | PlayerError::InvalidMp3(reason)) => (
+                            channel_count(track.channel),
+                            track.sampling_freq.hz().unwrap_or(0),
+                            Vec::new(),
+                            Some(reason),
+                        ),
+                        Err(e) => return Err(e),
+                    };
+                    result.push(ExtractedWave {
+                        kind: TrackKind::PCMAudioTrack,
+                        track: *track_number,
+                        wave_number: *wave_number,
+                        channels,
+                        sampling_rate,
+                        samples,
+                        skipped,
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use smaf::{encode_adpcm, AdpcmQuality, BaseBit, Channel, FormatType, PcmWaveFormat, SamplingRate, SmafBuilder};
+
+    use super::extract_waves;
+    use crate::{add_wave, write_wav, TrackKind, WaveImportOptions, WaveTarget};
+
+    #[test]
+    fn extracts_stream_and_pcm_audio_track_waves() {
+        let score = SmafBuilder::new()
+            .score_track(FormatType::MobileStandardNoCompress, 20, |track| track.note(0, 0, 100, 10))
+            .pcm_track(PcmWaveFormat::TwosComplementPCM, SamplingRate::Hz4000, BaseBit::Bit8, 1, |track| {
+                track.channel(Channel::Stereo).wave_data(2, &[0x10, 0xf0]).wave(0, 2, 0)
+            })
+            .pcm_track(PcmWaveFormat::TwinVQ, SamplingRate::Hz8000, BaseBit::Bit16, 1, |track| {
+                track.wave_data(1, &[0x00]).wave(0, 1, 0)
+            })
+            .build()
+            .unwrap();
+        let options = WaveImportOptions {
+            target: WaveTarget::StreamWave,
+            ..Default::default()
+        };
+        let (file, _) = add_wave(&score, &write_wav(&[1000; 800], 8000, 1), &options).unwrap();
+
+        let waves = extract_waves(&file).unwrap();
+        assert_eq!(waves.len(), 3);
+
+        let stream = &waves[0];
+        assert_eq!(
+            (stream.kind, stream.track, stream.wave_number, stream.channels, stream.sampling_rate),
+            (TrackKind::ScoreTrack, 5, 1, 1, 8000)
+        );
+        assert_eq!(stream.samples, smaf::decode_adpcm(&encode_adpcm(&[1000; 800], 1, AdpcmQuality::Fast), 1));
+
+        let pcm = &waves[1];
+        assert_eq!(
+            (pcm.kind, pcm.track, pcm.wave_number, pcm.channels, pcm.sampling_rate),
+            (TrackKind::PCMAudioTrack, 0, 2, 2, 4000)
+        );
+        assert_eq!(pcm.samples, [0x1000, -0x1000]);
+        assert_eq!(pcm.skipped, None);
+
+        let twinvq = &waves[2];
+        assert_eq!(
+            (twinvq.kind, twinvq.track, twinvq.wave_number, twinvq.channels, twinvq.sampling_rate),
+            (TrackKind::PCMAudioTrack, 1, 1, 1, 8000)
+        );
+        assert!(twinvq.samples.is_empty());
+        assert!(twinvq.skipped.is_some());
+    }
+}
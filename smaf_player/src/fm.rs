@@ -0,0 +1,723 @@
+// software fm synthesizer modeled after the yamaha ma-2/ma-3 fm sections
+// (2 operator voices for ma-1/ma-2, 4 operator voices for ma-3)
+
+use alloc::{vec, vec::Vec};
+use core::f32::consts::PI;
+
+use crate::{voice::FmVoiceBank, SmafEvent, MA_MELODY_BANK, MA_RHYTHM_BANK, MIDI_DRUM_CHANNEL};
+
+const MAX_SLOTS: usize = 32;
+const SILENT_DB: f32 = 96.0;
+const MODULATION_DEPTH: f32 = 4.0 * PI;
+const OUTPUT_GAIN: f32 = 0.25;
+const PAN_CENTER: u8 = 0x40;
+
+const LFO_FREQUENCIES: [f32; 4] = [1.8, 4.0, 5.9, 7.0];
+const TREMOLO_DEPTHS_DB: [f32; 4] = [1.3, 2.8, 5.8, 11.8];
+const VIBRATO_DEPTHS_CENTS: [f32; 4] = [3.4, 6.7, 13.5, 27.0];
+const KEY_SCALE_LEVELS_DB: [f32; 4] = [0.0, 3.0, 1.5, 6.0]; // per octave
+const DETUNE_STEPS: [f32; 8] = [0.0, 1.0, 2.0, 3.0, 0.0, -1.0, -2.0, -3.0];
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct FmOperator {
+    pub multiple: u8, // 0 is half the key frequency
+    pub detune: u8,
+    pub total_level: u8, // 0.75 db steps
+    pub key_scale_level: u8,
+    pub attack_rate: u8,
+    pub decay_rate: u8,
+    pub sustain_level: u8, // 3 db steps
+    pub sustain_rate: u8,
+    pub release_rate: u8,
+    pub sustain: bool,        // release slowly on key off
+    pub ignore_key_off: bool, // keep decaying at sustain rate after key off
+    pub key_scale_rate: bool,
+    pub feedback: u8,
+    pub waveform: u8,
+    pub tremolo: bool,
+    pub tremolo_depth: u8,
+    pub vibrato: bool,
+    pub vibrato_depth: u8,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FmVoice {
+    pub algorithm: u8, // 0 and 1 use two operators, 2 to 7 use four
+    pub operators: Vec<FmOperator>,
+    pub octave: i8, // basic octave shift
+    pub lfo: u8,
+    pub panpot: u8,
+    pub drum_key: u8, // note played by rhythm voices
+}
+
+impl FmVoice {
+    pub fn operator_count(&self) -> usize {
+        if self.algorithm < 2 {
+            2
+        } else {
+            4
+        }
+    }
+}
+
+impl Default for FmVoice {
+    // plain two operator electric piano-ish tone
+    fn default() -> Self {
+        let modulator = FmOperator {
+            multiple: 1,
+            total_level: 28,
+            attack_rate: 15,
+            decay_rate: 5,
+            sustain_level: 6,
+            sustain_rate: 2,
+            release_rate: 8,
+            feedback: 3,
+            ..Default::default()
+        };
+        let carrier = FmOperator {
+            multiple: 1,
+            attack_rate: 15,
+            decay_rate: 3,
+            sustain_level: 4,
+            sustain_rate: 2,
+            release_rate: 8,
+            ..Default::default()
+        };
+
+        Self {
+            algorithm: 0,
+            operators: vec![modulator, carrier],
+            octave: 0,
+            lfo: 0,
+            panpot: 15,
+            drum_key: 60,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum EnvelopeStage {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+#[derive(Clone)]
+struct OperatorState {
+    phase: f32,
+    attenuation: f32, // db
+    stage: EnvelopeStage,
+    steps: [f32; 4], // attack coefficient, then decay, sustain and release db per sample
+    history: [f32; 2],
+}
+
+struct Slot {
+    channel: u8,
+    note: u8,
+    key_on: bool,
+    age: usize,
+    voice: FmVoice,
+    pitch: f32, // midi note number of the voice, before pitch bend
+    gain: f32,
+    lfo_phase: f32,
+    operators: Vec<OperatorState>,
+}
+
+#[derive(Copy, Clone)]
+struct ChannelState {
+    program: u8,
+    bank_msb: u8,
+    bank_lsb: u8,
+    volume: u8,
+    expression: u8,
+    modulation: u8,
+    pan: u8,
+    pitch_bend: u16,
+}
+
+impl Default for ChannelState {
+    fn default() -> Self {
+        Self {
+            program: 0,
+            bank_msb: 0,
+            bank_lsb: 0,
+            volume: 100,
+            expression: 127,
+            modulation: 0,
+            pan: PAN_CENTER,
+            pitch_bend: 8192,
+        }
+    }
+}
+
+pub struct FmSynth {
+    sample_rate: u32,
+    voices: FmVoiceBank,
+    channels: [ChannelState; 16],
+    slots: Vec<Slot>,
+    age: usize,
+}
+
+impl FmSynth {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            sample_rate,
+            voices: FmVoiceBank::new(),
+            channels: [ChannelState::default(); 16],
+            slots: Vec::with_capacity(MAX_SLOTS),
+            age: 0,
+        }
+    }
+
+    // rhythm voices are set with bank 0x7d and the drum note as program
+    pub fn set_voice(&mut self, bank_msb: u8, bank_lsb: u8, program: u8, voice: FmVoice) {
+        self.voices.insert(bank_msb, bank_lsb, program, voice);
+    }
+
+    pub fn set_voice_bank(&mut self, voices: FmVoiceBank) {
+        self.voices = voices;
+    }
+
+    pub fn process(&mut self, event: &SmafEvent) {
+        match *event {
+            SmafEvent::MidiNoteOn { channel, note, velocity: 0 } | SmafEvent::MidiNoteOff { channel, note, .. } => self.note_off(channel, note),
+            SmafEvent::MidiNoteOn { channel, note, velocity } => self.note_on(channel, note, velocity),
+            SmafEvent::MidiProgramChange { channel, program } => self.channels[(channel & 0x0f) as usize].program = program & 0x7f,
+            SmafEvent::MidiControlChange { channel, control, value } => {
+                let state = &mut self.channels[(channel & 0x0f) as usize];
+                match control {
+                    0 => state.bank_msb = value,
+                    1 => state.modulation = value,
+                    7 => state.volume = value,
+                    10 => state.pan = value.min(0x7f),
+                    11 => state.expression = value,
+                    32 => state.bank_lsb = value,
+                    120 | 123 => {
+                        let channel = channel & 0x0f;
+                        self.slots.retain(|slot| slot.channel != channel || control == 123);
+                        self.release_channel(channel);
+                    }
+                    _ => {}
+                }
+            }
+            SmafEvent::MidiPitchBend { channel, value } => self.channels[(channel & 0x0f) as usize].pitch_bend = value,
+            SmafEvent::MidiSysEx(ref data) => {
+                self.voices.insert_message(data);
+            }
+            _ => {}
+        }
+    }
+
+    fn voice(&self, channel: u8, note: u8) -> FmVoice {
+        let state = &self.channels[channel as usize];
+        if channel == MIDI_DRUM_CHANNEL {
+            return self.voices.get(MA_RHYTHM_BANK, 0, note).cloned().unwrap_or(FmVoice {
+                drum_key: note,
+                ..FmVoice::default()
+            });
+        }
+
+        // unknown variations fall back to the basic voice of the program
+        self.voices
+            .get(state.bank_msb, state.bank_lsb, state.program)
+            .or_else(|| self.voices.get(state.bank_msb, 0, state.program))
+            .or_else(|| self.voices.get(MA_MELODY_BANK, 0, state.program))
+            .or_else(|| self.voices.get(0, 0, state.program))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn note_on(&mut self, channel: u8, note: u8, velocity: u8) {
+        let channel = channel & 0x0f;
+        let voice = self.voice(channel, note);
+        let pitch = if channel == MIDI_DRUM_CHANNEL { voice.drum_key } else { note } as f32 + voice.octave as f32 * 12.0;
+        let velocity = velocity as f32 / 127.0;
+
+        if self.slots.len() >= MAX_SLOTS {
+            // steal the oldest released slot, or the oldest one
+            let index = self
+                .slots
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, slot)| (slot.key_on, slot.age))
+                .map(|(index, _)| index)
+                .unwrap();
+            self.slots.remove(index);
+        }
+
+        self.age += 1;
+        self.slots.push(Slot {
+            channel,
+            note,
+            key_on: true,
+            age: self.age,
+            operators: voice
+                .operators
+                .iter()
+                .take(voice.operator_count())
+                .map(|operator| OperatorState {
+                    phase: 0.0,
+                    attenuation: SILENT_DB,
+                    stage: EnvelopeStage::Attack,
+                    steps: envelope_steps(operator, pitch, self.sample_rate as f32),
+                    history: [0.0; 2],
+                })
+                .collect(),
+            voice,
+            pitch,
+            gain: velocity * velocity,
+            lfo_phase: 0.0,
+        });
+    }
+
+    fn note_off(&mut self, channel: u8, note: u8) {
+        let channel = channel & 0x0f;
+        if let Some(slot) = self
+            .slots
+            .iter_mut()
+            .find(|slot| slot.key_on && slot.channel == channel && slot.note == note)
+        {
+            key_off(slot);
+        }
+    }
+
+    fn release_channel(&mut self, channel: u8) {
+        for slot in self.slots.iter_mut().filter(|slot| slot.channel == channel) {
+            key_off(slot);
+        }
+    }
+
+    pub fn is_silent(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    // mixes the next `output.len()` mono samples into `output`, pan is ignored
+    pub fn render(&mut self, output: &mut [i16]) {
+        self.mix(output, 1);
+    }
+
+    // mixes the next `output.len() / 2` stereo frames into `output`, interleaved left first
+    pub fn render_stereo(&mut self, output: &mut [i16]) {
+        self.mix(output, 2);
+    }
+
+    fn mix(&mut self, output: &mut [i16], channels: usize) {
+        let sample_rate = self.sample_rate as f32;
+
+        // channel state only changes between calls
+        let slot_parameters = self
+            .slots
+            .iter()
+            .map(|slot| {
+                let channel = &self.channels[slot.channel as usize];
+                let bend = (channel.pitch_bend as f32 - 8192.0) / 8192.0 * 2.0;
+                let frequency = 440.0 * libm::exp2f((slot.pitch + bend - 69.0) / 12.0);
+                let volume = channel.volume as f32 / 127.0;
+                let expression = channel.expression as f32 / 127.0;
+                let modulation_cents = channel.modulation as f32 / 127.0 * 50.0;
+                // balance, both sides stay at full level in the center
+                let pan = match channels {
+                    1 => [1.0, 1.0],
+                    _ => [
+                        ((0x7f - channel.pan) as f32 / (0x7f - PAN_CENTER) as f32).min(1.0),
+                        (channel.pan as f32 / PAN_CENTER as f32).min(1.0),
+                    ],
+                };
+
+                (frequency, slot.gain * volume * volume * expression * expression, modulation_cents, pan)
+            })
+            .collect::<Vec<_>>();
+
+        for frame in output.chunks_exact_mut(channels) {
+            let mut mixed = [0.0; 2];
+
+            for (slot, &(frequency, gain, modulation_cents, pan)) in self.slots.iter_mut().zip(&slot_parameters) {
+                let lfo = libm::sinf(2.0 * PI * slot.lfo_phase);
+                slot.lfo_phase = fract(slot.lfo_phase + LFO_FREQUENCIES[(slot.voice.lfo & 3) as usize] / sample_rate);
+
+                let frequency = frequency * cents_ratio(modulation_cents * lfo);
+                let value = render_voice(slot, frequency, lfo, sample_rate) * gain;
+                mixed[0] += value * pan[0];
+                mixed[1] += value * pan[1];
+            }
+
+            for (sample, mixed) in frame.iter_mut().zip(mixed) {
+                let value = (mixed * OUTPUT_GAIN * 32767.0) as i32 + *sample as i32;
+                *sample = value.clamp(-32768, 32767) as i16;
+            }
+        }
+
+        self.slots.retain(|slot| {
+            carriers(slot.voice.algorithm)
+                .iter()
+                .take(slot.operators.len())
+                .zip(&slot.operators)
+                .any(|(carrier, state)| *carrier && state.attenuation < SILENT_DB)
+        });
+    }
+}
+
+fn key_off(slot: &mut Slot) {
+    slot.key_on = false;
+    for (operator, state) in slot.voice.operators.iter().zip(slot.operators.iter_mut()) {
+        if !operator.ignore_key_off {
+            state.stage = EnvelopeStage::Release;
+        }
+    }
+}
+
+// which operators are audible for each algorithm
+fn carriers(algorithm: u8) -> [bool; 4] {
+    match algorithm {
+        0 => [false, true, false, false],
+        1 | 2 => [true, true, true, true],
+        3 | 4 => [false, false, false, true],
+        5 => [false, true, false, true],
+        6 => [true, false, false, true],
+        _ => [true, false, true, true],
+    }
+}
+
+fn render_voice(slot: &mut Slot, frequency: f32, lfo: f32, sample_rate: f32) -> f32 {
+    let voice = &slot.voice;
+    let mut outputs = [0.0f32; 4];
+    let mut operate = |index: usize, modulation: f32| -> f32 {
+        let (Some(operator), Some(state)) = (voice.operators.get(index), slot.operators.get_mut(index)) else {
+            return 0.0;
+        };
+        let output = run_operator(operator, state, slot.pitch, frequency, modulation, lfo, sample_rate);
+        outputs[index] = output;
+        output
+    };
+
+    match voice.algorithm {
+        0 => {
+            let modulator = operate(0, 0.0);
+            operate(1, modulator)
+        }
+        1 => operate(0, 0.0) + operate(1, 0.0),
+        2 => operate(0, 0.0) + operate(1, 0.0) + operate(2, 0.0) + operate(3, 0.0),
+        3 => {
+            let first = operate(0, 0.0);
+            let second = operate(1, 0.0);
+            let third = operate(2, second);
+            operate(3, first + third)
+        }
+        4 => {
+            let first = operate(0, 0.0);
+            let second = operate(1, first);
+            let third = operate(2, second);
+            operate(3, third)
+        }
+        5 => {
+            let first = operate(0, 0.0);
+            let second = operate(1, first);
+            let third = operate(2, 0.0);
+            second + operate(3, third)
+        }
+        6 => {
+            let first = operate(0, 0.0);
+            let second = operate(1, 0.0);
+            let third = operate(2, second);
+            first + operate(3, third)
+        }
+        _ => {
+            let first = operate(0, 0.0);
+            let second = operate(1, 0.0);
+            first + operate(2, second) + operate(3, 0.0)
+        }
+    }
+}
+
+fn run_operator(operator: &FmOperator, state: &mut OperatorState, pitch: f32, frequency: f32, modulation: f32, lfo: f32, sample_rate: f32) -> f32 {
+    let multiple = if operator.multiple == 0 { 0.5 } else { operator.multiple as f32 };
+    let mut frequency = frequency * multiple * (1.0 + DETUNE_STEPS[(operator.detune & 7) as usize] * 0.0008);
+    if operator.vibrato {
+        let depth = VIBRATO_DEPTHS_CENTS[(operator.vibrato_depth & 3) as usize];
+        frequency *= cents_ratio(depth * lfo);
+    }
+
+    advance_envelope(operator, state);
+
+    let feedback = operator.feedback & 7;
+    let feedback = if feedback > 0 {
+        (state.history[0] + state.history[1]) / 2.0 * PI / 16.0 * (1 << (feedback - 1)) as f32
+    } else {
+        0.0
+    };
+    let wave = waveform(
+        operator.waveform,
+        fract(state.phase + (modulation * MODULATION_DEPTH + feedback) / (2.0 * PI)),
+    );
+    state.phase = fract(state.phase + frequency / sample_rate);
+
+    let octave = (pitch - 60.0) / 12.0;
+    let mut attenuation =
+        state.attenuation + operator.total_level as f32 * 0.75 + (KEY_SCALE_LEVELS_DB[(operator.key_scale_level & 3) as usize] * octave).max(0.0);
+    if operator.tremolo {
+        attenuation += TREMOLO_DEPTHS_DB[(operator.tremolo_depth & 3) as usize] * (1.0 + lfo) / 2.0;
+    }
+
+    let output = if attenuation >= SILENT_DB {
+        0.0
+    } else {
+        wave * libm::exp10f(-attenuation / 20.0)
+    };
+    state.history = [state.history[1], output];
+
+    output
+}
+
+// envelope rates follow the opl family: rate 1 attacks in ~2.8 s and decays 96 db in ~39 s, each step doubles the speed
+fn envelope_steps(operator: &FmOperator, pitch: f32, sample_rate: f32) -> [f32; 4] {
+    let rate = |rate: u8| -> f32 {
+        if rate == 0 {
+            return 0.0;
+        }
+        let key_scale = if operator.key_scale_rate { (pitch / 24.0).max(0.0) } else { 0.0 };
+        ((rate & 0x0f) as f32 + key_scale).min(15.0)
+    };
+    let decay_step = |rate: f32| -> f32 {
+        if rate <= 0.0 {
+            0.0
+        } else {
+            SILENT_DB / (39.28 / libm::exp2f(rate - 1.0) * sample_rate)
+        }
+    };
+
+    let attack_rate = rate(operator.attack_rate);
+    let attack = if attack_rate >= 15.0 {
+        1.0
+    } else if attack_rate > 0.0 {
+        8.0 / (2.826 / libm::exp2f(attack_rate - 1.0) * sample_rate)
+    } else {
+        0.0
+    };
+    // sustaining operators release slowly
+    let release_rate = if operator.sustain {
+        operator.release_rate.min(4)
+    } else {
+        operator.release_rate
+    };
+
+    [
+        attack,
+        decay_step(rate(operator.decay_rate)),
+        decay_step(rate(operator.sustain_rate)),
+        decay_step(rate(release_rate.max(1))),
+    ]
+}
+
+fn advance_envelope(operator: &FmOperator, state: &mut OperatorState) {
+    match state.stage {
+        EnvelopeStage::Attack => {
+            state.attenuation -= (state.attenuation + 1.0) * state.steps[0];
+            if state.attenuation <= 0.0 {
+                state.attenuation = 0.0;
+                state.stage = EnvelopeStage::Decay;
+            }
+        }
+        EnvelopeStage::Decay => {
+            let sustain_level = if operator.sustain_level >= 15 {
+                93.0
+            } else {
+                operator.sustain_level as f32 * 3.0
+            };
+            state.attenuation += state.steps[1];
+            if state.attenuation >= sustain_level {
+                state.attenuation = sustain_level;
+                state.stage = EnvelopeStage::Sustain;
+            }
+        }
+        EnvelopeStage::Sustain => state.attenuation += state.steps[2],
+        EnvelopeStage::Release => state.attenuation += state.steps[3],
+    }
+    state.attenuation = state.attenuation.min(SILENT_DB);
+}
+
+// the 8 opl style waveforms, `phase` in 0..1
+fn waveform(waveform: u8, phase: f32) -> f32 {
+    let sine = libm::sinf(2.0 * PI * phase);
+
+    match waveform & 0x07 {
+        0 => sine,
+        1 => sine.max(0.0),
+        2 => sine.abs(),
+        3 => {
+            if fract(phase * 2.0) < 0.5 {
+                sine.abs()
+            } else {
+                0.0
+            }
+        }
+        4 => {
+            if phase < 0.5 {
+                libm::sinf(4.0 * PI * phase)
+            } else {
+                0.0
+            }
+        }
+        5 => {
+            if phase < 0.5 {
+                libm::sinf(4.0 * PI * phase).abs()
+            } else {
+                0.0
+            }
+        }
+        6 => {
+            if phase < 0.5 {
+                1.0
+            } else {
+                -1.0
+            }
+        }
+        _ => 1.0 - 2.0 * phase,
+    }
+}
+
+// small pitch deviations, close enough to 2^(cents / 1200)
+fn cents_ratio(cents: f32) -> f32 {
+    1.0 + cents * (core::f32::consts::LN_2 / 1200.0)
+}
+
+fn fract(value: f32) -> f32 {
+    value - libm::floorf(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{vec, vec::Vec};
+
+    use super::{FmOperator, FmSynth, FmVoice};
+    use crate::SmafEvent;
+
+    fn render(synth: &mut FmSynth, samples: usize) -> Vec<i16> {
+        let mut output = vec![0; samples];
+        synth.render(&mut output);
+        output
+    }
+
+    fn peak(samples: &[i16]) -> i16 {
+        samples.iter().map(|x| x.saturating_abs()).max().unwrap_or(0)
+    }
+
+    #[test]
+    fn renders_note_and_goes_silent_after_release() {
+        let mut synth = FmSynth::new(8000);
+        synth.process(&SmafEvent::MidiNoteOn {
+            channel: 0,
+            note: 69,
+            velocity: 127,
+        });
+        assert!(peak(&render(&mut synth, 800)) > 1000);
+
+        synth.process(&SmafEvent::MidiNoteOff {
+            channel: 0,
+            note: 69,
+            velocity: 0,
+        });
+        render(&mut synth, 8000 * 10);
+        assert!(synth.is_silent());
+    }
+
+    #[test]
+    fn algorithms_change_the_tone() {
+        let operator = FmOperator {
+            multiple: 2,
+            attack_rate: 15,
+            release_rate: 15,
+            ..Default::default()
+        };
+        let mut outputs = Vec::new();
+        for (algorithm, operator_count) in [(0, 2), (1, 2), (4, 4)] {
+            let mut synth = FmSynth::new(8000);
+            synth.set_voice(
+                0,
+                0,
+                0,
+                FmVoice {
+                    algorithm,
+                    operators: vec![operator; operator_count],
+                    ..FmVoice::default()
+                },
+            );
+            synth.process(&SmafEvent::MidiNoteOn {
+                channel: 0,
+                note: 60,
+                velocity: 100,
+            });
+            outputs.push(render(&mut synth, 400));
+        }
+
+        assert!(outputs.iter().all(|output| peak(output) > 0));
+        assert_ne!(outputs[0], outputs[1]);
+        assert_ne!(outputs[0], outputs[2]);
+    }
+
+    #[test]
+    fn masks_feedback_to_three_bits() {
+        let outputs = [7, 0xff].map(|feedback| {
+            let mut synth = FmSynth::new(8000);
+            synth.set_voice(
+                0,
+                0,
+                0,
+                FmVoice {
+                    operators: vec![
+                        FmOperator {
+                            feedback,
+                            ..Default::default()
+                        };
+                        2
+                    ],
+                    ..FmVoice::default()
+                },
+            );
+            synth.process(&SmafEvent::MidiNoteOn {
+                channel: 0,
+                note: 60,
+                velocity: 100,
+            });
+            render(&mut synth, 400)
+        });
+
+        assert_eq!(outputs[0], outputs[1]);
+    }
+
+    #[test]
+    fn rhythm_voices_play_their_drum_key() {
+        let mut synth = FmSynth::new(8000);
+        let voice = FmVoice {
+            algorithm: 1,
+            drum_key: 48,
+            ..FmVoice::default()
+        };
+        synth.set_voice(0x7d, 0, 36, voice.clone());
+        synth.set_voice(0, 0, 0, voice);
+
+        synth.process(&SmafEvent::MidiNoteOn {
+            channel: 9,
+            note: 36,
+            velocity: 100,
+        });
+        let drum = render(&mut synth, 400);
+
+        let mut synth = FmSynth::new(8000);
+        synth.set_voice(
+            0,
+            0,
+            0,
+            FmVoice {
+                algorithm: 1,
+                ..FmVoice::default()
+            },
+        );
+        synth.process(&SmafEvent::MidiNoteOn {
+            channel: 0,
+            note: 48,
+            velocity: 100,
+        });
+        assert_eq!(drum, render(&mut synth, 400));
+    }
+}
@@ -0,0 +1,403 @@
+// reduction of midi event streams into handy phone standard (ma-1/ma-2) tracks.
+// each track carries 4 channels, notes 1..=48 cover c#2 to c6 and move by a per channel octave shift, and rhythm channels
+// select the drum key with a program change. note velocities have no handy phone form and are always dropped.
+
+use alloc::{format, vec::Vec};
+
+use smaf::{
+    timebases, write_chunk, write_score_track, write_smaf, ChannelStatus, ChannelType, FormatType, ScoreTrackSequenceEvent, SequenceData,
+    MAX_HANDY_VARIABLE_NUMBER,
+};
+
+use crate::{
+    import::{ma_rhythm_key, pair_notes, pick_timebase, quantize, CONTENTS_INFO},
+    PlayerError, Result, SmafEvent, MIDI_DRUM_CHANNEL,
+};
+
+const MAX_TRACKS: usize = 4;
+const CHANNELS_PER_TRACK: usize = 4;
+const NOTE_OFFSET: i16 = 36; // handy phone note 0 is midi c2
+const RHYTHM_NOTE: u8 = 1; // rhythm channels ignore the note, the program is the key
+
+// what had to be dropped or reworked to fit the handy phone standard
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct HpsReport {
+    pub dropped_channels: Vec<u8>,       // midi channels beyond the 16 handy phone channels
+    pub dropped_controls: Vec<(u8, u8)>, // (midi channel, control) without a handy phone form, bank selects too, each listed once
+    pub dropped_program_changes: usize,  // kit changes on the drum channel
+    pub shortened_notes: usize,          // gate times cut to the longest handy phone gate
+    pub octave_shifts: usize,            // octave shifts inserted to reach notes outside the current range
+    pub unmappable_drum_keys: Vec<u8>,   // gm drum keys that are ma rhythm keys themselves and play as another drum, each listed once
+}
+
+pub fn events_to_hps(events: &[(usize, SmafEvent)], timebase: Option<u8>) -> Result<(Vec<u8>, HpsReport)> {
+    let mut report = HpsReport::default();
+    // stable, so events of the same time keep their order
+    let mut events = events.to_vec();
+    events.sort_by_key(|(time, _)| *time);
+    // pair_notes drops bank selects, the handy phone standard has no gm banks
+    for (_, event) in &events {
+        if let SmafEvent::MidiControlChange {
+            channel,
+            control: control @ (0 | 32),
+            ..
+        } = *event
+        {
+            if !report.dropped_controls.contains(&(channel, control)) {
+                report.dropped_controls.push((channel, control));
+            }
+        }
+    }
+    let (events, used_channels) = pair_notes(&events);
+    let timebase = match timebase {
+        Some(timebase) if timebases().any(|x| x == timebase) => timebase,
+        Some(timebase) => return Err(PlayerError::Unsupported(format!("timebase {timebase} ms"))),
+        None => pick_timebase(&events),
+    };
+
+    // used midi channels are packed in order, 4 to a track
+    let mut slots = [None; 16];
+    let mut slot_count = 0;
+    for channel in (0..16).filter(|x| used_channels[*x]) {
+        if slot_count < MAX_TRACKS * CHANNELS_PER_TRACK {
+            slots[channel] = Some(slot_count);
+            slot_count += 1;
+        } else {
+            report.dropped_channels.push(channel as u8);
+        }
+    }
+    let track_count = slot_count.div_ceil(CHANNELS_PER_TRACK).max(1);
+
+    let mut tracks = (0..track_count).map(|_| Vec::new()).collect::<Vec<_>>();
+    let mut octave_shifts = [0i16; 16];
+    let mut drum_keys = [None; 16];
+    for (time, event) in events {
+        let time = quantize(time, timebase);
+        let midi_channel = match &event {
+            ScoreTrackSequenceEvent::NoteMessage { channel, .. }
+            | ScoreTrackSequenceEvent::ControlChange { channel, .. }
+            | ScoreTrackSequenceEvent::ProgramChange { channel, .. }
+            | ScoreTrackSequenceEvent::PitchBend { channel, .. } => *channel & 0x0f,
+            _ => {
+                // exclusives and anything without a channel go to the first track
+                tracks[0].push((time, event));
+                continue;
+            }
+        };
+        let Some(slot) = slots[midi_channel as usize] else {
+            continue;
+        };
+        let (track, channel) = (&mut tracks[slot / CHANNELS_PER_TRACK], (slot % CHANNELS_PER_TRACK) as u8);
+        let is_rhythm = midi_channel == MIDI_DRUM_CHANNEL;
+
+        match event {
+            ScoreTrackSequenceEvent::NoteMessage { note, gate_time, .. } => {
+                let gate_time = (quantize(gate_time as usize, timebase) - time).max(1);
+                if gate_time > MAX_HANDY_VARIABLE_NUMBER as usize {
+                    report.shortened_notes += 1;
+                }
+
+                let note = if is_rhythm {
+                    let key = ma_rhythm_key(note, &mut report.unmappable_drum_keys);
+                    if drum_keys[slot] != Some(key) {
+                        drum_keys[slot] = Some(key);
+                        track.push((time, ScoreTrackSequenceEvent::ProgramChange { channel, program: key }));
+                    }
+                    RHYTHM_NOTE
+                } else {
+                    let note = note as i16 - NOTE_OFFSET;
+                    if !(1..=48).contains(&(note - octave_shifts[slot] * 12)) {
+                        octave_shifts[slot] = octave_shift_for(note, octave_shifts[slot]);
+                        report.octave_shifts += 1;
+                        track.push((
+                            time,
+                            ScoreTrackSequenceEvent::OctaveShift {
+                                channel,
+                                value: encode_octave_shift(octave_shifts[slot]),
+                            },
+                        ));
+                    }
+                    (note - octave_shifts[slot] * 12) as u8
+                };
+
+                track.push((
+                    time,
+                    ScoreTrackSequenceEvent::NoteMessage {
+                        channel,
+                        note,
+                        velocity: None,
+                        gate_time: gate_time as u32,
+                    },
+                ));
+            }
+            ScoreTrackSequenceEvent::ControlChange { control, value, .. } => {
+                let event = match control {
+                    1 => ScoreTrackSequenceEvent::Modulation { channel, value },
+                    7 => ScoreTrackSequenceEvent::Volume { channel, value },
+                    10 => ScoreTrackSequenceEvent::Pan { channel, value },
+                    11 => ScoreTrackSequenceEvent::Expression { channel, value },
+                    _ => {
+                        if !report.dropped_controls.contains(&(midi_channel, control)) {
+                            report.dropped_controls.push((midi_channel, control));
+                        }
+                        continue;
+                    }
+                };
+                track.push((time, event));
+            }
+            ScoreTrackSequenceEvent::ProgramChange { program, .. } => {
+                if is_rhythm {
+                    report.dropped_program_changes += 1;
+                    continue;
+                }
+                track.push((time, ScoreTrackSequenceEvent::ProgramChange { channel, program }));
+            }
+            ScoreTrackSequenceEvent::PitchBend { value, .. } => track.push((time, ScoreTrackSequenceEvent::PitchBend { channel, value })),
+            _ => {}
+        }
+    }
+
+    let mut chunks = Vec::new();
+    write_chunk(&mut chunks, b"CNTI", &CONTENTS_INFO);
+    for (index, track) in tracks.into_iter().enumerate() {
+        let statuses = (0..CHANNELS_PER_TRACK)
+            .map(|channel| {
+                let slot = index * CHANNELS_PER_TRACK + channel;
+                let midi_channel = slots.iter().position(|x| *x == Some(slot));
+                let channel_type = match midi_channel {
+                    Some(x) if x == MIDI_DRUM_CHANNEL as usize => ChannelType::Rhythm,
+                    Some(_) => ChannelType::Melody,
+                    None => ChannelType::NoCare,
+                };
+
+                ChannelStatus {
+                    kcs: 0,
+                    vs: 0,
+                    led: 0,
+                    channel_type,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let mut now = 0;
+        let sequence = track
+            .into_iter()
+            .map(|(time, event)| {
+                let duration = (time - now) as u32;
+                now = time;
+                SequenceData { duration, event }
+            })
+            .collect::<Vec<_>>();
+
+        write_chunk(
+            &mut chunks,
+            &[b'M', b'T', b'R', index as u8 + 1],
+            &write_score_track(
+                FormatType::HandyPhoneStandard,
+                timebase,
+                timebase,
+                &ChannelStatus::write_handy(&statuses).to_be_bytes(),
+                &[],
+                &SequenceData::write_handy(&sequence),
+            )?,
+        );
+    }
+
+    Ok((write_smaf(&chunks), report))
+}
+
+// the shift closest to the current one that brings the note into 1..=48
+fn octave_shift_for(note: i16, current: i16) -> i16 {
+    (-4..=4)
+        .filter(|shift| (1..=48).contains(&(note - shift * 12)))
+        .min_by_key(|shift| (shift - current).abs())
+        .unwrap_or(current)
+}
+
+fn encode_octave_shift(shift: i16) -> u8 {
+    if shift < 0 {
+        0x80 | (-shift) as u8
+    } else {
+        shift as u8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use smaf::{hps_to_mobile, ChannelType, FormatType, Smaf, SmafBuilder, SmafChunk};
+
+    use super::{events_to_hps, octave_shift_for};
+    use crate::{parse_smaf, SmafEvent};
+
+    fn note(time: usize, channel: u8, note: u8, velocity: u8) -> (usize, SmafEvent) {
+        if velocity == 0 {
+            (time, SmafEvent::MidiNoteOff { channel, note, velocity })
+        } else {
+            (time, SmafEvent::MidiNoteOn { channel, note, velocity })
+        }
+    }
+
+    #[test]
+    fn picks_nearest_octave_shift() {
+        assert_eq!(octave_shift_for(24, 0), 0);
+        assert_eq!(octave_shift_for(60, 0), 1);
+        assert_eq!(octave_shift_for(60, 4), 4);
+        assert_eq!(octave_shift_for(-20, 0), -2);
+    }
+
+    #[test]
+    fn reduces_channels_into_handy_phone_tracks() {
+        let mut events = Vec::from([
+            (0, SmafEvent::MidiProgramChange { channel: 0, program: 40 }),
+            (
+                0,
+                SmafEvent::MidiControlChange {
+                    channel: 0,
+                    control: 7,
+                    value: 90,
+                },
+            ),
+            (
+                0,
+                SmafEvent::MidiControlChange {
+                    channel: 0,
+                    control: 91,
+                    value: 40,
+                },
+            ),
+            (0, SmafEvent::MidiProgramChange { channel: 9, program: 8 }),
+            note(0, 0, 40, 100),
+            note(100, 0, 40, 0),
+            note(100, 0, 100, 100),
+            note(200, 0, 100, 0),
+            note(200, 9, 38, 100),
+            note(250, 9, 38, 0),
+        ]);
+        for channel in 1..6 {
+            events.push(note(300, channel, 60, 100));
+            events.push(note(400, channel, 60, 0));
+        }
+
+        let (smaf, report) = events_to_hps(&events, None).unwrap();
+        assert_eq!(report.dropped_channels, []);
+        assert_eq!(report.dropped_controls, [(0, 91)]);
+        assert_eq!(report.dropped_program_changes, 1);
+        assert_eq!(report.octave_shifts, 1);
+
+        let file = Smaf::parse(&smaf).unwrap();
+        let tracks = file
+            .chunks
+            .iter()
+            .filter_map(|chunk| match chunk {
+                SmafChunk::ScoreTrack(number, track) => Some((*number, track.format_type, track.timebase_d)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(tracks, [(1, FormatType::HandyPhoneStandard, 50), (2, FormatType::HandyPhoneStandard, 50)]);
+
+        let notes = parse_smaf(&smaf)
+            .unwrap()
+            .into_iter()
+            .filter_map(|(time, _, event)| match event {
+                SmafEvent::MidiNoteOn { channel, note, .. } => Some((time, channel == 9, note)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(notes[..3], [(0, false, 40), (100, false, 100), (200, true, 38)]);
+        assert_eq!(notes[3..], [(300, false, 60); 5]);
+    }
+
+    #[test]
+    fn maps_drum_keys_and_reports_bank_selects() {
+        let events = [
+            (
+                0,
+                SmafEvent::MidiControlChange {
+                    channel: 0,
+                    control: 0,
+                    value: 1,
+                },
+            ),
+            note(0, 0, 60, 100),
+            note(50, 0, 60, 0),
+            note(0, 9, 45, 100),
+            note(50, 9, 45, 0),
+            note(100, 9, 77, 100),
+            note(150, 9, 77, 0),
+        ];
+
+        let (smaf, report) = events_to_hps(&events, None).unwrap();
+        assert_eq!(report.dropped_controls, [(0, 0)]);
+        assert_eq!(report.unmappable_drum_keys, [77]);
+
+        let drums = parse_smaf(&smaf)
+            .unwrap()
+            .into_iter()
+            .filter_map(|(time, _, event)| match event {
+                SmafEvent::MidiNoteOn { channel: 9, note, .. } => Some((time, note)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(drums[0], (0, 45));
+    }
+
+    #[test]
+    fn sorts_events_by_time() {
+        let events = [note(0, 0, 60, 100), note(100, 0, 60, 0), note(150, 0, 62, 100), note(300, 0, 62, 0)];
+        let unsorted = [events[3].clone(), events[1].clone(), events[0].clone(), events[2].clone()];
+
+        assert_eq!(events_to_hps(&unsorted, None).unwrap().0, events_to_hps(&events, None).unwrap().0);
+    }
+
+    #[test]
+    fn plays_the_same_after_mobile_conversion() {
+        let mut events = Vec::from([(0, SmafEvent::MidiProgramChange { channel: 0, program: 40 })]);
+        for (index, note_number) in [30, 60, 90, 50].into_iter().enumerate() {
+            events.push(note(index * 100, 0, note_number, 100));
+            events.push(note(index * 100 + 80, 0, note_number, 0));
+        }
+        events.extend([note(50, 9, 38, 100), note(70, 9, 38, 0), note(150, 9, 42, 100), note(170, 9, 42, 0)]);
+        events.sort_by_key(|(time, _)| *time);
+        let (hps, _) = events_to_hps(&events, None).unwrap();
+
+        let mobile = hps_to_mobile(&hps).unwrap();
+        let file = Smaf::parse(&mobile).unwrap();
+        assert!(matches!(&file.chunks[1], SmafChunk::ScoreTrack(5, track) if track.format_type == FormatType::MobileStandardNoCompress));
+
+        let notes = |smaf: &[u8]| {
+            parse_smaf(smaf)
+                .unwrap()
+                .into_iter()
+                .filter_map(|(time, _, event)| match event {
+                    SmafEvent::MidiNoteOn { channel, note, .. } => Some((time, true, channel, note)),
+                    SmafEvent::MidiNoteOff { channel, note, .. } => Some((time, false, channel, note)),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(notes(&mobile), notes(&hps));
+
+        // a rhythm channel playing an ma rhythm key and a melody channel forced to rhythm by a bank select
+        let hps = SmafBuilder::new()
+            .score_track(FormatType::HandyPhoneStandard, 20, |track| {
+                track
+                    .channel_type(0, ChannelType::Rhythm)
+                    .program(0, 0x12)
+                    .note(0, 1, 0, 5)
+                    .bank_select(1, 0x80)
+                    .program(1, 38)
+                    .wait(5)
+                    .note(1, 24, 0, 5)
+            })
+            .build()
+            .unwrap();
+        let mobile = hps_to_mobile(&hps).unwrap();
+        assert_eq!(
+            notes(&hps),
+            [(0, true, 9, 45), (100, false, 9, 45), (100, true, 9, 38), (200, false, 9, 38)]
+        );
+        assert_eq!(notes(&mobile), notes(&hps));
+    }
+}
@@ -0,0 +1,397 @@
+// conversion of midi event streams into mobile standard smaf files
+
+use alloc::{format, vec::Vec};
+
+use smaf::{timebases, write_chunk, write_score_track, write_smaf, ChannelStatus, ChannelType, FormatType, ScoreTrackSequenceEvent, SequenceData};
+
+use crate::{hps::events_to_hps, midi::read_midi, PlayerError, Result, SmafEvent, MA_MELODY_BANK, MA_RHYTHM_BANK, MA_RHYTHM_KEYS, MIDI_DRUM_CHANNEL};
+
+pub(crate) const CONTENTS_INFO: [u8; 5] = [0x00, 0x32, 0x01, 0x00, 0x00]; // class, type, code type, copy status, copy count
+const SCORE_TRACK: u8 = 5; // mobile standard score tracks are numbered from 5
+const TIMING_TOLERANCE_MS: usize = 1;
+
+#[derive(Clone)]
+pub struct ImportOptions {
+    pub format_type: FormatType,
+    pub timebase: Option<u8>, // in ms, picked from the event times when none
+}
+
+impl Default for ImportOptions {
+    fn default() -> Self {
+        Self {
+            format_type: FormatType::MobileStandardNoCompress,
+            timebase: None,
+        }
+    }
+}
+
+// what could not be carried over as is
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ImportReport {
+    pub unmappable_drum_keys: Vec<u8>, // gm drum keys that are ma rhythm keys themselves and play as another drum, each listed once
+}
+
+pub fn import_midi(raw: &[u8], options: &ImportOptions) -> Result<(Vec<u8>, ImportReport)> {
+    events_to_smaf(&read_midi(raw)?, options)
+}
+
+// midi channels map to smaf channels one to one, the drum channel becomes a rhythm channel playing ma rhythm keys.
+// handy phone standard output goes through events_to_hps, use it directly for the report of dropped content.
+pub fn events_to_smaf(events: &[(usize, SmafEvent)], options: &ImportOptions) -> Result<(Vec<u8>, ImportReport)> {
+    if options.format_type == FormatType::HandyPhoneStandard {
+        let (smaf, report) = events_to_hps(events, options.timebase)?;
+        return Ok((
+            smaf,
+            ImportReport {
+                unmappable_drum_keys: report.unmappable_drum_keys,
+            },
+        ));
+    }
+
+    let mut report = ImportReport::default();
+
+    // stable, so events of the same time keep their order
+    let mut events = events.to_vec();
+    events.sort_by_key(|(time, _)| *time);
+
+    let (mut events, used_channels) = pair_notes(&events);
+    for channel in (0..16u8).rev().filter(|x| used_channels[*x as usize]) {
+        let bank = if channel == MIDI_DRUM_CHANNEL { MA_RHYTHM_BANK } else { MA_MELODY_BANK };
+        let bank_select = [(0, bank), (32, 0)].map(|(control, value)| (0, ScoreTrackSequenceEvent::ControlChange { channel, control, value }));
+        events.splice(0..0, bank_select);
+    }
+
+    let timebase = match options.timebase {
+        Some(timebase) if timebases().any(|x| x == timebase) => timebase,
+        Some(timebase) => return Err(PlayerError::Unsupported(format!("timebase {timebase} ms"))),
+        None => pick_timebase(&events),
+    };
+    let quantize = |time| quantize(time, timebase);
+
+    let mut sequence = Vec::new();
+    let mut now = 0;
+    for (time, event) in events {
+        let time = quantize(time);
+        let event = match event {
+            ScoreTrackSequenceEvent::NoteMessage {
+                channel,
+                note,
+                velocity,
+                gate_time,
+            } => ScoreTrackSequenceEvent::NoteMessage {
+                channel,
+                note: if channel == MIDI_DRUM_CHANNEL {
+                    ma_rhythm_key(note, &mut report.unmappable_drum_keys)
+                } else {
+                    note
+                },
+                velocity,
+                gate_time: (quantize(gate_time as usize) - time).max(1) as u32,
+            },
+            event => event,
+        };
+
+        sequence.push(SequenceData {
+            duration: (time - now) as u32,
+            event,
+        });
+        now = time;
+    }
+
+    let channel_status = (0..16)
+        .map(|channel| {
+            let channel_type = if !used_channels[channel] {
+                ChannelType::NoCare
+            } else if channel == MIDI_DRUM_CHANNEL as usize {
+                ChannelType::Rhythm
+            } else {
+                ChannelType::Melody
+            };
+
+            ChannelStatus {
+                kcs: 0,
+                vs: 0,
+                led: 0,
+                channel_type,
+            }
+            .write_mobile()
+        })
+        .collect::<Vec<_>>();
+    let sequence_data = if options.format_type == FormatType::MobileStandardCompress {
+        SequenceData::write_mobile_compressed(&sequence)
+    } else {
+        SequenceData::write_mobile(&sequence)
+    };
+
+    let mut chunks = Vec::new();
+    write_chunk(&mut chunks, b"CNTI", &CONTENTS_INFO);
+    write_chunk(
+        &mut chunks,
+        &[b'M', b'T', b'R', SCORE_TRACK],
+        &write_score_track(options.format_type, timebase, timebase, &channel_status, &[], &sequence_data)?,
+    );
+
+    Ok((write_smaf(&chunks), report))
+}
+
+// the key smaf players sound as the gm drum key. gm keys that are ma rhythm keys themselves have none, they are kept
+// and listed in `unmappable`
+pub(crate) fn ma_rhythm_key(gm_key: u8, unmappable: &mut Vec<u8>) -> u8 {
+    if MA_RHYTHM_KEYS.iter().any(|(ma_key, _)| *ma_key == gm_key) {
+        if !unmappable.contains(&gm_key) {
+            unmappable.push(gm_key);
+        }
+        return gm_key;
+    }
+
+    MA_RHYTHM_KEYS.iter().find(|(_, x)| *x == gm_key).map_or(gm_key, |(ma_key, _)| *ma_key)
+}
+
+// note on/off pairs become note messages, their gate time temporarily holding the absolute end in ms.
+// midi bank selects are dropped, the ma melody bank follows gm numbering while banks of the file are gm2/gs/xg specific.
+pub(crate) fn pair_notes(events: &[(usize, SmafEvent)]) -> (Vec<(usize, ScoreTrackSequenceEvent)>, [bool; 16]) {
+    let mut used_channels = [false; 16];
+    let mut result = Vec::new();
+    let mut sounding: Vec<(u8, u8, usize)> = Vec::new(); // channel, note, index into result
+    let end = events.iter().map(|(time, _)| *time).max().unwrap_or(0);
+
+    for (time, event) in events {
+        let (channel, event) = match event {
+            SmafEvent::MidiNoteOn { channel, note, velocity } => {
+                sounding.push((*channel, *note, result.len()));
+                (
+                    *channel,
+                    ScoreTrackSequenceEvent::NoteMessage {
+                        channel: *channel,
+                        note: *note,
+                        velocity: Some(*velocity),
+                        gate_time: end as u32,
+                    },
+                )
+            }
+            SmafEvent::MidiNoteOff { channel, note, .. } => {
+                if let Some(index) = sounding.iter().position(|(x, y, _)| x == channel && y == note) {
+                    let (_, _, index) = sounding.remove(index);
+                    if let (_, ScoreTrackSequenceEvent::NoteMessage { gate_time, .. }) = &mut result[index] {
+                        *gate_time = *time as u32;
+                    }
+                }
+                continue;
+            }
+            SmafEvent::MidiControlChange { control: 0 | 32, .. } => continue,
+            SmafEvent::MidiControlChange { channel, control, value } => (
+                *channel,
+                ScoreTrackSequenceEvent::ControlChange {
+                    channel: *channel,
+                    control: *control,
+                    value: *value,
+                },
+            ),
+            SmafEvent::MidiProgramChange { channel, program } => (
+                *channel,
+                ScoreTrackSequenceEvent::ProgramChange {
+                    channel: *channel,
+                    program: *program,
+                },
+            ),
+            SmafEvent::MidiPitchBend { channel, value } => (
+                *channel,
+                ScoreTrackSequenceEvent::PitchBend {
+                    channel: *channel,
+                    value: *value,
+                },
+            ),
+            SmafEvent::MidiSysEx(data) => {
+                let data = data.strip_prefix(&[0xf0]).unwrap_or(data);
+                result.push((*time, ScoreTrackSequenceEvent::Exclusive(data.to_vec())));
+                continue;
+            }
+            SmafEvent::Wave { .. } | SmafEvent::WaveStop | SmafEvent::NoteDropped { .. } | SmafEvent::End => continue,
+        };
+
+        used_channels[(channel & 0x0f) as usize] = true;
+        result.push((*time, event));
+    }
+
+    (result, used_channels)
+}
+
+pub(crate) fn quantize(time: usize, timebase: u8) -> usize {
+    (time + timebase as usize / 2) / timebase as usize
+}
+
+// the coarsest timebase that keeps every event and note end within the tolerance
+pub(crate) fn pick_timebase(events: &[(usize, ScoreTrackSequenceEvent)]) -> u8 {
+    let times = events.iter().flat_map(|(time, event)| match event {
+        ScoreTrackSequenceEvent::NoteMessage { gate_time, .. } => [*time, *gate_time as usize],
+        _ => [*time, *time],
+    });
+
+    timebases()
+        .filter(|&timebase| {
+            times.clone().all(|time| {
+                let error = time % timebase as usize;
+                error.min(timebase as usize - error) <= TIMING_TOLERANCE_MS
+            })
+        })
+        .max()
+        .unwrap_or(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use smaf::{FormatType, ScoreTrackChunk, ScoreTrackSequenceEvent, Smaf, SmafChunk};
+
+    use super::{events_to_smaf, import_midi, ImportOptions};
+    use crate::{parse_smaf, read_midi, write_midi, EventSource, SmafEvent, TrackKind};
+
+    fn note(time: usize, channel: u8, note: u8, velocity: u8) -> (usize, SmafEvent) {
+        if velocity == 0 {
+            (time, SmafEvent::MidiNoteOff { channel, note, velocity })
+        } else {
+            (time, SmafEvent::MidiNoteOn { channel, note, velocity })
+        }
+    }
+
+    fn notes(events: &[(usize, EventSource, SmafEvent)]) -> Vec<(usize, bool, u8, u8)> {
+        events
+            .iter()
+            .filter_map(|(time, _, event)| match event {
+                SmafEvent::MidiNoteOn { channel, note, .. } => Some((*time, true, *channel, *note)),
+                SmafEvent::MidiNoteOff { channel, note, .. } => Some((*time, false, *channel, *note)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn converts_notes_with_exact_timebase() {
+        let events = [
+            (0, SmafEvent::MidiProgramChange { channel: 0, program: 40 }),
+            note(0, 0, 60, 100),
+            note(250, 9, 36, 90),
+            note(300, 9, 36, 0),
+            note(500, 0, 60, 0),
+        ];
+
+        let smaf = events_to_smaf(&events, &ImportOptions::default()).unwrap().0;
+
+        let file = Smaf::parse(&smaf).unwrap();
+        let SmafChunk::ScoreTrack(5, track) = &file.chunks[1] else {
+            panic!("Expected ScoreTrack chunk");
+        };
+        assert_eq!(track.format_type, FormatType::MobileStandardNoCompress);
+        assert_eq!(track.timebase_d, 50);
+        assert_eq!(
+            track.channel_status.iter().map(|x| x.channel_type.to_u8()).collect::<Vec<_>>()[..10],
+            [1, 0, 0, 0, 0, 0, 0, 0, 0, 3]
+        );
+
+        let played = parse_smaf(&smaf).unwrap();
+        assert_eq!(
+            notes(&played),
+            [(0, true, 0, 60), (250, true, 9, 36), (300, false, 9, 36), (500, false, 0, 60)]
+        );
+        assert!(played
+            .iter()
+            .any(|(_, _, event)| matches!(event, SmafEvent::MidiProgramChange { channel: 0, program: 40 })));
+    }
+
+    #[test]
+    fn quantizes_to_requested_timebase() {
+        let events = [note(0, 0, 60, 100), note(13, 0, 60, 0), note(27, 0, 62, 100), note(31, 0, 62, 0)];
+        let options = ImportOptions {
+            format_type: FormatType::MobileStandardCompress,
+            timebase: Some(10),
+        };
+
+        let played = parse_smaf(&events_to_smaf(&events, &options).unwrap().0).unwrap();
+        assert_eq!(
+            notes(&played),
+            [(0, true, 0, 60), (10, false, 0, 60), (30, true, 0, 62), (40, false, 0, 62)]
+        );
+
+        let options = ImportOptions {
+            timebase: Some(3),
+            ..Default::default()
+        };
+        assert!(events_to_smaf(&events, &options).is_err());
+    }
+
+    #[test]
+    fn round_trips_drum_keys_through_ma_rhythm_keys() {
+        let source = EventSource::track(TrackKind::ScoreTrack, 0);
+        let drums = [45, 36, 77]
+            .into_iter()
+            .enumerate()
+            .flat_map(|(index, key)| [note(index * 100, 9, key, 100), note(index * 100 + 50, 9, key, 0)]);
+        let midi = write_midi(&drums.map(|(time, event)| (time, source, event)).collect::<Vec<_>>());
+
+        let (smaf, report) = import_midi(&midi, &ImportOptions::default()).unwrap();
+        assert_eq!(report.unmappable_drum_keys, [77]);
+
+        let file = Smaf::parse(&smaf).unwrap();
+        let SmafChunk::ScoreTrack(5, track) = &file.chunks[1] else {
+            panic!("Expected ScoreTrack chunk");
+        };
+        let Some(ScoreTrackChunk::SequenceData(sequence)) = track.chunks.iter().find(|x| matches!(x, ScoreTrackChunk::SequenceData(_))) else {
+            panic!("Expected SequenceData chunk");
+        };
+        let keys = sequence
+            .iter()
+            .filter_map(|x| match x.event {
+                ScoreTrackSequenceEvent::NoteMessage { note, .. } => Some(note),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(keys, [0x12, 36, 77]);
+
+        let played = read_midi(&write_midi(&parse_smaf(&smaf).unwrap())).unwrap();
+        let played = played.into_iter().map(|(time, event)| (time, source, event)).collect::<Vec<_>>();
+        assert_eq!(
+            notes(&played)[..4],
+            [(0, true, 9, 45), (50, false, 9, 45), (100, true, 9, 36), (150, false, 9, 36)]
+        );
+    }
+
+    #[test]
+    fn sorts_events_by_time() {
+        let events = [note(500, 0, 60, 0), note(300, 0, 62, 0), note(0, 0, 60, 100), note(250, 0, 62, 100)];
+
+        let played = parse_smaf(&events_to_smaf(&events, &ImportOptions::default()).unwrap().0).unwrap();
+        assert_eq!(
+            notes(&played),
+            [(0, true, 0, 60), (250, true, 0, 62), (300, false, 0, 62), (500, false, 0, 60)]
+        );
+    }
+
+    #[test]
+    fn imports_midi_file() {
+        let source = EventSource::track(TrackKind::ScoreTrack, 0);
+        let midi = write_midi(&[
+            (
+                0,
+                source,
+                SmafEvent::MidiNoteOn {
+                    channel: 2,
+                    note: 64,
+                    velocity: 80,
+                },
+            ),
+            (
+                120,
+                source,
+                SmafEvent::MidiNoteOff {
+                    channel: 2,
+                    note: 64,
+                    velocity: 0,
+                },
+            ),
+        ]);
+
+        let played = parse_smaf(&import_midi(&midi, &ImportOptions::default()).unwrap().0).unwrap();
+        assert_eq!(notes(&played), [(0, true, 0, 64), (120, false, 0, 64)]);
+    }
+}
@@ -1,62 +1,175 @@
 #![no_std]
 extern crate alloc;
-
-use alloc::vec::Vec;
-mod adpcm;
+#[cfg(feature = "mp3")]
+extern crate std;
+
+use alloc::{string::String, vec, vec::Vec};
+use core::result;
+mod allocator;
+mod atmosphere;
+mod default_voices;
+mod extract;
+mod fm;
+mod hps;
+mod import;
+mod midi;
+mod mp3;
+mod parts;
+mod pcm;
+mod pcm_channel;
+mod profile;
+mod render;
+mod voice;
+mod wav;
+mod wave_import;
 
 use smaf::{
-    Channel, ChannelStatus, ChannelType, PCMAudioSequenceEvent, PCMAudioTrack, PCMAudioTrackChunk, PCMDataChunk, ScoreTrack, ScoreTrackChunk,
-    ScoreTrackSequenceEvent, Smaf, SmafChunk,
+    ChannelStatus, ChannelType, PCMAudioSequenceEvent, PCMAudioTrack, PCMAudioTrackChunk, PCMDataChunk, ScoreTrack, ScoreTrackChunk,
+    ScoreTrackSequenceEvent, Smaf, SmafChunk, SmafError,
+};
+
+use self::{
+    allocator::{allocate_channels, FIRST_VIRTUAL_CHANNEL},
+    pcm::{channel_count, decode_pcm_audio_wave, decode_stream_wave},
+    pcm_channel::{apply_controls, apply_gate, PcmChannel},
+};
+
+pub use self::{
+    atmosphere::{AtmosphereConfig, AtmosphereLayer},
+    default_voices::default_voice_bank,
+    extract::{extract_waves, ExtractedWave},
+    fm::{FmOperator, FmSynth, FmVoice},
+    hps::{events_to_hps, HpsReport},
+    import::{events_to_smaf, import_midi, ImportOptions, ImportReport},
+    midi::{read_midi, write_midi},
+    mp3::extract_mp3,
+    parts::PartFilter,
+    profile::OutputProfile,
+    render::{render, render_events, render_with_options},
+    voice::{decode_fm_voice, FmVoiceBank},
+    wav::{read_wav, write_wav},
+    wave_import::{add_wave, WaveImportOptions, WaveTarget},
 };
 
-use self::adpcm::decode_adpcm;
+#[derive(Debug)]
+pub enum PlayerError {
+    SmafError(SmafError),
+    MissingSequenceData,
+    MissingWaveData(u8),
+    Unsupported(String),
+    InvalidMidi(String),
+    InvalidWave(String),
+    InvalidMp3(String),
+}
+
+impl From<SmafError> for PlayerError {
+    fn from(e: SmafError) -> Self {
+        Self::SmafError(e)
+    }
+}
+
+pub type Result<T> = result::Result<T, PlayerError>;
 
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub enum TrackKind {
+    ScoreTrack,           // MTRx
+    PCMAudioTrack,        // ATRx
+    SoftbankSequenceData, // SEQU
+    Player,               // generated by the player, e.g. output profile reset
+}
+
+#[derive(Eq, PartialEq, Copy, Clone, Debug)]
+pub struct EventSource {
+    pub kind: TrackKind,
+    pub track: u8,
+    pub channel: Option<u8>,        // logical smaf channel, hps channels are numbered across tracks
+    pub event_index: Option<usize>, // index into the track's sequence data
+}
+
+impl EventSource {
+    fn track(kind: TrackKind, track: u8) -> Self {
+        Self {
+            kind,
+            track,
+            channel: None,
+            event_index: None,
+        }
+    }
+}
+
+#[derive(Clone)]
 pub enum SmafEvent {
     Wave { channel: u8, sampling_rate: u32, data: Vec<i16> },
+    WaveStop, // gate time of the wave with the same source ran out, the wave data already ends there
     MidiNoteOn { channel: u8, note: u8, velocity: u8 },
     MidiNoteOff { channel: u8, note: u8, velocity: u8 },
     MidiProgramChange { channel: u8, program: u8 },
     MidiControlChange { channel: u8, control: u8, value: u8 },
     MidiPitchBend { channel: u8, value: u16 },
     MidiSysEx(Vec<u8>),
+    NoteDropped { channel: u8, note: u8 }, // no midi channel was free to play the note, channel is the virtual one
     End,
 }
 
-pub fn parse_smaf(raw: &[u8]) -> Vec<(usize, SmafEvent)> {
-    let Ok(smaf) = Smaf::parse(raw) else {
-        return Vec::new();
-    };
+#[derive(Clone, Default)]
+pub struct PlayerOptions {
+    pub profile: OutputProfile,
+    pub atmosphere: AtmosphereConfig,
+    pub parts: PartFilter,
+}
+
+pub fn parse_smaf(raw: &[u8]) -> Result<Vec<(usize, EventSource, SmafEvent)>> {
+    parse_smaf_with_options(raw, &PlayerOptions::default())
+}
+
+pub fn parse_smaf_with_options(raw: &[u8], options: &PlayerOptions) -> Result<Vec<(usize, EventSource, SmafEvent)>> {
+    parse_smaf_events(raw, options, false)
+}
+
+// native voices keep ma banks, programs and rhythm keys as the file has them, for the built-in synthesizer
+// which knows the ma voice set. midi outputs get them translated by the profile instead.
+pub(crate) fn parse_smaf_events(raw: &[u8], options: &PlayerOptions, native_voices: bool) -> Result<Vec<(usize, EventSource, SmafEvent)>> {
+    let smaf = Smaf::parse(raw)?;
 
     let mut result = Vec::new();
     let mut handy_channel_offset = 0;
-    let mut handy_tone_map = ToneMap::new();
+    let mut tone_map = ToneMap::new(options);
+    tone_map.native_voices = native_voices;
+
+    for message in options.profile.reset_messages() {
+        result.push((0, EventSource::track(TrackKind::Player, 0), SmafEvent::MidiSysEx(message.to_vec())));
+    }
 
     for chunk in &smaf.chunks {
         match chunk {
-            SmafChunk::ScoreTrack(_, x) => {
-                let (events, next_offset) = parse_score_track_events(x, handy_channel_offset, &mut handy_tone_map);
+            SmafChunk::ScoreTrack(track_number, x) => {
+                let (events, next_offset) = parse_score_track_events(*track_number, x, handy_channel_offset, &mut tone_map, options);
                 result.extend(events);
                 handy_channel_offset = next_offset;
             }
-            SmafChunk::PCMAudioTrack(_, x) => result.extend(parse_pcm_audio_track_events(x)),
+            SmafChunk::PCMAudioTrack(track_number, x) if options.parts.plays_track(TrackKind::PCMAudioTrack, *track_number) => {
+                result.extend(parse_pcm_audio_track_events(*track_number, x)?)
+            }
             SmafChunk::SoftbankSequenceData(x) => {
-                let mut tone_map = ToneMap::new();
                 tone_map.init_track(smaf::FormatType::HandyPhoneStandard, &[], handy_channel_offset);
-                let (events, next_offset) = parse_sequence_events(x, 20, 20, handy_channel_offset, true, &[], &mut tone_map);
-                result.extend(events);
+                let source = EventSource::track(TrackKind::SoftbankSequenceData, 0);
+                let (events, next_offset) = parse_sequence_events(x, 20, 20, handy_channel_offset, true, &[], &mut tone_map, source, &options.parts);
+                if options.parts.plays_track(source.kind, source.track) {
+                    result.extend(events);
+                }
                 handy_channel_offset = next_offset;
             }
             _ => {}
         }
     }
 
-    result.sort_by(|(left_time, left_event), (right_time, right_event)| {
+    result.sort_by(|(left_time, _, left_event), (right_time, _, right_event)| {
         left_time
             .cmp(right_time)
             .then_with(|| event_sort_key(left_event).cmp(&event_sort_key(right_event)))
     });
 
-    result
+    Ok(allocate_channels(result))
 }
 
 fn event_sort_key(event: &SmafEvent) -> (u8, [u8; 3]) {
@@ -67,14 +180,22 @@ fn event_sort_key(event: &SmafEvent) -> (u8, [u8; 3]) {
         SmafEvent::MidiProgramChange { channel, program } => (6, [0xc0 | *channel, *program, 0]),
         SmafEvent::MidiNoteOff { channel, note, velocity } => (20, [0x80 | *channel, *note, *velocity]),
         SmafEvent::MidiNoteOn { channel, note, velocity } => (30, [0x90 | *channel, *note, *velocity]),
+        SmafEvent::WaveStop => (25, [0, 0, 0]),
         SmafEvent::Wave { channel, .. } => (40, [*channel, 0, 0]),
+        SmafEvent::NoteDropped { channel, note } => (50, [*channel, *note, 0]),
         SmafEvent::End => (99, [0xff, 0x2f, 0]),
     }
 }
 
-fn parse_score_track_events(track: &ScoreTrack, handy_channel_offset: u8, handy_tone_map: &mut ToneMap) -> (Vec<(usize, SmafEvent)>, u8) {
+fn parse_score_track_events(
+    track_number: u8,
+    track: &ScoreTrack,
+    handy_channel_offset: u8,
+    tone_map: &mut ToneMap,
+    options: &PlayerOptions,
+) -> (Vec<(usize, EventSource, SmafEvent)>, u8) {
     let mut result = Vec::new();
-    let mut mobile_tone_map = ToneMap::new();
+    let source = EventSource::track(TrackKind::ScoreTrack, track_number);
     let pcm_chunks = track
         .chunks
         .iter()
@@ -87,11 +208,20 @@ fn parse_score_track_events(track: &ScoreTrack, handy_channel_offset: u8, handy_
         })
         .unwrap_or(&[]);
     let is_handy = track.format_type == smaf::FormatType::HandyPhoneStandard;
+    let next_offset = if is_handy {
+        handy_channel_offset.saturating_add(4)
+    } else {
+        handy_channel_offset
+    };
+
+    if !options.parts.plays_track(TrackKind::ScoreTrack, track_number) {
+        return (result, next_offset);
+    }
 
     if is_handy {
-        handy_tone_map.init_track(track.format_type, &track.channel_status, handy_channel_offset);
+        tone_map.init_track(track.format_type, &track.channel_status, handy_channel_offset);
     } else {
-        mobile_tone_map.init_track(track.format_type, &track.channel_status, 0);
+        tone_map.init_track(track.format_type, &track.channel_status, 0);
     }
 
     for setup_data in track
@@ -99,11 +229,14 @@ fn parse_score_track_events(track: &ScoreTrack, handy_channel_offset: u8, handy_
         .iter()
         .filter_map(|chunk| if let ScoreTrackChunk::SetupData(x) = chunk { Some(*x) } else { None })
     {
-        result.extend(parse_setup_sysex_events(setup_data));
+        result.extend(
+            parse_setup_sysex_events(setup_data)
+                .into_iter()
+                .filter(|(_, event)| !matches!(event, SmafEvent::MidiSysEx(data) if !options.profile.forwards_exclusive(data)))
+                .map(|(time, event)| (time, source, event)),
+        );
     }
 
-    let tone_map: &mut ToneMap = if is_handy { handy_tone_map } else { &mut mobile_tone_map };
-
     for sequence_data in track
         .chunks
         .iter()
@@ -117,20 +250,17 @@ fn parse_score_track_events(track: &ScoreTrack, handy_channel_offset: u8, handy_
             handy_channel_offset,
             is_handy,
             pcm_chunks,
-            &mut *tone_map,
+            tone_map,
+            source,
+            &options.parts,
         );
         result.extend(events);
     }
 
-    let next_offset = if is_handy {
-        handy_channel_offset.saturating_add(4)
-    } else {
-        handy_channel_offset
-    };
-
     (result, next_offset)
 }
 
+#[allow(clippy::too_many_arguments)]
 fn parse_sequence_events(
     sequence_data: &[smaf::SequenceData],
     timebase_d: u8,
@@ -139,8 +269,12 @@ fn parse_sequence_events(
     use_channel_offset: bool,
     pcm_chunks: &[PCMDataChunk<'_>],
     tone_map: &mut ToneMap,
-) -> (Vec<(usize, SmafEvent)>, u8) {
+    track_source: EventSource,
+    parts: &PartFilter,
+) -> (Vec<(usize, EventSource, SmafEvent)>, u8) {
     let mut result = Vec::new();
+    let mut sources = Vec::new();
+    let mut source = track_source;
     let mut now = 0;
     let mut octave_shift = [0i8; MAX_SMAF_CHANNELS];
 
@@ -152,10 +286,22 @@ fn parse_sequence_events(
         }
     };
 
-    for event in sequence_data.iter() {
+    for (event_index, event) in sequence_data.iter().enumerate() {
         now += (event.duration * (timebase_d as u32)) as usize;
         let time = now;
 
+        // everything pushed since the previous sequence event came from it
+        sources.resize(result.len(), source);
+        source = EventSource {
+            channel: sequence_event_channel(&event.event).map(map_channel),
+            event_index: Some(event_index),
+            ..track_source
+        };
+        // muted parts are dropped before they can claim a midi channel
+        if !parts.plays(&source) {
+            continue;
+        }
+
         match event.event {
             ScoreTrackSequenceEvent::NoteMessage {
                 channel,
@@ -178,28 +324,14 @@ fn parse_sequence_events(
                         continue;
                     };
 
-                    match pcm.format {
-                        smaf::StreamWaveFormat::YamahaADPCM => {
-                            if pcm.base_bit != smaf::BaseBit::Bit4 || pcm.channel != Channel::Mono {
-                                continue;
-                            }
-
-                            let decoded = decode_adpcm(pcm.wave_data);
-                            let channel = match pcm.channel {
-                                Channel::Mono => 1,
-                                Channel::Stereo => 2,
-                            };
-                            result.push((
-                                time,
-                                SmafEvent::Wave {
-                                    channel,
-                                    sampling_rate: pcm.sampling_freq as _,
-                                    data: decoded,
-                                },
-                            ))
-                        }
-                        smaf::StreamWaveFormat::TwosComplementPCM | smaf::StreamWaveFormat::OffsetBinaryPCM => {}
-                    }
+                    result.push((
+                        time,
+                        SmafEvent::Wave {
+                            channel: channel_count(pcm.channel),
+                            sampling_rate: pcm.sampling_freq as _,
+                            data: decode_stream_wave(pcm.format, pcm.base_bit, pcm.channel, pcm.wave_data),
+                        },
+                    ))
                 } else {
                     let duration = (gate_time * (timebase_g as u32)) as usize;
                     let channel_index = (channel as usize).min(octave_shift.len() - 1);
@@ -229,12 +361,16 @@ fn parse_sequence_events(
             ScoreTrackSequenceEvent::ControlChange { channel, control, value } => {
                 let channel = map_channel(channel);
                 tone_map.update_control(channel, control, value);
+                if (control == 0 || control == 32) && !tone_map.profile.forwards_bank_select() {
+                    continue;
+                }
                 let channel = tone_map.real_channel(channel);
                 result.push((time, SmafEvent::MidiControlChange { channel, control, value }))
             }
             ScoreTrackSequenceEvent::ProgramChange { channel, program } => {
                 let source_channel = map_channel(channel);
                 let (channel, mapped_program) = tone_map.set_program(source_channel, program);
+                result.extend(tone_map.emit_bank_select(time, source_channel));
                 result.push((
                     time,
                     SmafEvent::MidiProgramChange {
@@ -245,6 +381,9 @@ fn parse_sequence_events(
                 result.extend(tone_map.emit_atmosphere_setup(time, source_channel, program));
             }
             ScoreTrackSequenceEvent::Exclusive(ref data) => {
+                if !tone_map.profile.forwards_exclusive(data) {
+                    continue;
+                }
                 result.push((time, SmafEvent::MidiSysEx(make_sysex_message(data))));
             }
             ScoreTrackSequenceEvent::Nop => continue,
@@ -322,6 +461,9 @@ fn parse_sequence_events(
             ScoreTrackSequenceEvent::BankSelect { channel, value } => {
                 let channel = map_channel(channel);
                 tone_map.update_bank_select(channel, value);
+                if !tone_map.profile.forwards_bank_select() {
+                    continue;
+                }
                 let midi_channel = tone_map.real_channel(channel);
                 result.push((
                     time,
@@ -334,7 +476,9 @@ fn parse_sequence_events(
             }
         }
     }
+    sources.resize(result.len(), source);
     result.push((now, SmafEvent::End));
+    sources.push(track_source);
 
     let next_offset = if use_channel_offset {
         channel_offset.saturating_add(4)
@@ -342,14 +486,44 @@ fn parse_sequence_events(
         channel_offset
     };
 
+    let result = result
+        .into_iter()
+        .zip(sources)
+        .map(|((time, event), source)| (time, source, event))
+        .collect();
+
     (result, next_offset)
 }
 
+fn sequence_event_channel(event: &ScoreTrackSequenceEvent) -> Option<u8> {
+    match *event {
+        ScoreTrackSequenceEvent::NoteMessage { channel, .. }
+        | ScoreTrackSequenceEvent::ControlChange { channel, .. }
+        | ScoreTrackSequenceEvent::ProgramChange { channel, .. }
+        | ScoreTrackSequenceEvent::BankSelect { channel, .. }
+        | ScoreTrackSequenceEvent::OctaveShift { channel, .. }
+        | ScoreTrackSequenceEvent::Modulation { channel, .. }
+        | ScoreTrackSequenceEvent::PitchBend { channel, .. }
+        | ScoreTrackSequenceEvent::Volume { channel, .. }
+        | ScoreTrackSequenceEvent::Pan { channel, .. }
+        | ScoreTrackSequenceEvent::Expression { channel, .. } => Some(channel),
+        ScoreTrackSequenceEvent::Exclusive(_) | ScoreTrackSequenceEvent::Nop => None,
+    }
+}
+
 const MIDI_DRUM_CHANNEL: u8 = 9;
+const MA_MELODY_BANK: u8 = 0x7c;
+const MA_RHYTHM_BANK: u8 = 0x7d;
+// (ma-3 program in bank 0x7c/0x01, closest gm program)
+const MA_MELODY_VARIATIONS: [(u8, u8); 6] = [(0x22, 81), (0x70, 30), (0x46, 84), (0x21, 33), (0x6a, 87), (0x62, 98)];
+// (ma-3 rhythm key, closest gm drum key)
+const MA_RHYTHM_KEYS: [(u8, u8); 6] = [(0x12, 45), (0x1a, 41), (0x1f, 47), (0x4d, 50), (0x54, 43), (0x59, 48)];
 const MAX_SMAF_CHANNELS: usize = 64;
 const MELODY_ALLOCATION_ORDER: [u8; 15] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 10, 11, 12, 13, 14, 15];
 
 struct ToneMap {
+    profile: OutputProfile,
+    native_voices: bool,
     format_type: smaf::FormatType,
     channel_types: [u8; MAX_SMAF_CHANNELS],
     programs: [u8; MAX_SMAF_CHANNELS],
@@ -360,25 +534,19 @@ struct ToneMap {
     bank_lsb: [u8; MAX_SMAF_CHANNELS],
     forced_rhythm: [bool; MAX_SMAF_CHANNELS],
     real_map: [Option<u8>; MAX_SMAF_CHANNELS],
+    allocated_channels: [bool; 16],
     reserved_channels: [bool; 16],
+    next_virtual_channel: u8,
     atmosphere_source: [bool; MAX_SMAF_CHANNELS],
-    atmosphere_layers: [[Option<AtmosphereLayer>; 2]; MAX_SMAF_CHANNELS],
-}
-
-#[derive(Copy, Clone)]
-struct AtmosphereLayer {
-    channel: u8,
-    program: u8,
-    velocity_percent: u8,
-    note_offset: i8,
-    pan: u8,
-    pitch_bend: u16,
-    gate_extension_ms: usize,
+    atmosphere_layers: [Vec<AtmosphereLayer>; MAX_SMAF_CHANNELS],
+    atmosphere: AtmosphereConfig,
 }
 
 impl ToneMap {
-    fn new() -> Self {
+    fn new(options: &PlayerOptions) -> Self {
         Self {
+            profile: options.profile,
+            native_voices: false,
             format_type: smaf::FormatType::MobileStandardNoCompress,
             channel_types: [2; MAX_SMAF_CHANNELS],
             programs: [0; MAX_SMAF_CHANNELS],
@@ -389,9 +557,12 @@ impl ToneMap {
             bank_lsb: [0; MAX_SMAF_CHANNELS],
             forced_rhythm: [false; MAX_SMAF_CHANNELS],
             real_map: [None; MAX_SMAF_CHANNELS],
+            allocated_channels: [false; 16],
             reserved_channels: [false; 16],
+            next_virtual_channel: FIRST_VIRTUAL_CHANNEL,
             atmosphere_source: [false; MAX_SMAF_CHANNELS],
-            atmosphere_layers: [[None; 2]; MAX_SMAF_CHANNELS],
+            atmosphere_layers: core::array::from_fn(|_| Vec::new()),
+            atmosphere: options.atmosphere.clone(),
         }
     }
 
@@ -417,7 +588,7 @@ impl ToneMap {
                 self.bank_lsb[channel] = 0;
                 self.forced_rhythm[channel] = false;
                 self.atmosphere_source[channel] = false;
-                self.atmosphere_layers[channel] = [None; 2];
+                self.atmosphere_layers[channel].clear();
             }
             return;
         }
@@ -430,10 +601,10 @@ impl ToneMap {
         self.bank_msb = [0; MAX_SMAF_CHANNELS];
         self.bank_lsb = [0; MAX_SMAF_CHANNELS];
         self.forced_rhythm = [false; MAX_SMAF_CHANNELS];
+        // channels given to previous tracks stay taken, logical channels of this track are new parts
         self.real_map = [None; MAX_SMAF_CHANNELS];
-        self.reserved_channels = [false; 16];
         self.atmosphere_source = [false; MAX_SMAF_CHANNELS];
-        self.atmosphere_layers = [[None; 2]; MAX_SMAF_CHANNELS];
+        self.atmosphere_layers.iter_mut().for_each(Vec::clear);
 
         for (channel, status) in channel_statuses.iter().take(16).enumerate() {
             self.channel_types[channel] = match &status.channel_type {
@@ -504,22 +675,30 @@ impl ToneMap {
             return real_channel;
         }
 
+        let used = self.used_channels();
+        let real_channel = match MELODY_ALLOCATION_ORDER.iter().copied().find(|candidate| !used[*candidate as usize]) {
+            Some(real_channel) => {
+                self.allocated_channels[real_channel as usize] = true;
+                real_channel
+            }
+            None => {
+                // out of midi channels, share idle ones at playback time
+                let virtual_channel = self.next_virtual_channel;
+                self.next_virtual_channel = self.next_virtual_channel.saturating_add(1);
+                virtual_channel
+            }
+        };
+        self.real_map[channel] = Some(real_channel);
+        real_channel
+    }
+
+    fn used_channels(&self) -> [bool; 16] {
         let mut used = [false; 16];
         used[MIDI_DRUM_CHANNEL as usize] = true;
-        for real_channel in self.real_map.iter().flatten() {
-            used[*real_channel as usize] = true;
-        }
-        for (channel, reserved) in self.reserved_channels.iter().enumerate() {
-            used[channel] |= *reserved;
+        for (channel, used) in used.iter_mut().enumerate() {
+            *used |= self.allocated_channels[channel] || self.reserved_channels[channel];
         }
-
-        let real_channel = MELODY_ALLOCATION_ORDER
-            .iter()
-            .copied()
-            .find(|candidate| !used[*candidate as usize])
-            .unwrap_or(channel as u8);
-        self.real_map[channel] = Some(real_channel);
-        real_channel
+        used
     }
 
     fn update_control(&mut self, channel: u8, control: u8, value: u8) {
@@ -542,7 +721,8 @@ impl ToneMap {
 
     fn set_program(&mut self, channel: u8, program: u8) -> (u8, u8) {
         let channel = self.pseudo_channel(channel);
-        if self.format_type == smaf::FormatType::HandyPhoneStandard && self.channel_types[channel] == 3 {
+        // handy phone standard rhythm channels, typed or forced by bank select, play their program as the key
+        if self.format_type == smaf::FormatType::HandyPhoneStandard && self.is_rhythm(channel as u8) {
             self.programs[channel] = program.min(0x7f);
             return (MIDI_DRUM_CHANNEL, 0);
         }
@@ -553,25 +733,58 @@ impl ToneMap {
 
         let program = if self.is_rhythm(channel as u8) {
             0
-        } else {
+        } else if self.profile.collapses_voices() && !self.native_voices {
             self.map_program(channel as u8, program)
+        } else {
+            program & 0x7f
         };
         self.programs[channel] = program;
 
         (self.real_channel(channel as u8), program)
     }
 
+    fn emit_bank_select(&mut self, time: usize, channel: u8) -> Vec<(usize, SmafEvent)> {
+        let channel_index = self.pseudo_channel(channel);
+        let bank = if self.is_rhythm(channel) {
+            self.profile.rhythm_bank()
+        } else {
+            self.profile.melody_bank(self.bank_msb[channel_index], self.bank_lsb[channel_index])
+        };
+        let Some((msb, lsb)) = bank else {
+            return Vec::new();
+        };
+
+        let midi_channel = self.real_channel(channel);
+        vec![
+            (
+                time,
+                SmafEvent::MidiControlChange {
+                    channel: midi_channel,
+                    control: 0,
+                    value: msb,
+                },
+            ),
+            (
+                time,
+                SmafEvent::MidiControlChange {
+                    channel: midi_channel,
+                    control: 32,
+                    value: lsb,
+                },
+            ),
+        ]
+    }
+
     fn map_program(&self, channel: u8, program: u8) -> u8 {
         let channel = self.pseudo_channel(channel);
-        match (self.bank_msb[channel], self.bank_lsb[channel], program & 0x7f) {
-            (0x7c, 0x01, 0x22) => 81,
-            (0x7c, 0x01, 0x70) => 30,
-            (0x7c, 0x01, 0x46) => 84,
-            (0x7c, 0x01, 0x21) => 33,
-            (0x7c, 0x01, 0x6a) => 87,
-            (0x7c, 0x01, 0x62) => 98,
-            (0x7d, 0x00, 0x02) => 0,
-            _ => program & 0x7f,
+        let program = program & 0x7f;
+        match (self.bank_msb[channel], self.bank_lsb[channel]) {
+            (MA_MELODY_BANK, 0x01) => MA_MELODY_VARIATIONS
+                .iter()
+                .find(|(ma_program, _)| *ma_program == program)
+                .map_or(program, |(_, gm_program)| *gm_program),
+            (MA_RHYTHM_BANK, 0x00) if program == 0x02 => 0,
+            _ => program,
         }
     }
 
@@ -580,7 +793,7 @@ impl ToneMap {
 
         if self.format_type == smaf::FormatType::HandyPhoneStandard {
             if self.is_rhythm(channel) {
-                return self.programs[channel_index].min(0x7f);
+                return self.rhythm_key(self.programs[channel_index].min(0x7f));
             }
             return (note + 36).clamp(0, 127) as u8;
         }
@@ -589,21 +802,24 @@ impl ToneMap {
         if !self.is_rhythm(channel) {
             return note;
         }
+        self.rhythm_key(note)
+    }
 
-        match note {
-            0x12 => 45,
-            0x1a => 41,
-            0x1f => 47,
-            0x4d => 50,
-            0x54 => 43,
-            0x59 => 48,
-            _ => note,
+    // both formats share the ma rhythm keys
+    fn rhythm_key(&self, key: u8) -> u8 {
+        if self.native_voices {
+            return key;
         }
+
+        MA_RHYTHM_KEYS
+            .iter()
+            .find(|(ma_key, _)| *ma_key == key)
+            .map_or(key, |(_, gm_key)| *gm_key)
     }
 
     fn note_velocity(&mut self, channel: u8, velocity: Option<u8>) -> u8 {
         let channel = self.pseudo_channel(channel);
-        if self.format_type == smaf::FormatType::HandyPhoneStandard && self.channel_types[channel] == 3 {
+        if self.format_type == smaf::FormatType::HandyPhoneStandard && self.is_rhythm(channel as u8) {
             return self.hps_drum_velocity(channel);
         }
         if let Some(velocity) = velocity {
@@ -638,7 +854,7 @@ impl ToneMap {
 
     fn note_duration(&self, channel: u8, duration: usize) -> usize {
         if self.atmosphere_source[self.pseudo_channel(channel)] {
-            duration + 120
+            duration + self.atmosphere.source_gate_extension_ms
         } else {
             duration
         }
@@ -652,43 +868,18 @@ impl ToneMap {
         let channel_index = self.pseudo_channel(channel);
         self.atmosphere_source[channel_index] = true;
 
-        if self.atmosphere_layers[channel_index][0].is_none() {
-            let specs = [
-                AtmosphereLayer {
-                    channel: 15,
-                    program: 99,
-                    velocity_percent: 42,
-                    note_offset: 0,
-                    pan: 52,
-                    pitch_bend: 8192 - 170,
-                    gate_extension_ms: 220,
-                },
-                AtmosphereLayer {
-                    channel: 14,
-                    program: 94,
-                    velocity_percent: 30,
-                    note_offset: -12,
-                    pan: 76,
-                    pitch_bend: 8192 + 130,
-                    gate_extension_ms: 360,
-                },
-            ];
-
-            let mut next_layer = 0;
-            let mut used = [false; 16];
-            used[MIDI_DRUM_CHANNEL as usize] = true;
-            for real_channel in self.real_map.iter().flatten() {
-                used[*real_channel as usize] = true;
-            }
-            for (reserved_channel, reserved) in self.reserved_channels.iter().enumerate() {
-                used[reserved_channel] |= *reserved;
-            }
-
-            for spec in specs {
-                if !used[spec.channel as usize] && next_layer < self.atmosphere_layers[channel_index].len() {
-                    self.reserved_channels[spec.channel as usize] = true;
-                    self.atmosphere_layers[channel_index][next_layer] = Some(spec);
-                    next_layer += 1;
+        if self.atmosphere_layers[channel_index].is_empty() {
+            let mut used = self.used_channels();
+
+            for spec in &self.atmosphere.layers {
+                let channel = (spec.channel & 0x0f) as usize;
+                if !used[channel] {
+                    used[channel] = true;
+                    self.reserved_channels[channel] = true;
+                    self.atmosphere_layers[channel_index].push(AtmosphereLayer {
+                        channel: channel as u8,
+                        ..*spec
+                    });
                 }
             }
         }
@@ -721,7 +912,7 @@ impl ToneMap {
         ));
 
         let source_volume = ((self.channel_volumes[channel_index] as u16 * 70) / 100).clamp(1, 127) as u8;
-        for layer in self.atmosphere_layers[channel_index].iter().flatten() {
+        for layer in &self.atmosphere_layers[channel_index] {
             result.push((
                 time,
                 SmafEvent::MidiControlChange {
@@ -796,7 +987,7 @@ impl ToneMap {
         }
 
         let mut result = Vec::new();
-        for layer in self.atmosphere_layers[channel].iter().flatten() {
+        for layer in &self.atmosphere_layers[channel] {
             let note = (note as i16 + layer.note_offset as i16).clamp(0, 127) as u8;
             let velocity = ((velocity as u16 * layer.velocity_percent as u16) / 100).clamp(1, 127) as u8;
             result.push((
@@ -822,7 +1013,7 @@ impl ToneMap {
 
     fn is_atmosphere_voice(&self, channel: u8, program: u8) -> bool {
         let channel = self.pseudo_channel(channel);
-        (self.bank_msb[channel], self.bank_lsb[channel], program & 0x7f) == (0x7c, 0x01, 0x62)
+        self.atmosphere.enabled && (self.bank_msb[channel], self.bank_lsb[channel], program & 0x7f) == (0x7c, 0x01, 0x62)
     }
 }
 
@@ -884,7 +1075,8 @@ fn parse_octave_shift(value: u8) -> Option<i8> {
     }
 }
 
-fn parse_pcm_audio_track_events(track: &PCMAudioTrack) -> Vec<(usize, SmafEvent)> {
+fn parse_pcm_audio_track_events(track_number: u8, track: &PCMAudioTrack) -> Result<Vec<(usize, EventSource, SmafEvent)>> {
+    let track_source = EventSource::track(TrackKind::PCMAudioTrack, track_number);
     let sequence_data = track
         .chunks
         .iter()
@@ -895,20 +1087,30 @@ fn parse_pcm_audio_track_events(track: &PCMAudioTrack) -> Vec<(usize, SmafEvent)
                 None
             }
         })
-        .unwrap();
+        .ok_or(PlayerError::MissingSequenceData)?;
+
+    // (time, event index, channel, event) of the channel controls
+    let mut controls = Vec::new();
+    let mut now = 0;
+    for (event_index, event) in sequence_data.iter().enumerate() {
+        now += (event.duration * (track.timebase_d as u32)) as usize;
+        if let Some(channel) = pcm_control_channel(&event.event) {
+            controls.push((now, event_index, channel, &event.event));
+        }
+    }
 
     let mut result = Vec::new();
     let mut now = 0;
 
-    for event in sequence_data.iter() {
+    for (event_index, event) in sequence_data.iter().enumerate() {
         now += (event.duration * (track.timebase_d as u32)) as usize;
         let time = now;
 
         match event.event {
             PCMAudioSequenceEvent::WaveMessage {
-                channel: _,
+                channel: wave_channel,
                 wave_number,
-                gate_time: _,
+                gate_time,
             } => {
                 let pcm = track
                     .chunks
@@ -921,25 +1123,45 @@ fn parse_pcm_audio_track_events(track: &PCMAudioTrack) -> Vec<(usize, SmafEvent)
                         }
                         None
                     })
-                    .unwrap();
+                    .ok_or(PlayerError::MissingWaveData(wave_number))?;
+
+                let (channel, sampling_rate, decoded) = decode_pcm_audio_wave(track, pcm)?;
+
+                // controls before the wave message set up the channel, later ones change the playing wave
+                let mut state = PcmChannel::default();
+                let mut changes = Vec::new();
+                for (control_time, control_index, _, control) in controls.iter().filter(|x| x.2 == wave_channel) {
+                    if *control_index < event_index {
+                        state.update(control);
+                    } else {
+                        changes.push((*control_time, *control));
+                    }
+                }
+                let (channel, mut decoded) = apply_controls(&decoded, channel, sampling_rate, time, state, &changes);
 
-                assert!(track.format == smaf::PcmWaveFormat::Adpcm); // current decoder is adpcm only
-                assert!(track.channel == Channel::Mono); // current decoder is mono only
+                // a gate time of 0 plays the whole wave
+                let gate = (gate_time * track.timebase_g as u32) as usize;
+                let stopped = gate != 0 && apply_gate(&mut decoded, channel, sampling_rate, gate);
 
-                let decoded = decode_adpcm(pcm);
-                let channel = match track.channel {
-                    Channel::Mono => 1,
-                    Channel::Stereo => 2,
+                let source = EventSource {
+                    channel: Some(wave_channel),
+                    event_index: Some(event_index),
+                    ..track_source
                 };
                 result.push((
                     time,
+                    source,
                     SmafEvent::Wave {
                         channel,
-                        sampling_rate: track.sampling_freq as _,
+                        sampling_rate,
                         data: decoded,
                     },
-                ))
+                ));
+                if stopped {
+                    result.push((time + gate, source, SmafEvent::WaveStop));
+                }
             }
+            // channel controls are applied to the waves, exclusives carry nothing the player uses
             PCMAudioSequenceEvent::Expression { .. } => continue,
             PCMAudioSequenceEvent::Nop => continue,
             PCMAudioSequenceEvent::Pan { .. } => continue,
@@ -949,18 +1171,31 @@ fn parse_pcm_audio_track_events(track: &PCMAudioTrack) -> Vec<(usize, SmafEvent)
         }
     }
 
-    result.push((now, SmafEvent::End));
-    result
+    result.push((now, track_source, SmafEvent::End));
+    Ok(result)
+}
+
+fn pcm_control_channel(event: &PCMAudioSequenceEvent) -> Option<u8> {
+    match *event {
+        PCMAudioSequenceEvent::Expression { channel, .. }
+        | PCMAudioSequenceEvent::Pan { channel, .. }
+        | PCMAudioSequenceEvent::PitchBend { channel, .. }
+        | PCMAudioSequenceEvent::Volume { channel, .. } => Some(channel),
+        PCMAudioSequenceEvent::WaveMessage { .. } | PCMAudioSequenceEvent::Nop | PCMAudioSequenceEvent::Exclusive(_) => None,
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use alloc::vec;
+    use alloc::{vec, vec::Vec};
 
-    use super::{parse_pcm_audio_track_events, parse_sequence_events, SmafEvent, ToneMap};
+    use super::{
+        parse_pcm_audio_track_events, parse_sequence_events, parse_smaf, parse_smaf_with_options, AtmosphereConfig, EventSource, OutputProfile,
+        PartFilter, PlayerError, PlayerOptions, SmafEvent, ToneMap, TrackKind,
+    };
     use smaf::{
         BaseBit, Channel, ChannelStatus, ChannelType, PCMAudioSequenceData, PCMAudioSequenceEvent, PCMAudioTrack, PCMAudioTrackChunk, PcmWaveFormat,
-        ScoreTrackSequenceEvent, SequenceData,
+        SamplingRate, ScoreTrackSequenceEvent, SequenceData, SmafBuilder,
     };
 
     fn channel_status(channel_type: ChannelType) -> ChannelStatus {
@@ -974,7 +1209,7 @@ mod tests {
 
     #[test]
     fn maps_yamaha_ma_program_to_gm_fallback() {
-        let mut tone_map = ToneMap::new();
+        let mut tone_map = ToneMap::new(&PlayerOptions::default());
 
         tone_map.update_control(1, 0, 0x7c);
         tone_map.update_control(1, 32, 0x01);
@@ -984,7 +1219,7 @@ mod tests {
 
     #[test]
     fn maps_yamaha_rhythm_bank_to_midi_drum_channel() {
-        let mut tone_map = ToneMap::new();
+        let mut tone_map = ToneMap::new(&PlayerOptions::default());
 
         tone_map.update_control(9, 0, 0x7d);
         tone_map.update_control(9, 32, 0x00);
@@ -995,7 +1230,7 @@ mod tests {
 
     #[test]
     fn compacts_melody_channels_around_midi_drum_channel() {
-        let mut tone_map = ToneMap::new();
+        let mut tone_map = ToneMap::new(&PlayerOptions::default());
 
         assert_eq!(tone_map.real_channel(1), 0);
         assert_eq!(tone_map.real_channel(2), 1);
@@ -1009,7 +1244,7 @@ mod tests {
 
     #[test]
     fn emits_atmosphere_layers_for_yamaha_ma_ambience_voice() {
-        let mut tone_map = ToneMap::new();
+        let mut tone_map = ToneMap::new(&PlayerOptions::default());
 
         tone_map.update_control(7, 0, 0x7c);
         tone_map.update_control(7, 32, 0x01);
@@ -1052,7 +1287,7 @@ mod tests {
 
     #[test]
     fn reuses_previous_velocity_for_mobile_notes_without_velocity() {
-        let mut tone_map = ToneMap::new();
+        let mut tone_map = ToneMap::new(&PlayerOptions::default());
         tone_map.init_track(smaf::FormatType::MobileStandardNoCompress, &[], 0);
         let sequence = [
             SequenceData {
@@ -1075,15 +1310,25 @@ mod tests {
             },
         ];
 
-        let (events, _) = parse_sequence_events(&sequence, 1, 1, 0, false, &[], &mut tone_map);
+        let (events, _) = parse_sequence_events(
+            &sequence,
+            1,
+            1,
+            0,
+            false,
+            &[],
+            &mut tone_map,
+            EventSource::track(TrackKind::ScoreTrack, 0),
+            &PartFilter::default(),
+        );
         assert!(events
             .iter()
-            .any(|(_, event)| matches!(event, SmafEvent::MidiNoteOn { note: 62, velocity: 96, .. })));
+            .any(|(_, _, event)| matches!(event, SmafEvent::MidiNoteOn { note: 62, velocity: 96, .. })));
     }
 
     #[test]
     fn applies_sequence_duration_before_event() {
-        let mut tone_map = ToneMap::new();
+        let mut tone_map = ToneMap::new(&PlayerOptions::default());
         tone_map.init_track(smaf::FormatType::MobileStandardNoCompress, &[], 0);
         let sequence = [SequenceData {
             duration: 5,
@@ -1095,13 +1340,23 @@ mod tests {
             },
         }];
 
-        let (events, _) = parse_sequence_events(&sequence, 4, 4, 0, false, &[], &mut tone_map);
+        let (events, _) = parse_sequence_events(
+            &sequence,
+            4,
+            4,
+            0,
+            false,
+            &[],
+            &mut tone_map,
+            EventSource::track(TrackKind::ScoreTrack, 0),
+            &PartFilter::default(),
+        );
         assert!(events
             .iter()
-            .any(|(time, event)| *time == 20 && matches!(event, SmafEvent::MidiNoteOn { note: 60, .. })));
+            .any(|(time, _, event)| *time == 20 && matches!(event, SmafEvent::MidiNoteOn { note: 60, .. })));
         assert!(events
             .iter()
-            .any(|(time, event)| *time == 28 && matches!(event, SmafEvent::MidiNoteOff { note: 60, .. })));
+            .any(|(time, _, event)| *time == 28 && matches!(event, SmafEvent::MidiNoteOff { note: 60, .. })));
     }
 
     #[test]
@@ -1111,7 +1366,7 @@ mod tests {
             sequence_type: 0,
             channel: Channel::Mono,
             format: PcmWaveFormat::Adpcm,
-            sampling_freq: 8000,
+            sampling_freq: SamplingRate::Hz8000,
             base_bit: BaseBit::Bit4,
             timebase_d: 4,
             timebase_g: 4,
@@ -1121,13 +1376,13 @@ mod tests {
             }])],
         };
 
-        let events = parse_pcm_audio_track_events(&track);
-        assert!(events.iter().any(|(time, event)| *time == 20 && matches!(event, SmafEvent::End)));
+        let events = parse_pcm_audio_track_events(0, &track).unwrap();
+        assert!(events.iter().any(|(time, _, event)| *time == 20 && matches!(event, SmafEvent::End)));
     }
 
     #[test]
     fn hps_tracks_keep_independent_midi_channel_allocations() {
-        let mut tone_map = ToneMap::new();
+        let mut tone_map = ToneMap::new(&PlayerOptions::default());
         let first_track = [channel_status(ChannelType::Melody)];
         tone_map.init_track(smaf::FormatType::HandyPhoneStandard, &first_track, 0);
         let first_sequence = [SequenceData {
@@ -1135,10 +1390,20 @@ mod tests {
             event: ScoreTrackSequenceEvent::ProgramChange { channel: 0, program: 40 },
         }];
 
-        let (events, _) = parse_sequence_events(&first_sequence, 1, 1, 0, true, &[], &mut tone_map);
+        let (events, _) = parse_sequence_events(
+            &first_sequence,
+            1,
+            1,
+            0,
+            true,
+            &[],
+            &mut tone_map,
+            EventSource::track(TrackKind::ScoreTrack, 0),
+            &PartFilter::default(),
+        );
         assert!(events
             .iter()
-            .any(|(_, event)| matches!(event, SmafEvent::MidiProgramChange { channel: 0, program: 40 })));
+            .any(|(_, _, event)| matches!(event, SmafEvent::MidiProgramChange { channel: 0, program: 40 })));
 
         let second_track = [channel_status(ChannelType::Melody)];
         tone_map.init_track(smaf::FormatType::HandyPhoneStandard, &second_track, 4);
@@ -1147,15 +1412,25 @@ mod tests {
             event: ScoreTrackSequenceEvent::ProgramChange { channel: 0, program: 41 },
         }];
 
-        let (events, _) = parse_sequence_events(&second_sequence, 1, 1, 4, true, &[], &mut tone_map);
+        let (events, _) = parse_sequence_events(
+            &second_sequence,
+            1,
+            1,
+            4,
+            true,
+            &[],
+            &mut tone_map,
+            EventSource::track(TrackKind::ScoreTrack, 0),
+            &PartFilter::default(),
+        );
         assert!(events
             .iter()
-            .any(|(_, event)| matches!(event, SmafEvent::MidiProgramChange { channel: 1, program: 41 })));
+            .any(|(_, _, event)| matches!(event, SmafEvent::MidiProgramChange { channel: 1, program: 41 })));
     }
 
     #[test]
     fn hps_rhythm_uses_program_as_drum_key_and_expression_velocity() {
-        let mut tone_map = ToneMap::new();
+        let mut tone_map = ToneMap::new(&PlayerOptions::default());
         let statuses = [channel_status(ChannelType::Rhythm)];
         tone_map.init_track(smaf::FormatType::HandyPhoneStandard, &statuses, 0);
         let sequence = [
@@ -1178,11 +1453,21 @@ mod tests {
             },
         ];
 
-        let (events, _) = parse_sequence_events(&sequence, 1, 1, 0, true, &[], &mut tone_map);
+        let (events, _) = parse_sequence_events(
+            &sequence,
+            1,
+            1,
+            0,
+            true,
+            &[],
+            &mut tone_map,
+            EventSource::track(TrackKind::ScoreTrack, 0),
+            &PartFilter::default(),
+        );
         assert!(events
             .iter()
-            .any(|(_, event)| matches!(event, SmafEvent::MidiProgramChange { channel: 9, program: 0 })));
-        assert!(events.iter().any(|(_, event)| {
+            .any(|(_, _, event)| matches!(event, SmafEvent::MidiProgramChange { channel: 9, program: 0 })));
+        assert!(events.iter().any(|(_, _, event)| {
             matches!(
                 event,
                 SmafEvent::MidiNoteOn {
@@ -1194,12 +1479,12 @@ mod tests {
         }));
         assert!(!events
             .iter()
-            .any(|(_, event)| matches!(event, SmafEvent::MidiControlChange { channel: 9, control: 11, .. })));
+            .any(|(_, _, event)| matches!(event, SmafEvent::MidiControlChange { channel: 9, control: 11, .. })));
     }
 
     #[test]
     fn hps_melody_expression_is_folded_into_volume() {
-        let mut tone_map = ToneMap::new();
+        let mut tone_map = ToneMap::new(&PlayerOptions::default());
         let statuses = [channel_status(ChannelType::Melody)];
         tone_map.init_track(smaf::FormatType::HandyPhoneStandard, &statuses, 0);
         let sequence = [
@@ -1213,8 +1498,18 @@ mod tests {
             },
         ];
 
-        let (events, _) = parse_sequence_events(&sequence, 1, 1, 0, true, &[], &mut tone_map);
-        assert!(events.iter().any(|(_, event)| matches!(
+        let (events, _) = parse_sequence_events(
+            &sequence,
+            1,
+            1,
+            0,
+            true,
+            &[],
+            &mut tone_map,
+            EventSource::track(TrackKind::ScoreTrack, 0),
+            &PartFilter::default(),
+        );
+        assert!(events.iter().any(|(_, _, event)| matches!(
             event,
             SmafEvent::MidiControlChange {
                 channel: 0,
@@ -1224,20 +1519,459 @@ mod tests {
         )));
         assert!(!events
             .iter()
-            .any(|(_, event)| matches!(event, SmafEvent::MidiControlChange { channel: 0, control: 11, .. })));
+            .any(|(_, _, event)| matches!(event, SmafEvent::MidiControlChange { channel: 0, control: 11, .. })));
     }
 
     #[test]
     fn hps_melody_pitch_adds_base_offset_but_rhythm_does_not() {
-        let mut melody_map = ToneMap::new();
+        let mut melody_map = ToneMap::new(&PlayerOptions::default());
         let melody = [channel_status(ChannelType::Melody)];
         melody_map.init_track(smaf::FormatType::HandyPhoneStandard, &melody, 0);
         assert_eq!(melody_map.map_note(0, 24), 60);
 
-        let mut rhythm_map = ToneMap::new();
+        let mut rhythm_map = ToneMap::new(&PlayerOptions::default());
         let rhythm = [channel_status(ChannelType::Rhythm)];
         rhythm_map.init_track(smaf::FormatType::HandyPhoneStandard, &rhythm, 0);
         assert_eq!(rhythm_map.set_program(0, 38), (9, 0));
         assert_eq!(rhythm_map.map_note(0, 24), 38);
+        assert_eq!(rhythm_map.set_program(0, 0x12), (9, 0));
+        assert_eq!(rhythm_map.map_note(0, 24), 45);
+
+        let mut forced_map = ToneMap::new(&PlayerOptions::default());
+        forced_map.init_track(smaf::FormatType::HandyPhoneStandard, &melody, 0);
+        forced_map.update_bank_select(0, 0x80);
+        assert_eq!(forced_map.set_program(0, 38), (9, 0));
+        assert_eq!(forced_map.map_note(0, 24), 38);
+    }
+
+    #[test]
+    fn xg_profile_selects_variation_bank_instead_of_gm_fallback() {
+        let mut tone_map = ToneMap::new(&PlayerOptions {
+            profile: OutputProfile::Xg,
+            ..Default::default()
+        });
+        tone_map.init_track(smaf::FormatType::MobileStandardNoCompress, &[], 0);
+        let sequence = [
+            SequenceData {
+                duration: 0,
+                event: ScoreTrackSequenceEvent::ControlChange {
+                    channel: 1,
+                    control: 0,
+                    value: 0x7c,
+                },
+            },
+            SequenceData {
+                duration: 0,
+                event: ScoreTrackSequenceEvent::ControlChange {
+                    channel: 1,
+                    control: 32,
+                    value: 0x01,
+                },
+            },
+            SequenceData {
+                duration: 0,
+                event: ScoreTrackSequenceEvent::ProgramChange { channel: 1, program: 0x22 },
+            },
+        ];
+
+        let (events, _) = parse_sequence_events(
+            &sequence,
+            1,
+            1,
+            0,
+            false,
+            &[],
+            &mut tone_map,
+            EventSource::track(TrackKind::ScoreTrack, 0),
+            &PartFilter::default(),
+        );
+        assert!(events
+            .iter()
+            .any(|(_, _, event)| matches!(event, SmafEvent::MidiProgramChange { channel: 0, program: 0x22 })));
+        assert!(events.iter().any(|(_, _, event)| matches!(
+            event,
+            SmafEvent::MidiControlChange {
+                channel: 0,
+                control: 0,
+                value: 0
+            }
+        )));
+        assert!(events.iter().any(|(_, _, event)| matches!(
+            event,
+            SmafEvent::MidiControlChange {
+                channel: 0,
+                control: 32,
+                value: 1
+            }
+        )));
+        assert!(!events
+            .iter()
+            .any(|(_, _, event)| matches!(event, SmafEvent::MidiControlChange { control: 0, value: 0x7c, .. })));
+    }
+
+    #[test]
+    fn gm2_profile_selects_drum_bank_on_rhythm_channel() {
+        let mut tone_map = ToneMap::new(&PlayerOptions {
+            profile: OutputProfile::GeneralMidi2,
+            ..Default::default()
+        });
+        tone_map.init_track(smaf::FormatType::MobileStandardNoCompress, &[], 0);
+        let sequence = [
+            SequenceData {
+                duration: 0,
+                event: ScoreTrackSequenceEvent::ControlChange {
+                    channel: 9,
+                    control: 0,
+                    value: 0x7d,
+                },
+            },
+            SequenceData {
+                duration: 0,
+                event: ScoreTrackSequenceEvent::ProgramChange { channel: 9, program: 0x02 },
+            },
+        ];
+
+        let (events, _) = parse_sequence_events(
+            &sequence,
+            1,
+            1,
+            0,
+            false,
+            &[],
+            &mut tone_map,
+            EventSource::track(TrackKind::ScoreTrack, 0),
+            &PartFilter::default(),
+        );
+        assert!(events.iter().any(|(_, _, event)| matches!(
+            event,
+            SmafEvent::MidiControlChange {
+                channel: 9,
+                control: 0,
+                value: 0x78
+            }
+        )));
+        assert!(events
+            .iter()
+            .any(|(_, _, event)| matches!(event, SmafEvent::MidiProgramChange { channel: 9, program: 0 })));
+    }
+
+    #[test]
+    fn gm_profile_resets_target_and_drops_yamaha_ma_exclusive() {
+        let data = include_bytes!("../../test_data/midi.mmf");
+        let events = parse_smaf_with_options(
+            data,
+            &PlayerOptions {
+                profile: OutputProfile::GeneralMidi,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        assert!(matches!(&events[0], (0, _, SmafEvent::MidiSysEx(data)) if data == &[0xf0, 0x7e, 0x7f, 0x09, 0x01, 0xf7]));
+        assert!(!events
+            .iter()
+            .any(|(_, _, event)| matches!(event, SmafEvent::MidiSysEx(data) if data.starts_with(&[0xf0, 0x43, 0x79]))));
+    }
+
+    #[test]
+    fn disabled_atmosphere_leaves_ambience_voice_alone() {
+        let mut tone_map = ToneMap::new(&PlayerOptions {
+            atmosphere: AtmosphereConfig::disabled(),
+            ..Default::default()
+        });
+
+        tone_map.update_control(7, 0, 0x7c);
+        tone_map.update_control(7, 32, 0x01);
+
+        assert!(tone_map.emit_atmosphere_setup(0, 7, 0x62).is_empty());
+        assert!(tone_map.emit_atmosphere_notes(100, 200, 7, 84, 64).is_empty());
+        assert_eq!(tone_map.note_duration(7, 200), 200);
+    }
+
+    #[test]
+    fn atmosphere_layers_use_configured_channels() {
+        let mut atmosphere = AtmosphereConfig::default();
+        atmosphere.layers.truncate(1);
+        atmosphere.layers[0].channel = 12;
+        let mut tone_map = ToneMap::new(&PlayerOptions {
+            atmosphere,
+            ..Default::default()
+        });
+
+        tone_map.update_control(7, 0, 0x7c);
+        tone_map.update_control(7, 32, 0x01);
+
+        let setup = tone_map.emit_atmosphere_setup(0, 7, 0x62);
+        assert!(setup
+            .iter()
+            .any(|(_, event)| matches!(event, SmafEvent::MidiProgramChange { channel: 12, program: 99 })));
+        assert!(!setup
+            .iter()
+            .any(|(_, event)| matches!(event, SmafEvent::MidiProgramChange { channel: 14 | 15, .. })));
+        assert_eq!(tone_map.real_channel(8), 1);
+        assert_ne!(tone_map.real_channel(13), 12);
+    }
+
+    #[test]
+    fn hps_channels_beyond_midi_range_get_virtual_channels() {
+        let mut tone_map = ToneMap::new(&PlayerOptions::default());
+        let statuses = [
+            channel_status(ChannelType::Melody),
+            channel_status(ChannelType::Melody),
+            channel_status(ChannelType::Melody),
+            channel_status(ChannelType::Melody),
+        ];
+        for track in 0..5 {
+            tone_map.init_track(smaf::FormatType::HandyPhoneStandard, &statuses, track * 4);
+        }
+
+        let channels = (0..20).map(|channel| tone_map.real_channel(channel)).collect::<vec::Vec<_>>();
+        assert!(channels[..15].iter().all(|&channel| channel < 16 && channel != 9));
+        assert_eq!(&channels[15..], &[16, 17, 18, 19, 20]);
+    }
+
+    #[test]
+    fn reports_parse_error_for_invalid_file() {
+        assert!(matches!(parse_smaf(b"MMMD"), Err(PlayerError::SmafError(_))));
+    }
+
+    #[test]
+    fn reports_unsupported_pcm_track_instead_of_panicking() {
+        let mut track = PCMAudioTrack {
+            format_type: 0,
+            sequence_type: 0,
+            channel: Channel::Mono,
+            format: PcmWaveFormat::TwinVQ,
+            sampling_freq: SamplingRate::Hz8000,
+            base_bit: BaseBit::Bit4,
+            timebase_d: 4,
+            timebase_g: 4,
+            chunks: vec![],
+        };
+        assert!(matches!(parse_pcm_audio_track_events(0, &track), Err(PlayerError::MissingSequenceData)));
+
+        track.chunks.push(PCMAudioTrackChunk::SequenceData(vec![PCMAudioSequenceData {
+            duration: 0,
+            event: PCMAudioSequenceEvent::WaveMessage {
+                channel: 0,
+                wave_number: 1,
+                gate_time: 1,
+            },
+        }]));
+        assert!(matches!(parse_pcm_audio_track_events(0, &track), Err(PlayerError::MissingWaveData(1))));
+
+        track.chunks.push(PCMAudioTrackChunk::WaveData(1, &[0x00]));
+        assert!(matches!(parse_pcm_audio_track_events(0, &track), Err(PlayerError::Unsupported(_))));
+    }
+
+    #[test]
+    fn decodes_stereo_pcm_track() {
+        let track = PCMAudioTrack {
+            format_type: 0,
+            sequence_type: 0,
+            channel: Channel::Stereo,
+            format: PcmWaveFormat::Adpcm,
+            sampling_freq: SamplingRate::Hz8000,
+            base_bit: BaseBit::Bit4,
+            timebase_d: 4,
+            timebase_g: 4,
+            chunks: vec![
+                PCMAudioTrackChunk::SequenceData(vec![PCMAudioSequenceData {
+                    duration: 0,
+                    event: PCMAudioSequenceEvent::WaveMessage {
+                        channel: 0,
+                        wave_number: 1,
+                        gate_time: 1,
+                    },
+                }]),
+                PCMAudioTrackChunk::WaveData(1, &[0x70, 0x70]),
+            ],
+        };
+
+        let events = parse_pcm_audio_track_events(0, &track).unwrap();
+        assert!(matches!(&events[0].2, SmafEvent::Wave { channel: 2, data, .. } if data.len() == 4));
+    }
+
+    #[test]
+    fn applies_pcm_channel_controls_to_waves() {
+        let file = SmafBuilder::new()
+            .pcm_track(PcmWaveFormat::TwosComplementPCM, SamplingRate::Hz4000, BaseBit::Bit8, 1, |track| {
+                track
+                    .wave_data(1, &[0x40; 8])
+                    .volume(1, 0x40)
+                    .pan(1, 0x7f)
+                    .wave(0, 1, 0)
+                    .wave(1, 1, 0)
+                    .wait(1)
+                    .expression(1, 0)
+            })
+            .build()
+            .unwrap();
+
+        let waves = parse_smaf(&file)
+            .unwrap()
+            .into_iter()
+            .filter_map(|(_, _, event)| match event {
+                SmafEvent::Wave { channel, data, .. } => Some((channel, data)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        // channel 0 is untouched, channel 1 is halved, panned right and silenced after 1 ms
+        assert_eq!(waves[0], (1, vec![0x4000; 8]));
+        assert_eq!(
+            waves[1],
+            (2, [[0, (0x4000 * 0x40 / 0x7f) as i16]; 4].concat().into_iter().chain([0; 8]).collect())
+        );
+    }
+
+    #[test]
+    fn cuts_pcm_waves_at_their_gate_time() {
+        let file = SmafBuilder::new()
+            .pcm_track(PcmWaveFormat::TwosComplementPCM, SamplingRate::Hz4000, BaseBit::Bit8, 2, |track| {
+                track.wave_data(1, &[0x40; 400]).wave(0, 1, 5).wait(10).wave(0, 1, 0)
+            })
+            .build()
+            .unwrap();
+
+        let events = parse_smaf(&file)
+            .unwrap()
+            .into_iter()
+            .filter_map(|(time, source, event)| match event {
+                SmafEvent::Wave { data, .. } => Some((time, source.event_index, Some(data.len()))),
+                SmafEvent::WaveStop => Some((time, source.event_index, None)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        // 5 ticks of 2 ms, the ungated wave plays to its end
+        assert_eq!(events, [(0, Some(0), Some(40)), (10, Some(0), None), (20, Some(1), Some(400))]);
+    }
+
+    #[test]
+    fn attributes_events_to_source_track_channel_and_event() {
+        let mut tone_map = ToneMap::new(&PlayerOptions::default());
+        tone_map.init_track(smaf::FormatType::HandyPhoneStandard, &[channel_status(ChannelType::Melody)], 4);
+        let sequence = [
+            SequenceData {
+                duration: 0,
+                event: ScoreTrackSequenceEvent::ProgramChange { channel: 0, program: 40 },
+            },
+            SequenceData {
+                duration: 1,
+                event: ScoreTrackSequenceEvent::NoteMessage {
+                    channel: 0,
+                    note: 60,
+                    velocity: None,
+                    gate_time: 2,
+                },
+            },
+        ];
+
+        let (events, _) = parse_sequence_events(
+            &sequence,
+            1,
+            1,
+            4,
+            true,
+            &[],
+            &mut tone_map,
+            EventSource::track(TrackKind::ScoreTrack, 2),
+            &PartFilter::default(),
+        );
+        let note_on = events
+            .iter()
+            .find_map(|(_, source, event)| matches!(event, SmafEvent::MidiNoteOn { .. }).then_some(*source))
+            .unwrap();
+        assert_eq!(
+            note_on,
+            EventSource {
+                kind: TrackKind::ScoreTrack,
+                track: 2,
+                channel: Some(4),
+                event_index: Some(1),
+            }
+        );
+        assert!(events
+            .iter()
+            .any(|(_, source, event)| matches!(event, SmafEvent::MidiProgramChange { .. }) && source.event_index == Some(0)));
+        assert!(matches!(events.last(), Some((_, EventSource { event_index: None, .. }, SmafEvent::End))));
+    }
+
+    #[test]
+    fn attributes_file_events_to_their_chunks() {
+        let events = parse_smaf(include_bytes!("../../test_data/midi.mmf")).unwrap();
+        assert!(events
+            .iter()
+            .filter(|(_, _, event)| matches!(event, SmafEvent::MidiNoteOn { .. }))
+            .all(|(_, source, _)| source.kind == TrackKind::ScoreTrack && source.channel.is_some() && source.event_index.is_some()));
+
+        let events = parse_smaf(include_bytes!("../../test_data/wave.mmf")).unwrap();
+        assert!(events
+            .iter()
+            .filter(|(_, _, event)| matches!(event, SmafEvent::Wave { .. }))
+            .all(|(_, source, _)| source.kind == TrackKind::PCMAudioTrack));
+    }
+
+    #[test]
+    fn muted_channels_do_not_claim_midi_channels() {
+        let mut tone_map = ToneMap::new(&PlayerOptions::default());
+        tone_map.init_track(smaf::FormatType::MobileStandardNoCompress, &[], 0);
+        let note = |channel| SequenceData {
+            duration: 0,
+            event: ScoreTrackSequenceEvent::NoteMessage {
+                channel,
+                note: 60,
+                velocity: Some(100),
+                gate_time: 10,
+            },
+        };
+        let parts = PartFilter {
+            solo_channels: vec![1, 2],
+            muted_channels: vec![2],
+            ..Default::default()
+        };
+
+        let (events, _) = parse_sequence_events(
+            &[note(0), note(1), note(2)],
+            1,
+            1,
+            0,
+            false,
+            &[],
+            &mut tone_map,
+            EventSource::track(TrackKind::ScoreTrack, 0),
+            &parts,
+        );
+        let played = events
+            .iter()
+            .filter_map(|(_, source, event)| match event {
+                SmafEvent::MidiNoteOn { channel, .. } => Some((source.channel, *channel)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(played, [(Some(1), 0)]);
+    }
+
+    #[test]
+    fn muted_tracks_are_skipped() {
+        let data = include_bytes!("../../test_data/midi.mmf");
+        let all = parse_smaf(data).unwrap();
+        let track = all.iter().find(|(_, source, _)| source.kind == TrackKind::ScoreTrack).unwrap().1.track;
+
+        let events = parse_smaf_with_options(
+            data,
+            &PlayerOptions {
+                parts: PartFilter {
+                    muted_tracks: vec![(TrackKind::ScoreTrack, track)],
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(!events
+            .iter()
+            .any(|(_, source, _)| source.kind == TrackKind::ScoreTrack && source.track == track));
     }
 }
@@ -0,0 +1,466 @@
+// standard midi file (type 1) writer for player event streams, and a reader producing the same kind of stream
+
+use alloc::{format, string::String, vec::Vec};
+
+use crate::{EventSource, PlayerError, Result, SmafEvent, TrackKind};
+
+// 500 ticks per quarter note at 120 bpm makes one tick a millisecond, so event times are kept exactly
+const TICKS_PER_QUARTER: u16 = 500;
+const TEMPO: u32 = 500000; // microseconds per quarter note
+
+type TrackKey = (TrackKind, u8, Option<u8>);
+
+// the first track carries the tempo and player level messages, then one track per smaf track and logical channel
+pub fn write_midi(events: &[(usize, EventSource, SmafEvent)]) -> Vec<u8> {
+    let end = events.iter().map(|(time, _, _)| *time).max().unwrap_or(0);
+
+    let mut conductor = tempo_track();
+    let mut conductor_now = 0;
+    let mut tracks: Vec<(TrackKey, usize, Vec<u8>)> = Vec::new();
+
+    for (time, source, event) in events {
+        let Some(message) = event_message(event) else {
+            continue;
+        };
+
+        let (now, data) = if source.kind == TrackKind::Player {
+            (&mut conductor_now, &mut conductor)
+        } else {
+            let key = (source.kind, source.track, source.channel);
+            let index = match tracks.iter().position(|(x, _, _)| *x == key) {
+                Some(index) => index,
+                None => {
+                    tracks.push((key, 0, track_name(source)));
+                    tracks.len() - 1
+                }
+            };
+            let (_, now, data) = &mut tracks[index];
+            (now, data)
+        };
+
+        write_variable_length(data, (time - *now) as u32);
+        data.extend_from_slice(&message);
+        *now = *time;
+    }
+
+    let mut result = Vec::new();
+    result.extend_from_slice(b"MThd");
+    result.extend_from_slice(&6u32.to_be_bytes());
+    result.extend_from_slice(&1u16.to_be_bytes());
+    result.extend_from_slice(&(tracks.len() as u16 + 1).to_be_bytes());
+    result.extend_from_slice(&TICKS_PER_QUARTER.to_be_bytes());
+
+    write_track(&mut result, conductor, end - conductor_now);
+    for (_, now, data) in tracks {
+        write_track(&mut result, data, end - now);
+    }
+
+    result
+}
+
+fn tempo_track() -> Vec<u8> {
+    let mut result = Vec::from([0x00, 0xff, 0x51, 0x03]);
+    result.extend_from_slice(&TEMPO.to_be_bytes()[1..]);
+
+    result
+}
+
+fn track_name(source: &EventSource) -> Vec<u8> {
+    let name = match (source.kind, source.channel) {
+        (TrackKind::PCMAudioTrack, _) => format!("PCM track {}", source.track),
+        (TrackKind::SoftbankSequenceData, Some(channel)) => format!("Softbank channel {}", channel),
+        (TrackKind::SoftbankSequenceData, None) => String::from("Softbank"),
+        (_, Some(channel)) => format!("Score track {} channel {}", source.track, channel),
+        (_, None) => format!("Score track {}", source.track),
+    };
+
+    let mut result = Vec::from([0x00, 0xff, 0x03]);
+    write_variable_length(&mut result, name.len() as u32);
+    result.extend_from_slice(name.as_bytes());
+
+    result
+}
+
+// waves can't be stored in a midi file, end of track is written separately
+fn event_message(event: &SmafEvent) -> Option<Vec<u8>> {
+    Some(match event {
+        SmafEvent::MidiNoteOn { channel, note, velocity } => Vec::from([0x90 | channel, *note, *velocity]),
+        SmafEvent::MidiNoteOff { channel, note, velocity } => Vec::from([0x80 | channel, *note, *velocity]),
+        SmafEvent::MidiProgramChange { channel, program } => Vec::from([0xc0 | channel, *program]),
+        SmafEvent::MidiControlChange { channel, control, value } => Vec::from([0xb0 | channel, *control, *value]),
+        SmafEvent::MidiPitchBend { channel, value } => Vec::from([0xe0 | channel, (value & 0x7f) as u8, ((value >> 7) & 0x7f) as u8]),
+        SmafEvent::MidiSysEx(data) => {
+            // f0 <length> <data including f7>
+            let data = data.strip_prefix(&[0xf0]).unwrap_or(data);
+            let mut result = Vec::from([0xf0]);
+            write_variable_length(&mut result, data.len() as u32);
+            result.extend_from_slice(data);
+            result
+        }
+        SmafEvent::Wave { .. } | SmafEvent::WaveStop | SmafEvent::NoteDropped { .. } | SmafEvent::End => return None,
+    })
+}
+
+fn write_track(result: &mut Vec<u8>, mut data: Vec<u8>, end_delta: usize) {
+    write_variable_length(&mut data, end_delta as u32);
+    data.extend_from_slice(&[0xff, 0x2f, 0x00]);
+
+    result.extend_from_slice(b"MTrk");
+    result.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    result.extend_from_slice(&data);
+}
+
+fn write_variable_length(result: &mut Vec<u8>, value: u32) {
+    let mut shift = 28;
+    while shift > 0 && (value >> shift) == 0 {
+        shift -= 7;
+    }
+    while shift > 0 {
+        result.push(0x80 | ((value >> shift) & 0x7f) as u8);
+        shift -= 7;
+    }
+    result.push((value & 0x7f) as u8);
+}
+
+enum MidiItem {
+    Tempo(u32),
+    Event(SmafEvent),
+}
+
+// reads format 0 and 1 files into events timed in ms, note on with velocity 0 becomes a note off
+pub fn read_midi(raw: &[u8]) -> Result<Vec<(usize, SmafEvent)>> {
+    let invalid = |message: &str| PlayerError::InvalidMidi(String::from(message));
+
+    let mut reader = Reader { data: raw, offset: 0 };
+    if reader.take(4)? != b"MThd" {
+        return Err(invalid("missing MThd"));
+    }
+    let header_length = reader.u32()? as usize;
+    let header = reader.take(header_length)?;
+    if header.len() < 6 {
+        return Err(invalid("short header"));
+    }
+    let format = u16::from_be_bytes([header[0], header[1]]);
+    let track_count = u16::from_be_bytes([header[2], header[3]]);
+    let division = u16::from_be_bytes([header[4], header[5]]);
+    if format > 1 {
+        return Err(PlayerError::Unsupported(format!("midi format {format}")));
+    }
+    if division & 0x8000 != 0 || division == 0 {
+        return Err(PlayerError::Unsupported(String::from("smpte time division")));
+    }
+
+    let mut items = Vec::new();
+    for _ in 0..track_count {
+        if reader.offset >= raw.len() {
+            break;
+        }
+        let tag = reader.take(4)?;
+        let length = reader.u32()? as usize;
+        let data = reader.take(length)?;
+        if tag == b"MTrk" {
+            read_track(data, &mut items)?;
+        }
+    }
+
+    // stable, so events of the same tick keep their track order
+    items.sort_by_key(|(tick, _)| *tick);
+
+    let mut result = Vec::with_capacity(items.len());
+    let mut tempo = TEMPO as u64;
+    let mut last_tick = 0;
+    let mut microseconds = 0u64;
+    for (tick, item) in items {
+        microseconds += (tick - last_tick) * tempo / division as u64;
+        last_tick = tick;
+
+        match item {
+            MidiItem::Tempo(x) => tempo = x as u64,
+            MidiItem::Event(event) => result.push(((microseconds + 500) as usize / 1000, event)),
+        }
+    }
+
+    Ok(result)
+}
+
+fn read_track(data: &[u8], items: &mut Vec<(u64, MidiItem)>) -> Result<()> {
+    let mut reader = Reader { data, offset: 0 };
+    let mut tick = 0u64;
+    let mut running_status = 0;
+
+    while reader.offset < data.len() {
+        tick += reader.variable_length()? as u64;
+
+        let mut status = reader.u8()?;
+        if status < 0x80 {
+            // running status, the byte was the first data byte
+            if running_status == 0 {
+                return Err(PlayerError::InvalidMidi(String::from("data byte without status")));
+            }
+            reader.offset -= 1;
+            status = running_status;
+        }
+
+        let channel = status & 0x0f;
+        let event = match status & 0xf0 {
+            0x80 | 0x90 | 0xa0 | 0xb0 | 0xe0 => {
+                running_status = status;
+                let (first, second) = (reader.u8()? & 0x7f, reader.u8()? & 0x7f);
+                match status & 0xf0 {
+                    0x90 if second != 0 => SmafEvent::MidiNoteOn {
+                        channel,
+                        note: first,
+                        velocity: second,
+                    },
+                    0x80 | 0x90 => SmafEvent::MidiNoteOff {
+                        channel,
+                        note: first,
+                        velocity: second,
+                    },
+                    0xb0 => SmafEvent::MidiControlChange {
+                        channel,
+                        control: first,
+                        value: second,
+                    },
+                    0xe0 => SmafEvent::MidiPitchBend {
+                        channel,
+                        value: ((second as u16) << 7) | first as u16,
+                    },
+                    _ => continue, // polyphonic aftertouch
+                }
+            }
+            0xc0 | 0xd0 => {
+                running_status = status;
+                let value = reader.u8()? & 0x7f;
+                if status & 0xf0 == 0xd0 {
+                    continue; // channel aftertouch
+                }
+                SmafEvent::MidiProgramChange { channel, program: value }
+            }
+            _ => {
+                running_status = 0;
+                match status {
+                    0xf0 => {
+                        let length = reader.variable_length()? as usize;
+                        let mut message = Vec::from([0xf0]);
+                        message.extend_from_slice(reader.take(length)?);
+                        SmafEvent::MidiSysEx(message)
+                    }
+                    0xf7 => {
+                        // escaped data, no complete message to keep
+                        let length = reader.variable_length()? as usize;
+                        reader.take(length)?;
+                        continue;
+                    }
+                    0xff => {
+                        let kind = reader.u8()?;
+                        let length = reader.variable_length()? as usize;
+                        let data = reader.take(length)?;
+                        match (kind, data) {
+                            (0x2f, _) => break,
+                            (0x51, &[a, b, c]) => items.push((tick, MidiItem::Tempo(u32::from_be_bytes([0, a, b, c])))),
+                            _ => {}
+                        }
+                        continue;
+                    }
+                    _ => return Err(PlayerError::InvalidMidi(format!("unknown status {status:#x}"))),
+                }
+            }
+        };
+        items.push((tick, MidiItem::Event(event)));
+    }
+
+    Ok(())
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8]> {
+        let data = self
+            .data
+            .get(self.offset..self.offset + length)
+            .ok_or_else(|| PlayerError::InvalidMidi(String::from("unexpected end of data")))?;
+        self.offset += length;
+
+        Ok(data)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let data = self.take(4)?;
+        Ok(u32::from_be_bytes([data[0], data[1], data[2], data[3]]))
+    }
+
+    fn variable_length(&mut self) -> Result<u32> {
+        let mut result = 0u32;
+        for _ in 0..4 {
+            let byte = self.u8()?;
+            result = (result << 7) | (byte & 0x7f) as u32;
+            if byte & 0x80 == 0 {
+                return Ok(result);
+            }
+        }
+
+        Err(PlayerError::InvalidMidi(String::from("variable length quantity too long")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::{read_midi, write_midi, write_variable_length};
+    use crate::{parse_smaf, EventSource, SmafEvent, TrackKind};
+
+    #[test]
+    fn writes_variable_length_quantities() {
+        let encode = |value| {
+            let mut result = Vec::new();
+            write_variable_length(&mut result, value);
+            result
+        };
+
+        assert_eq!(encode(0), [0x00]);
+        assert_eq!(encode(0x7f), [0x7f]);
+        assert_eq!(encode(0x80), [0x81, 0x00]);
+        assert_eq!(encode(0x3fff), [0xff, 0x7f]);
+        assert_eq!(encode(0x0fffffff), [0xff, 0xff, 0xff, 0x7f]);
+    }
+
+    #[test]
+    fn writes_one_track_per_channel_in_milliseconds() {
+        let source = |channel| EventSource {
+            kind: TrackKind::ScoreTrack,
+            track: 0,
+            channel: Some(channel),
+            event_index: None,
+        };
+        let events = [
+            (
+                0,
+                EventSource::track(TrackKind::Player, 0),
+                SmafEvent::MidiSysEx(Vec::from([0xf0, 0x7e, 0xf7])),
+            ),
+            (
+                0,
+                source(1),
+                SmafEvent::MidiNoteOn {
+                    channel: 3,
+                    note: 60,
+                    velocity: 100,
+                },
+            ),
+            (200, source(2), SmafEvent::MidiProgramChange { channel: 4, program: 5 }),
+            (
+                300,
+                source(1),
+                SmafEvent::MidiNoteOff {
+                    channel: 3,
+                    note: 60,
+                    velocity: 0,
+                },
+            ),
+            (400, EventSource::track(TrackKind::ScoreTrack, 0), SmafEvent::End),
+        ];
+
+        let midi = write_midi(&events);
+        assert_eq!(&midi[..14], &[b'M', b'T', b'h', b'd', 0, 0, 0, 6, 0, 1, 0, 3, 0x01, 0xf4]);
+
+        // tempo, the sysex and end of track 400 ms later
+        assert_eq!(
+            &midi[14..39],
+            &[
+                b'M', b'T', b'r', b'k', 0, 0, 0, 17, 0x00, 0xff, 0x51, 0x03, 0x07, 0xa1, 0x20, 0x00, 0xf0, 0x02, 0x7e, 0xf7, 0x83, 0x10, 0xff, 0x2f,
+                0x00
+            ]
+        );
+
+        let name = b"Score track 0 channel 1";
+        let track = &midi[39..];
+        assert_eq!(&track[..4], b"MTrk");
+        assert_eq!(&track[8..11], &[0x00, 0xff, 0x03]);
+        assert_eq!(&track[12..12 + name.len()], name);
+        assert_eq!(
+            &track[12 + name.len()..23 + name.len()],
+            &[0x00, 0x93, 60, 100, 0x82, 0x2c, 0x83, 60, 0, 0x64, 0xff]
+        );
+    }
+
+    #[test]
+    fn writes_file() {
+        let events = parse_smaf(include_bytes!("../../test_data/midi.mmf")).unwrap();
+        let midi = write_midi(&events);
+
+        let track_count = u16::from_be_bytes([midi[10], midi[11]]) as usize;
+        let mut offset = 14;
+        for _ in 0..track_count {
+            assert_eq!(&midi[offset..offset + 4], b"MTrk");
+            let length = u32::from_be_bytes(midi[offset + 4..offset + 8].try_into().unwrap()) as usize;
+            assert_eq!(&midi[offset + 8 + length - 3..offset + 8 + length], &[0xff, 0x2f, 0x00]);
+            offset += 8 + length;
+        }
+        assert_eq!(offset, midi.len());
+        assert!(track_count > 2);
+    }
+
+    #[test]
+    fn reads_running_status_and_tempo_changes() {
+        let mut midi = Vec::from(*b"MThd\x00\x00\x00\x06\x00\x00\x00\x01\x00\x60");
+        let track = [
+            0x00, 0x90, 60, 100, // note on
+            0x60, 60, 0, // running status note on with velocity 0, a quarter note later
+            0x00, 0xff, 0x51, 0x03, 0x0f, 0x42, 0x40, // 60 bpm
+            0x60, 0xc1, 5, // program change a second later
+            0x00, 0xd1, 10, // channel aftertouch is dropped
+            0x00, 0xf0, 0x03, 0x7e, 0x7f, 0xf7, // sysex
+            0x00, 0xff, 0x2f, 0x00,
+        ];
+        midi.extend_from_slice(b"MTrk");
+        midi.extend_from_slice(&(track.len() as u32).to_be_bytes());
+        midi.extend_from_slice(&track);
+
+        let events = read_midi(&midi).unwrap();
+        assert_eq!(events.len(), 4);
+        assert!(matches!(
+            events[0],
+            (
+                0,
+                SmafEvent::MidiNoteOn {
+                    channel: 0,
+                    note: 60,
+                    velocity: 100
+                }
+            )
+        ));
+        assert!(matches!(events[1], (500, SmafEvent::MidiNoteOff { channel: 0, note: 60, .. })));
+        assert!(matches!(events[2], (1500, SmafEvent::MidiProgramChange { channel: 1, program: 5 })));
+        assert!(matches!(&events[3], (1500, SmafEvent::MidiSysEx(x)) if x == &[0xf0, 0x7e, 0x7f, 0xf7]));
+    }
+
+    #[test]
+    fn reads_written_file() {
+        let events = parse_smaf(include_bytes!("../../test_data/midi.mmf")).unwrap();
+        let read = read_midi(&write_midi(&events)).unwrap();
+
+        let notes = |events: &mut dyn Iterator<Item = (usize, &SmafEvent)>| {
+            events
+                .filter_map(|(time, event)| match event {
+                    SmafEvent::MidiNoteOn { channel, note, velocity } => Some((time, *channel, *note, *velocity)),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        let mut expected = notes(&mut events.iter().map(|(time, _, event)| (*time, event)));
+        let mut actual = notes(&mut read.iter().map(|(time, event)| (*time, event)));
+        expected.sort();
+        actual.sort();
+        assert_eq!(actual, expected);
+    }
+}
@@ -0,0 +1,133 @@
+// mp3 payloads of pcm audio tracks. extraction works everywhere, decoding needs the mp3 feature which brings in std.
+
+use alloc::vec::Vec;
+
+use smaf::{PCMAudioTrackChunk, PcmWaveFormat, Smaf, SmafChunk};
+
+use crate::Result;
+
+// (track number, wave number, mp3 stream) of every mp3 wave, as stored in the file
+pub fn extract_mp3(file: &[u8]) -> Result<Vec<(u8, u8, Vec<u8>)>> {
+    let smaf = Smaf::parse(file)?;
+
+    let mut result = Vec::new();
+    for chunk in &smaf.chunks {
+        let SmafChunk::PCMAudioTrack(track_number, track) = chunk else {
+            continue;
+        };
+        if track.format != PcmWaveFormat::MP3 {
+            continue;
+        }
+
+        for chunk in &track.chunks {
+            if let PCMAudioTrackChunk::WaveData(wave_number, data) = chunk {
+                result.push((*track_number, *wave_number, data.to_vec()));
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+// (channels, sampling rate, interleaved samples)
+#[cfg(feature = "mp3")]
+pub fn decode_mp3(data: &[u8]) -> Result<(u8, u32, Vec<i16>)> {
+    use alloc::{boxed::Box, format, string::String};
+    use std::io::{Cursor, ErrorKind};
+
+    use symphonia::core::{
+        audio::SampleBuffer, codecs::DecoderOptions, errors::Error, formats::FormatOptions, io::MediaSourceStream, meta::MetadataOptions, probe::Hint,
+    };
+
+    use crate::PlayerError;
+
+    let invalid = |e: Error| PlayerError::InvalidMp3(format!("{e}"));
+
+    let source = MediaSourceStream::new(Box::new(Cursor::new(data.to_vec())), Default::default());
+    let mut hint = Hint::new();
+    hint.with_extension("mp3");
+    let mut format = symphonia::default::get_probe()
+        .format(&hint, source, &FormatOptions::default(), &MetadataOptions::default())
+        .map_err(invalid)?
+        .format;
+    let track = format
+        .default_track()
+        .ok_or_else(|| PlayerError::InvalidMp3(String::from("no audio track")))?;
+    let track_id = track.id;
+    let mut decoder = symphonia::default::get_codecs()
+        .make(&track.codec_params, &DecoderOptions::default())
+        .map_err(invalid)?;
+
+    let (mut channels, mut sampling_rate) = (1, 0);
+    let mut samples = Vec::new();
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(Error::IoError(e)) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(invalid(e)),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            Err(Error::DecodeError(_)) => continue, // skip corrupt frames
+            Err(e) => return Err(invalid(e)),
+        };
+        let spec = *decoded.spec();
+        channels = spec.channels.count() as u8;
+        sampling_rate = spec.rate;
+
+        let mut buffer = SampleBuffer::<i16>::new(decoded.capacity() as u64, spec);
+        buffer.copy_interleaved_ref(decoded);
+        samples.extend_from_slice(buffer.samples());
+    }
+
+    Ok((channels, sampling_rate, samples))
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use smaf::{BaseBit, PcmWaveFormat, SamplingRate, SmafBuilder};
+
+    use super::extract_mp3;
+
+    // mpeg 1 layer 3, 128 kbps, 44100 hz, stereo. zero side info decodes to silence
+    fn silent_frames(count: usize) -> Vec<u8> {
+        let mut frame = Vec::from([0xff, 0xfb, 0x90, 0x00]);
+        frame.resize(417, 0);
+
+        frame.repeat(count)
+    }
+
+    #[test]
+    fn extracts_mp3_untouched() {
+        let mp3 = silent_frames(2);
+        let file = SmafBuilder::new()
+            .pcm_track(PcmWaveFormat::MP3, SamplingRate::Hz44100, BaseBit::Bit16, 1, |track| {
+                track.wave_data(1, &mp3).wave(0, 1, 0)
+            })
+            .pcm_track(PcmWaveFormat::Adpcm, SamplingRate::Hz8000, BaseBit::Bit4, 1, |track| {
+                track.wave_data(1, &[0x00]).wave(0, 1, 0)
+            })
+            .build()
+            .unwrap();
+
+        assert_eq!(extract_mp3(&file).unwrap(), [(0, 1, mp3)]);
+    }
+
+    #[cfg(feature = "mp3")]
+    #[test]
+    fn decodes_mp3() {
+        let (channels, sampling_rate, samples) = super::decode_mp3(&silent_frames(4)).unwrap();
+
+        assert_eq!((channels, sampling_rate), (2, 44100));
+        assert!(!samples.is_empty());
+        assert!(samples.iter().all(|&x| x == 0));
+
+        assert!(matches!(super::decode_mp3(&[0x00; 64]), Err(crate::PlayerError::InvalidMp3(_))));
+    }
+}
@@ -0,0 +1,38 @@
+use alloc::vec::Vec;
+
+use crate::{EventSource, TrackKind};
+
+// mute/solo selection of tracks and logical channels.
+// once anything is soloed only soloed parts play, and a mute always wins over a solo.
+#[derive(Clone, Debug, Default)]
+pub struct PartFilter {
+    pub muted_tracks: Vec<(TrackKind, u8)>,
+    pub solo_tracks: Vec<(TrackKind, u8)>,
+    pub muted_channels: Vec<u8>, // logical channels of score tracks, hps channels are numbered across tracks
+    pub solo_channels: Vec<u8>,
+}
+
+impl PartFilter {
+    pub(crate) fn plays_track(&self, kind: TrackKind, track: u8) -> bool {
+        if kind == TrackKind::Player {
+            return true;
+        }
+
+        selected(&self.muted_tracks, &self.solo_tracks, &(kind, track))
+    }
+
+    pub(crate) fn plays(&self, source: &EventSource) -> bool {
+        if !self.plays_track(source.kind, source.track) {
+            return false;
+        }
+
+        match (source.kind, source.channel) {
+            (TrackKind::ScoreTrack | TrackKind::SoftbankSequenceData, Some(channel)) => selected(&self.muted_channels, &self.solo_channels, &channel),
+            _ => true,
+        }
+    }
+}
+
+fn selected<T: PartialEq>(muted: &[T], solo: &[T], part: &T) -> bool {
+    !muted.contains(part) && (solo.is_empty() || solo.contains(part))
+}
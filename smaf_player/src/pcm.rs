@@ -0,0 +1,121 @@
+// decode stream waves into 16 bit samples, stereo samples are interleaved left first
+
+use alloc::{format, string::String, vec::Vec};
+
+use smaf::{decode_adpcm, BaseBit, Channel, PCMAudioTrack, PcmWaveFormat, StreamWaveFormat};
+
+use crate::{PlayerError, Result};
+
+pub fn decode_stream_wave(format: StreamWaveFormat, base_bit: BaseBit, channel: Channel, data: &[u8]) -> Vec<i16> {
+    match format {
+        // yamaha adpcm is coded in 4 bit steps whatever the base bit says
+        StreamWaveFormat::YamahaADPCM => decode_adpcm(data, channel_count(channel)),
+        StreamWaveFormat::TwosComplementPCM => decode_pcm(base_bit, data, true),
+        StreamWaveFormat::OffsetBinaryPCM => decode_pcm(base_bit, data, false),
+    }
+}
+
+// (channels, sampling rate, samples) of a wave of a pcm audio track
+pub fn decode_pcm_audio_wave(track: &PCMAudioTrack, data: &[u8]) -> Result<(u8, u32, Vec<i16>)> {
+    let sampling_rate = track
+        .sampling_freq
+        .hz()
+        .ok_or_else(|| PlayerError::Unsupported(format!("sampling frequency code {}", track.sampling_freq.to_u8())))?;
+    let channel = channel_count(track.channel);
+
+    Ok(match track.format {
+        PcmWaveFormat::Adpcm => (channel, sampling_rate, decode_adpcm(data, channel)),
+        PcmWaveFormat::TwosComplementPCM => (channel, sampling_rate, decode_pcm(track.base_bit, data, true)),
+        #[cfg(feature = "mp3")]
+        PcmWaveFormat::MP3 => crate::mp3::decode_mp3(data)?,
+        #[cfg(not(feature = "mp3"))]
+        PcmWaveFormat::MP3 => {
+            return Err(PlayerError::Unsupported(String::from(
+                "MP3 wave, enable the mp3 feature or use extract_mp3",
+            )))
+        }
+        // no twinvq decoder yet, it needs the codebook and window tables of the reference decoder
+        PcmWaveFormat::TwinVQ => return Err(PlayerError::Unsupported(String::from("TwinVQ wave, no decoder available"))),
+    })
+}
+
+pub fn channel_count(channel: Channel) -> u8 {
+    match channel {
+        Channel::Mono => 1,
+        Channel::Stereo => 2,
+    }
+}
+
+// samples are big endian and scaled up to 16 bits. 4 bit samples take the high nibble first,
+// 12 bit samples are packed two to three bytes.
+pub fn decode_pcm(base_bit: BaseBit, data: &[u8], signed: bool) -> Vec<i16> {
+    let (bits, raw): (u32, Vec<u16>) = match base_bit {
+        BaseBit::Bit4 => (4, data.iter().flat_map(|x| [x >> 4, x & 0x0f]).map(u16::from).collect()),
+        BaseBit::Bit8 => (8, data.iter().map(|&x| x as u16).collect()),
+        BaseBit::Bit12 => {
+            let mut raw = Vec::with_capacity(data.len() * 2 / 3);
+            for x in data.chunks(3) {
+                if x.len() >= 2 {
+                    raw.push(((x[0] as u16) << 4) | (x[1] >> 4) as u16);
+                }
+                if x.len() == 3 {
+                    raw.push((((x[1] & 0x0f) as u16) << 8) | x[2] as u16);
+                }
+            }
+            (12, raw)
+        }
+        BaseBit::Bit16 => (16, data.chunks_exact(2).map(|x| u16::from_be_bytes([x[0], x[1]])).collect()),
+    };
+
+    // offset binary only differs from two's complement in the sign bit
+    let sign = if signed { 0 } else { 1 << (bits - 1) };
+    raw.into_iter().map(|x| ((x ^ sign) << (16 - bits)) as i16).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use smaf::{decode_adpcm, BaseBit, Channel, StreamWaveFormat};
+
+    use super::{decode_pcm, decode_stream_wave};
+
+    #[test]
+    fn decodes_every_base_bit() {
+        assert_eq!(decode_pcm(BaseBit::Bit4, &[0x7f], true), [0x7000, -0x1000]);
+        assert_eq!(decode_pcm(BaseBit::Bit8, &[0x80, 0x7f], true), [-0x8000, 0x7f00]);
+        assert_eq!(
+            decode_pcm(BaseBit::Bit12, &[0x80, 0x07, 0xff, 0x12, 0x3f], true),
+            [-0x8000, 0x7ff0, 0x1230]
+        );
+        assert_eq!(decode_pcm(BaseBit::Bit16, &[0xff, 0xfe, 0x12], true), [-2]);
+    }
+
+    #[test]
+    fn decodes_offset_binary() {
+        assert_eq!(
+            decode_stream_wave(StreamWaveFormat::OffsetBinaryPCM, BaseBit::Bit8, Channel::Mono, &[0x00, 0x80, 0xff]),
+            [-0x8000, 0, 0x7f00]
+        );
+        assert_eq!(
+            decode_stream_wave(StreamWaveFormat::OffsetBinaryPCM, BaseBit::Bit12, Channel::Mono, &[0x80, 0x00, 0x00]),
+            [0, -0x8000]
+        );
+        assert_eq!(
+            decode_stream_wave(StreamWaveFormat::YamahaADPCM, BaseBit::Bit8, Channel::Mono, &[0x00]).len(),
+            2
+        );
+    }
+
+    #[test]
+    fn decodes_stereo_adpcm_with_a_state_per_channel() {
+        let data = [0x70, 0x70, 0x70];
+        let stereo = decode_stream_wave(StreamWaveFormat::YamahaADPCM, BaseBit::Bit4, Channel::Stereo, &data);
+        let left = decode_adpcm(&[0x77, 0x70], 1);
+        let right = decode_adpcm(&[0x00, 0x00], 1);
+
+        assert_eq!(stereo.len(), 6);
+        assert_eq!(stereo.iter().step_by(2).copied().collect::<Vec<_>>(), left[..3]);
+        assert_eq!(stereo.iter().skip(1).step_by(2).copied().collect::<Vec<_>>(), right[..3]);
+    }
+}
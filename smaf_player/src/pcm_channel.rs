@@ -0,0 +1,182 @@
+// volume, expression, pan and pitch bend of pcm audio track channels, baked into the wave samples
+
+use alloc::vec::Vec;
+
+use smaf::PCMAudioSequenceEvent;
+
+const MAX_LEVEL: u8 = 0x7f;
+const PAN_CENTER: u8 = 0x40;
+const PITCH_BEND_CENTER: u8 = 0x40;
+const PITCH_BEND_RANGE: f64 = 2.0; // semitones
+const GATE_FADE_MS: usize = 2; // fade out of gated waves, a hard cut clicks
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PcmChannel {
+    volume: u8,
+    expression: u8,
+    pan: u8,
+    pitch_bend: u8,
+}
+
+impl Default for PcmChannel {
+    fn default() -> Self {
+        Self {
+            volume: MAX_LEVEL,
+            expression: MAX_LEVEL,
+            pan: PAN_CENTER,
+            pitch_bend: PITCH_BEND_CENTER,
+        }
+    }
+}
+
+impl PcmChannel {
+    // other events are ignored
+    pub fn update(&mut self, event: &PCMAudioSequenceEvent) {
+        match *event {
+            PCMAudioSequenceEvent::Volume { value, .. } => self.volume = value.min(0x7f),
+            PCMAudioSequenceEvent::Expression { value, .. } => self.expression = value.min(0x7f),
+            PCMAudioSequenceEvent::Pan { value, .. } => self.pan = value.min(0x7f),
+            PCMAudioSequenceEvent::PitchBend { value, .. } => self.pitch_bend = value.min(0x7f),
+            _ => {}
+        }
+    }
+
+    // full volume plays the wave as stored, files commonly set 127 for that
+    fn gain(&self) -> f64 {
+        (self.volume as f64 / MAX_LEVEL as f64) * (self.expression as f64 / MAX_LEVEL as f64)
+    }
+
+    // balance, both sides stay at full level in the center
+    fn pan_gains(&self) -> (f64, f64) {
+        let left = ((0x7f - self.pan) as f64 / (0x7f - PAN_CENTER) as f64).min(1.0);
+        let right = (self.pan as f64 / PAN_CENTER as f64).min(1.0);
+        (left, right)
+    }
+
+    // source frames per output frame
+    fn pitch_ratio(&self) -> f64 {
+        let semitones = (self.pitch_bend as f64 - PITCH_BEND_CENTER as f64) / PITCH_BEND_CENTER as f64 * PITCH_BEND_RANGE;
+        libm::exp2(semitones / 12.0)
+    }
+}
+
+// plays the wave from `start` ms with the channel state and the following control changes of its channel (time in ms, event),
+// returns (channels, samples). mono waves become stereo once panned off center.
+pub fn apply_controls(
+    samples: &[i16],
+    channels: u8,
+    sampling_rate: u32,
+    start: usize,
+    mut state: PcmChannel,
+    controls: &[(usize, &PCMAudioSequenceEvent)],
+) -> (u8, Vec<i16>) {
+    let channels = channels.max(1) as usize;
+    let frames = samples.len() / channels;
+    let sampling_rate = sampling_rate.max(1) as usize;
+
+    let mut result = Vec::with_capacity(frames * 2);
+    let mut panned = false;
+    let mut next = 0;
+    let mut position = 0.0;
+    while (position as usize) < frames {
+        let time = start + result.len() / 2 * 1000 / sampling_rate;
+        while let Some((_, event)) = controls.get(next).filter(|(x, _)| *x <= time) {
+            state.update(event);
+            next += 1;
+        }
+
+        let frame = position as usize;
+        let fraction = position - frame as f64;
+        let sample = |channel: usize| {
+            let current = samples[frame * channels + channel] as f64;
+            let next = samples.get((frame + 1) * channels + channel).map_or(current, |&x| x as f64);
+            current + (next - current) * fraction
+        };
+        let (left, right) = if channels == 1 { (sample(0), sample(0)) } else { (sample(0), sample(1)) };
+
+        let gain = state.gain();
+        let (left_gain, right_gain) = state.pan_gains();
+        panned |= state.pan != PAN_CENTER;
+        result.push((left * gain * left_gain).clamp(i16::MIN as f64, i16::MAX as f64) as i16);
+        result.push((right * gain * right_gain).clamp(i16::MIN as f64, i16::MAX as f64) as i16);
+
+        position += state.pitch_ratio();
+    }
+
+    if channels == 1 && !panned {
+        (1, result.into_iter().step_by(2).collect())
+    } else {
+        (2, result)
+    }
+}
+
+// cuts the wave `gate` ms after its start, fading out over the last few ms. returns whether the wave was cut.
+pub fn apply_gate(samples: &mut Vec<i16>, channels: u8, sampling_rate: u32, gate: usize) -> bool {
+    let channels = channels.max(1) as usize;
+    let frames = gate * sampling_rate as usize / 1000;
+    if frames >= samples.len() / channels {
+        return false;
+    }
+
+    samples.truncate(frames * channels);
+    let fade = (GATE_FADE_MS * sampling_rate as usize / 1000).clamp(1, frames.max(1));
+    for (index, frame) in samples.chunks_exact_mut(channels).rev().take(fade).enumerate() {
+        for sample in frame {
+            *sample = (*sample as i32 * index as i32 / fade as i32) as i16;
+        }
+    }
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use smaf::PCMAudioSequenceEvent;
+
+    use super::{apply_controls, apply_gate, PcmChannel};
+
+    #[test]
+    fn keeps_waves_with_default_controls() {
+        let samples = [100, -200, 300, -400];
+
+        assert_eq!(apply_controls(&samples, 1, 1000, 0, PcmChannel::default(), &[]), (1, samples.to_vec()));
+        assert_eq!(apply_controls(&samples, 2, 1000, 0, PcmChannel::default(), &[]), (2, samples.to_vec()));
+    }
+
+    #[test]
+    fn applies_gain_and_pan() {
+        let mut state = PcmChannel::default();
+        state.update(&PCMAudioSequenceEvent::Expression { channel: 0, value: 0x40 });
+        state.update(&PCMAudioSequenceEvent::Pan { channel: 0, value: 0 });
+
+        assert_eq!(apply_controls(&[1000, 1000], 1, 1000, 0, state, &[]), (2, [503, 0, 503, 0].to_vec()));
+    }
+
+    #[test]
+    fn applies_changes_in_the_middle_of_a_wave() {
+        let expression = PCMAudioSequenceEvent::Expression { channel: 0, value: 0 };
+        let bend = PCMAudioSequenceEvent::PitchBend { channel: 0, value: 0x7f };
+
+        let (_, samples) = apply_controls(&[1000; 4], 1, 1000, 10, PcmChannel::default(), &[(12, &expression)]);
+        assert_eq!(samples, [1000, 1000, 0, 0]);
+
+        // two semitones up from the second frame
+        let (_, samples) = apply_controls(&[0, 1000, 2000, 3000, 4000, 5000], 1, 1000, 0, PcmChannel::default(), &[(1, &bend)]);
+        assert_eq!(samples.len(), 6);
+        assert_eq!(samples[..2], [0, 1000]);
+        assert!(samples[2] > 2000 && samples[2] < 2300);
+    }
+
+    #[test]
+    fn cuts_waves_at_the_gate_with_a_fade() {
+        let mut samples = vec![1000; 20];
+        assert!(apply_gate(&mut samples, 2, 2000, 4));
+        assert_eq!(samples, [1000, 1000, 1000, 1000, 750, 500, 250, 0].map(|x| [x, x]).concat());
+
+        let mut samples = vec![1000; 4];
+        assert!(!apply_gate(&mut samples, 1, 1000, 10));
+        assert_eq!(samples, [1000; 4]);
+    }
+}
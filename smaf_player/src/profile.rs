@@ -0,0 +1,68 @@
+use crate::MA_MELODY_BANK;
+
+const GM_SYSTEM_ON: [u8; 6] = [0xf0, 0x7e, 0x7f, 0x09, 0x01, 0xf7];
+const GM2_SYSTEM_ON: [u8; 6] = [0xf0, 0x7e, 0x7f, 0x09, 0x03, 0xf7];
+const GS_RESET: [u8; 11] = [0xf0, 0x41, 0x10, 0x42, 0x12, 0x40, 0x00, 0x7f, 0x00, 0x41, 0xf7];
+const XG_SYSTEM_ON: [u8; 9] = [0xf0, 0x43, 0x10, 0x4c, 0x00, 0x00, 0x7e, 0x00, 0xf7];
+
+#[derive(Eq, PartialEq, Copy, Clone, Debug, Default)]
+pub enum OutputProfile {
+    // gm fallbacks for yamaha ma voices, ma exclusive messages forwarded as is
+    #[default]
+    Generic,
+    GeneralMidi,
+    GeneralMidi2,
+    Gs,
+    Xg,
+}
+
+impl OutputProfile {
+    pub(crate) fn reset_messages(self) -> &'static [&'static [u8]] {
+        match self {
+            Self::Generic => &[],
+            Self::GeneralMidi => &[&GM_SYSTEM_ON],
+            Self::GeneralMidi2 => &[&GM2_SYSTEM_ON],
+            Self::Gs => &[&GS_RESET],
+            Self::Xg => &[&GM_SYSTEM_ON, &XG_SYSTEM_ON],
+        }
+    }
+
+    // yamaha ma exclusive messages (manufacturer 0x43, model 0x79) only make sense on ma hardware
+    pub(crate) fn forwards_exclusive(self, data: &[u8]) -> bool {
+        let data = data.strip_prefix(&[0xf0]).unwrap_or(data);
+
+        self == Self::Generic || !data.starts_with(&[0x43, 0x79])
+    }
+
+    // generic and gm targets have no variation banks, so ma voices collapse to gm programs
+    pub(crate) fn collapses_voices(self) -> bool {
+        matches!(self, Self::Generic | Self::GeneralMidi)
+    }
+
+    // whether raw bank select controls from the sequence are passed through
+    pub(crate) fn forwards_bank_select(self) -> bool {
+        self == Self::Generic
+    }
+
+    // (msb, lsb) to send before a melody program change
+    pub(crate) fn melody_bank(self, bank_msb: u8, bank_lsb: u8) -> Option<(u8, u8)> {
+        let variation = if bank_msb == MA_MELODY_BANK { bank_lsb & 0x7f } else { 0 };
+
+        match self {
+            Self::Generic | Self::GeneralMidi => None,
+            Self::GeneralMidi2 => Some((0x79, variation)),
+            Self::Gs => Some((variation, 0)),
+            Self::Xg => Some((0, variation)),
+        }
+    }
+
+    // (msb, lsb) to send before a drum kit program change
+    pub(crate) fn rhythm_bank(self) -> Option<(u8, u8)> {
+        match self {
+            Self::Generic | Self::GeneralMidi => None,
+            Self::GeneralMidi2 => Some((0x78, 0)),
+            Self::Gs => Some((0, 0)),
+            Self::Xg => Some((0x7f, 0)),
+        }
+    }
+}
@@ -0,0 +1,263 @@
+// offline rendering of a whole file to stereo 16 bit pcm, interleaved left first, midi parts through the fm synthesizer
+
+use alloc::{vec, vec::Vec};
+
+use smaf::Smaf;
+
+use crate::{
+    default_voice_bank, parse_smaf_events, AtmosphereConfig, EventSource, FmSynth, FmVoiceBank, OutputProfile, PlayerOptions, Result, SmafEvent,
+};
+
+const MAX_TAIL_MS: usize = 10000; // how long released notes may ring after the last event
+
+pub fn render(raw: &[u8], sample_rate: u32) -> Result<Vec<i16>> {
+    render_with_options(raw, sample_rate, &PlayerOptions::default())
+}
+
+pub fn render_with_options(raw: &[u8], sample_rate: u32, options: &PlayerOptions) -> Result<Vec<i16>> {
+    // the synthesizer plays the file's own banks and programs, the atmosphere layers only work around midi devices
+    let options = PlayerOptions {
+        profile: OutputProfile::Generic,
+        atmosphere: AtmosphereConfig::disabled(),
+        ..options.clone()
+    };
+    let events = parse_smaf_events(raw, &options, true)?;
+
+    // voices defined by the file replace the built-in ones
+    let mut voices = default_voice_bank();
+    voices.merge(FmVoiceBank::from_smaf(&Smaf::parse(raw)?));
+    let mut synth = FmSynth::new(sample_rate);
+    synth.set_voice_bank(voices);
+
+    Ok(render_events(&events, &mut synth, sample_rate))
+}
+
+pub fn render_events(events: &[(usize, EventSource, SmafEvent)], synth: &mut FmSynth, sample_rate: u32) -> Vec<i16> {
+    let mut result = Vec::new();
+    let mut position = 0;
+
+    for (time, _, event) in events {
+        let target = time * sample_rate as usize / 1000 * 2;
+        if result.len() < target {
+            result.resize(target, 0);
+        }
+        if target > position {
+            synth.render_stereo(&mut result[position..target]);
+            position = target;
+        }
+
+        match event {
+            SmafEvent::Wave {
+                channel,
+                sampling_rate,
+                data,
+            } => {
+                let wave = resample(data, *channel, *sampling_rate, sample_rate);
+                if result.len() < target + wave.len() {
+                    result.resize(target + wave.len(), 0);
+                }
+                for (output, sample) in result[target..].iter_mut().zip(wave) {
+                    *output = output.saturating_add(sample);
+                }
+            }
+            event => synth.process(event),
+        }
+    }
+
+    // let waves and released notes finish
+    let tail_end = result.len().max(position + MAX_TAIL_MS * sample_rate as usize / 1000 * 2);
+    let chunk = (sample_rate as usize / 100).max(1) * 2;
+    while position < tail_end && (position < result.len() || !synth.is_silent()) {
+        let end = (position + chunk).min(tail_end);
+        if result.len() < end {
+            result.resize(end, 0);
+        }
+        synth.render_stereo(&mut result[position..end]);
+        position = end;
+    }
+
+    result
+}
+
+// linear interpolation to `to` hz, interleaved stereo out of mono or stereo
+fn resample(data: &[i16], channels: u8, from: u32, to: u32) -> Vec<i16> {
+    let channels = channels.max(1) as usize;
+    let frames = data
+        .chunks_exact(channels)
+        .map(|frame| [frame[0], frame[channels - 1]])
+        .collect::<Vec<_>>();
+    if from == to || frames.is_empty() || from == 0 {
+        return frames.concat();
+    }
+
+    let length = (frames.len() as u64 * to as u64 / from as u64) as usize;
+    let mut result = vec![0; length * 2];
+    for (index, output) in result.chunks_exact_mut(2).enumerate() {
+        let position = index as u64 * from as u64;
+        let source = (position / to as u64) as usize;
+        let fraction = (position % to as u64) as i64;
+        let next = frames.get(source + 1).unwrap_or(&frames[source]);
+        for ((output, &current), &next) in output.iter_mut().zip(&frames[source]).zip(next) {
+            *output = (current as i64 + (next as i64 - current as i64) * fraction / to as i64) as i16;
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{vec, vec::Vec};
+
+    use smaf::{ChannelType, FormatType, SmafBuilder};
+
+    use super::{render, render_events, resample};
+    use crate::{EventSource, FmSynth, SmafEvent, TrackKind};
+
+    // a two operator fm voice that never attacks
+    fn silent_voice(bank_msb: u8, bank_lsb: u8, program: u8, drum_note: u8) -> Vec<u8> {
+        let mut message = Vec::from([0x43, 0x79, 0x06, 0x7f, 0x01, bank_msb, bank_lsb, program, drum_note, 0x00]);
+        message.extend_from_slice(&[0; 20]);
+        message.push(0xf7);
+        message
+    }
+
+    fn peak(samples: &[i16]) -> i16 {
+        samples.iter().map(|x| x.saturating_abs()).max().unwrap_or(0)
+    }
+
+    #[test]
+    fn resamples_to_stereo() {
+        assert_eq!(
+            resample(&[0, 100, 200], 1, 4000, 8000),
+            [0, 0, 50, 50, 100, 100, 150, 150, 200, 200, 200, 200]
+        );
+        assert_eq!(resample(&[100, 300, -100, -300], 2, 8000, 8000), [100, 300, -100, -300]);
+        assert_eq!(resample(&[0, 100, 200, 300], 2, 4000, 8000), [0, 100, 100, 200, 200, 300, 200, 300]);
+    }
+
+    #[test]
+    fn places_waves_and_notes_on_the_timeline() {
+        let source = EventSource::track(TrackKind::PCMAudioTrack, 0);
+        let events = vec![
+            (
+                10,
+                source,
+                SmafEvent::Wave {
+                    channel: 1,
+                    sampling_rate: 1000,
+                    data: vec![1000; 5],
+                },
+            ),
+            (
+                20,
+                source,
+                SmafEvent::MidiNoteOn {
+                    channel: 0,
+                    note: 60,
+                    velocity: 100,
+                },
+            ),
+            (
+                30,
+                source,
+                SmafEvent::MidiNoteOff {
+                    channel: 0,
+                    note: 60,
+                    velocity: 0,
+                },
+            ),
+        ];
+
+        let result = render_events(&events, &mut FmSynth::new(1000), 1000);
+        assert!(result[..20].iter().all(|&x| x == 0));
+        assert_eq!(result[20..30], [1000; 10]);
+        assert!(result[40..60].iter().any(|&x| x != 0));
+    }
+
+    #[test]
+    fn keeps_pan_of_waves_and_notes() {
+        let source = EventSource::track(TrackKind::ScoreTrack, 0);
+        let events = vec![
+            (
+                0,
+                source,
+                SmafEvent::Wave {
+                    channel: 2,
+                    sampling_rate: 1000,
+                    data: vec![1000, 0, 1000, 0],
+                },
+            ),
+            (
+                10,
+                source,
+                SmafEvent::MidiControlChange {
+                    channel: 0,
+                    control: 10,
+                    value: 0,
+                },
+            ),
+            (
+                10,
+                source,
+                SmafEvent::MidiNoteOn {
+                    channel: 0,
+                    note: 60,
+                    velocity: 100,
+                },
+            ),
+        ];
+
+        let result = render_events(&events, &mut FmSynth::new(1000), 1000);
+        assert_eq!(result[..4], [1000, 0, 1000, 0]);
+        let (left, right): (Vec<_>, Vec<_>) = result[20..].chunks_exact(2).map(|frame| (frame[0], frame[1])).unzip();
+        assert!(peak(&left) > 1000);
+        assert_eq!(peak(&right), 0);
+    }
+
+    #[test]
+    fn plays_file_defined_variation_and_rhythm_voices() {
+        let melody = |voice: Option<Vec<u8>>| {
+            SmafBuilder::new()
+                .score_track(FormatType::MobileStandardNoCompress, 20, |track| {
+                    let track = match voice {
+                        Some(voice) => track.exclusive(&voice),
+                        None => track,
+                    };
+                    track
+                        .control_change(0, 0, 0x7c)
+                        .control_change(0, 32, 0x01)
+                        .program(0, 0x22)
+                        .note(0, 60, 100, 10)
+                })
+                .build()
+                .unwrap()
+        };
+        assert!(peak(&render(&melody(None), 8000).unwrap()) > 1000);
+        assert_eq!(peak(&render(&melody(Some(silent_voice(0x7c, 0x01, 0x22, 0))), 8000).unwrap()), 0);
+
+        // 0x12 is a ma rhythm key that midi outputs play as gm key 45
+        let rhythm = |voice: Option<Vec<u8>>| {
+            SmafBuilder::new()
+                .score_track(FormatType::MobileStandardNoCompress, 20, |track| {
+                    let track = match voice {
+                        Some(voice) => track.exclusive(&voice),
+                        None => track,
+                    };
+                    track.channel_type(0, ChannelType::Rhythm).note(0, 0x12, 100, 10)
+                })
+                .build()
+                .unwrap()
+        };
+        assert!(peak(&render(&rhythm(None), 8000).unwrap()) > 1000);
+        assert_eq!(peak(&render(&rhythm(Some(silent_voice(0x7d, 0x00, 0, 0x12))), 8000).unwrap()), 0);
+    }
+
+    #[test]
+    fn renders_file() {
+        let result = render(include_bytes!("../../test_data/midi.mmf"), 4000).unwrap();
+
+        assert!(result.len() > 4000);
+        assert!(result.iter().any(|&x| x.saturating_abs() > 1000));
+    }
+}
@@ -0,0 +1,231 @@
+// yamaha ma-3 voice parameter exclusive messages
+// f0 43 79 06 7f 01 <bank msb> <bank lsb> <program> <drum note> <voice type> <7 bit packed parameters> f7
+
+use alloc::{collections::BTreeMap, vec::Vec};
+
+use smaf::{ScoreTrackChunk, ScoreTrackSequenceEvent, Smaf, SmafChunk};
+
+use crate::{
+    fm::{FmOperator, FmVoice},
+    parse_setup_sysex_events, Result, SmafEvent, MA_RHYTHM_BANK,
+};
+
+const VOICE_HEADER: [u8; 5] = [0x43, 0x79, 0x06, 0x7f, 0x01];
+const VOICE_TYPE_FM: u8 = 0x00;
+const OPERATOR_SIZE: usize = 7;
+
+// voices keyed by (bank msb, bank lsb, program), rhythm voices by (bank msb, bank lsb, drum note)
+#[derive(Clone, Debug, Default)]
+pub struct FmVoiceBank {
+    voices: BTreeMap<(u8, u8, u8), FmVoice>,
+}
+
+impl FmVoiceBank {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // voices defined in setup data and exclusive messages of every score track
+    pub fn from_smaf(smaf: &Smaf) -> Self {
+        let mut bank = Self::new();
+
+        for chunk in &smaf.chunks {
+            match chunk {
+                SmafChunk::ScoreTrack(_, track) => {
+                    for chunk in &track.chunks {
+                        match chunk {
+                            ScoreTrackChunk::SetupData(data) => {
+                                for (_, event) in parse_setup_sysex_events(data) {
+                                    if let SmafEvent::MidiSysEx(message) = event {
+                                        bank.insert_message(&message);
+                                    }
+                                }
+                            }
+                            ScoreTrackChunk::SequenceData(sequence) => bank.insert_sequence(sequence),
+                            _ => {}
+                        }
+                    }
+                }
+                SmafChunk::SoftbankSequenceData(sequence) => bank.insert_sequence(sequence),
+                _ => {}
+            }
+        }
+
+        bank
+    }
+
+    pub fn parse(raw: &[u8]) -> Result<Self> {
+        Ok(Self::from_smaf(&Smaf::parse(raw)?))
+    }
+
+    fn insert_sequence(&mut self, sequence: &[smaf::SequenceData]) {
+        for event in sequence {
+            if let ScoreTrackSequenceEvent::Exclusive(ref data) = event.event {
+                self.insert_message(data);
+            }
+        }
+    }
+
+    // returns whether the message was an fm voice definition
+    pub fn insert_message(&mut self, message: &[u8]) -> bool {
+        let Some((key, voice)) = decode_fm_voice(message) else {
+            return false;
+        };
+        self.voices.insert(key, voice);
+
+        true
+    }
+
+    // voices of `other` replace the ones with the same key
+    pub fn merge(&mut self, other: FmVoiceBank) {
+        self.voices.extend(other.voices);
+    }
+
+    pub fn insert(&mut self, bank_msb: u8, bank_lsb: u8, program: u8, voice: FmVoice) {
+        self.voices.insert((bank_msb, bank_lsb, program), voice);
+    }
+
+    pub fn get(&self, bank_msb: u8, bank_lsb: u8, program: u8) -> Option<&FmVoice> {
+        self.voices.get(&(bank_msb, bank_lsb, program))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&(u8, u8, u8), &FmVoice)> {
+        self.voices.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.voices.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.voices.is_empty()
+    }
+}
+
+// decodes an fm voice definition, with or without the surrounding f0/f7, into its bank key and voice
+pub fn decode_fm_voice(message: &[u8]) -> Option<((u8, u8, u8), FmVoice)> {
+    let message = message.strip_prefix(&[0xf0]).unwrap_or(message);
+    let message = message.strip_suffix(&[0xf7]).unwrap_or(message);
+    let data = message.strip_prefix(&VOICE_HEADER)?;
+    let (&[bank_msb, bank_lsb, program, drum_note, voice_type], packed) = data.split_first_chunk::<5>()?;
+    if voice_type != VOICE_TYPE_FM {
+        return None;
+    }
+
+    let data = unpack_7bit(packed);
+    let &[drum_key, octave_pan, lfo_algorithm, ..] = data.as_slice() else {
+        return None;
+    };
+
+    let algorithm = lfo_algorithm & 0x07;
+    let operator_count = if algorithm < 2 { 2 } else { 4 };
+    let operators = data[3..]
+        .chunks_exact(OPERATOR_SIZE)
+        .take(operator_count)
+        .map(decode_operator)
+        .collect::<Vec<_>>();
+    if operators.len() != operator_count {
+        return None;
+    }
+
+    let voice = FmVoice {
+        algorithm,
+        operators,
+        octave: (octave_pan & 0x03) as i8 - 1,
+        lfo: lfo_algorithm >> 6,
+        panpot: octave_pan >> 3,
+        drum_key: drum_key & 0x7f,
+    };
+    let key = if bank_msb == MA_RHYTHM_BANK {
+        (bank_msb, bank_lsb, drum_note)
+    } else {
+        (bank_msb, bank_lsb, program)
+    };
+
+    Some((key, voice))
+}
+
+fn decode_operator(data: &[u8]) -> FmOperator {
+    FmOperator {
+        sustain_rate: data[0] >> 4,
+        ignore_key_off: data[0] & 0x08 != 0,
+        sustain: data[0] & 0x02 != 0,
+        key_scale_rate: data[0] & 0x01 != 0,
+        release_rate: data[1] >> 4,
+        decay_rate: data[1] & 0x0f,
+        attack_rate: data[2] >> 4,
+        sustain_level: data[2] & 0x0f,
+        total_level: data[3] >> 2,
+        key_scale_level: data[3] & 0x03,
+        tremolo_depth: (data[4] >> 5) & 0x03,
+        tremolo: data[4] & 0x10 != 0,
+        vibrato_depth: (data[4] >> 1) & 0x03,
+        vibrato: data[4] & 0x01 != 0,
+        multiple: data[5] >> 4,
+        detune: data[5] & 0x07,
+        waveform: data[6] >> 3,
+        feedback: data[6] & 0x07,
+    }
+}
+
+// groups of 8 bytes, the first one carrying the top bits of the following 7
+fn unpack_7bit(data: &[u8]) -> Vec<u8> {
+    data.chunks(8)
+        .flat_map(|group| {
+            let msb = group[0];
+            group[1..]
+                .iter()
+                .enumerate()
+                .map(move |(index, byte)| byte | (((msb >> (6 - index)) & 1) << 7))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_fm_voice, FmVoiceBank};
+
+    #[test]
+    fn decodes_four_operator_voice() {
+        let message = [
+            0xf0, 0x43, 0x79, 0x06, 0x7f, 0x01, 0x7c, 0x01, 0x3a, 0x00, 0x00, 0x02, 0x00, 0x79, 0x45, 0x23, 0x34, 0x72, 0x19, 0x02, 0x00, 0x10, 0x2b,
+            0x13, 0x52, 0x73, 0x22, 0x02, 0x01, 0x10, 0x00, 0x23, 0x14, 0x72, 0x42, 0x02, 0x00, 0x10, 0x2b, 0x13, 0x21, 0x73, 0x1e, 0x00, 0x01, 0x10,
+            0x00, 0xf7,
+        ];
+
+        let (key, voice) = decode_fm_voice(&message).unwrap();
+        assert_eq!(key, (0x7c, 0x01, 0x3a));
+        assert_eq!(voice.algorithm, 5);
+        assert_eq!(voice.lfo, 1);
+        assert_eq!(voice.panpot, 15);
+        assert_eq!(voice.octave, 0);
+        assert_eq!(voice.operators.len(), 4);
+
+        // 0x72 carries its top bit in the group header
+        let first = voice.operators[0];
+        assert_eq!(
+            (first.sustain_rate, first.ignore_key_off, first.sustain, first.key_scale_rate),
+            (2, false, true, true)
+        );
+        assert_eq!((first.release_rate, first.decay_rate), (3, 4));
+        assert_eq!((first.attack_rate, first.sustain_level), (15, 2));
+        assert_eq!((first.total_level, first.key_scale_level), (6, 1));
+        assert_eq!((first.multiple, first.detune), (1, 0));
+        assert_eq!((first.waveform, first.feedback), (5, 3));
+    }
+
+    #[test]
+    fn ignores_non_fm_messages() {
+        assert!(decode_fm_voice(&[0xf0, 0x43, 0x79, 0x06, 0x7f, 0x7f, 0xf7]).is_none());
+        assert!(decode_fm_voice(&[0xf0, 0x43, 0x79, 0x06, 0x7f, 0x01, 0x7d, 0x00, 0x02, 0x1d, 0x01, 0x33, 0xf7]).is_none());
+    }
+
+    #[test]
+    fn collects_voices_from_file() {
+        let bank = FmVoiceBank::parse(include_bytes!("../../test_data/midi.mmf")).unwrap();
+
+        assert_eq!(bank.len(), 2);
+        assert!(bank.get(0x7c, 0x01, 0x3a).is_some());
+        assert_eq!(bank.get(0x7d, 0x00, 0x54).unwrap().drum_key, 0x49);
+    }
+}
@@ -0,0 +1,155 @@
+// minimal riff/wave reader and writer for integer pcm
+
+use alloc::{format, string::String, vec::Vec};
+
+use crate::{PlayerError, Result};
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xfffe;
+// the sub-format guid of extensible files is the format tag followed by these bytes
+const SUB_FORMAT_GUID_TAIL: [u8; 14] = [0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xaa, 0x00, 0x38, 0x9b, 0x71];
+
+// (channels, sample rate, interleaved samples) of 8, 16, 24 or 32 bit mono or stereo pcm, scaled to 16 bits
+pub fn read_wav(raw: &[u8]) -> Result<(u8, u32, Vec<i16>)> {
+    let invalid = |message: &str| PlayerError::InvalidWave(String::from(message));
+
+    if raw.len() < 12 || &raw[0..4] != b"RIFF" || &raw[8..12] != b"WAVE" {
+        return Err(invalid("missing RIFF WAVE header"));
+    }
+
+    let mut format = None;
+    let mut data = None;
+    let mut rest = &raw[12..];
+    while rest.len() >= 8 {
+        let length = u32::from_le_bytes([rest[4], rest[5], rest[6], rest[7]]) as usize;
+        let chunk = rest.get(8..8 + length).ok_or_else(|| invalid("truncated chunk"))?;
+        match &rest[..4] {
+            b"fmt " if chunk.len() >= 16 => format = Some(chunk),
+            b"data" => data = Some(chunk),
+            _ => {}
+        }
+        // chunks are padded to even lengths
+        rest = rest.get(8 + length + (length & 1)..).unwrap_or_default();
+    }
+    let format = format.ok_or_else(|| invalid("missing fmt chunk"))?;
+    let data = data.ok_or_else(|| invalid("missing data chunk"))?;
+
+    let mut tag = u16::from_le_bytes([format[0], format[1]]);
+    if tag == WAVE_FORMAT_EXTENSIBLE {
+        let sub_format = format.get(24..40).ok_or_else(|| invalid("truncated extensible fmt chunk"))?;
+        if sub_format[2..] != SUB_FORMAT_GUID_TAIL {
+            return Err(PlayerError::Unsupported(String::from("extensible wave sub-format")));
+        }
+        tag = u16::from_le_bytes([sub_format[0], sub_format[1]]);
+    }
+    let channels = u16::from_le_bytes([format[2], format[3]]);
+    let sample_rate = u32::from_le_bytes([format[4], format[5], format[6], format[7]]);
+    let bits = u16::from_le_bytes([format[14], format[15]]);
+    if tag != WAVE_FORMAT_PCM {
+        return Err(PlayerError::Unsupported(format!("wave format {tag:#x}")));
+    }
+    if !(1..=2).contains(&channels) || sample_rate == 0 {
+        return Err(PlayerError::Unsupported(format!("{channels} channel {sample_rate} hz wave")));
+    }
+
+    let samples = match bits {
+        8 => data.iter().map(|&x| ((x as i16) - 0x80) << 8).collect(),
+        16 | 24 | 32 => {
+            // the two most significant bytes of each little endian sample
+            let width = bits as usize / 8;
+            data.chunks_exact(width)
+                .map(|x| i16::from_le_bytes([x[width - 2], x[width - 1]]))
+                .collect()
+        }
+        _ => return Err(PlayerError::Unsupported(format!("{bits} bit wave"))),
+    };
+
+    Ok((channels as u8, sample_rate, samples))
+}
+
+pub fn write_wav(samples: &[i16], sample_rate: u32, channels: u16) -> Vec<u8> {
+    let data_size = (samples.len() * 2) as u32;
+    let block_align = channels * 2;
+    let mut result = Vec::with_capacity(44 + data_size as usize);
+
+    result.extend_from_slice(b"RIFF");
+    result.extend_from_slice(&(36 + data_size).to_le_bytes());
+    result.extend_from_slice(b"WAVE");
+
+    result.extend_from_slice(b"fmt ");
+    result.extend_from_slice(&16u32.to_le_bytes());
+    result.extend_from_slice(&1u16.to_le_bytes()); // pcm
+    result.extend_from_slice(&channels.to_le_bytes());
+    result.extend_from_slice(&sample_rate.to_le_bytes());
+    result.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+    result.extend_from_slice(&block_align.to_le_bytes());
+    result.extend_from_slice(&16u16.to_le_bytes());
+
+    result.extend_from_slice(b"data");
+    result.extend_from_slice(&data_size.to_le_bytes());
+    for sample in samples {
+        result.extend_from_slice(&sample.to_le_bytes());
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::{read_wav, write_wav, SUB_FORMAT_GUID_TAIL};
+    use crate::PlayerError;
+
+    #[test]
+    fn writes_pcm_header_and_samples() {
+        let wav = write_wav(&[1, -2], 8000, 1);
+
+        assert_eq!(wav.len(), 48);
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes(wav[4..8].try_into().unwrap()), 40);
+        assert_eq!(&wav[8..16], b"WAVEfmt ");
+        assert_eq!(u32::from_le_bytes(wav[24..28].try_into().unwrap()), 8000);
+        assert_eq!(u32::from_le_bytes(wav[28..32].try_into().unwrap()), 16000);
+        assert_eq!(&wav[36..40], b"data");
+        assert_eq!(&wav[44..], &[0x01, 0x00, 0xfe, 0xff]);
+    }
+
+    #[test]
+    fn reads_written_file() {
+        let wav = write_wav(&[1, -2, 300, -400], 22050, 2);
+        assert_eq!(read_wav(&wav).unwrap(), (2, 22050, alloc::vec![1, -2, 300, -400]));
+
+        // 8 bit unsigned
+        let mut wav = wav;
+        wav[34] = 8;
+        assert_eq!(read_wav(&wav).unwrap().2[..2], [-0x7f00, -0x8000]);
+
+        assert!(matches!(read_wav(b"RIFF"), Err(PlayerError::InvalidWave(_))));
+    }
+
+    #[test]
+    fn checks_extensible_sub_format() {
+        let extensible = |sub_format: u16| {
+            let wav = write_wav(&[1, -2], 8000, 1);
+            let mut result = Vec::from(&wav[..12]);
+            result.extend_from_slice(b"fmt ");
+            result.extend_from_slice(&40u32.to_le_bytes());
+            result.extend_from_slice(&0xfffeu16.to_le_bytes());
+            result.extend_from_slice(&wav[22..36]);
+            result.extend_from_slice(&[22, 0, 16, 0, 0, 0, 0, 0]); // extension size, valid bits, channel mask
+            result.extend_from_slice(&sub_format.to_le_bytes());
+            result.extend_from_slice(&SUB_FORMAT_GUID_TAIL);
+            result.extend_from_slice(&wav[36..]);
+            result
+        };
+
+        assert_eq!(read_wav(&extensible(1)).unwrap(), (1, 8000, alloc::vec![1, -2]));
+        assert!(matches!(read_wav(&extensible(3)), Err(PlayerError::Unsupported(_))));
+
+        let mut truncated = extensible(1);
+        truncated[16] = 16;
+        truncated.drain(36..60);
+        assert!(matches!(read_wav(&truncated), Err(PlayerError::InvalidWave(_))));
+    }
+}
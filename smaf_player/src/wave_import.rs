@@ -0,0 +1,282 @@
+// adding wav files to smaf files as yamaha adpcm, in a new pcm audio track or as a stream wave of a score track
+
+use alloc::{format, string::String, vec::Vec};
+
+use smaf::{
+    encode_adpcm, read_chunks, read_smaf_chunks, write_chunk, write_pcm_audio_track, write_smaf, AdpcmQuality, BaseBit, Channel, FormatType,
+    PCMAudioSequenceData, PCMAudioSequenceEvent, PcmWaveFormat, SamplingRate, StreamWaveFormat, WaveData,
+};
+
+use crate::{wav::read_wav, PlayerError, Result};
+
+const PCM_TRACK_TIMEBASE: u8 = 1;
+const HANDY_CHANNEL_STATUS_LENGTH: usize = 2;
+const MOBILE_CHANNEL_STATUS_LENGTH: usize = 16;
+const MAX_STREAM_WAVES: u8 = 16; // a note 0 on channel n plays stream wave n + 1
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum WaveTarget {
+    PcmAudioTrack, // a new ATR chunk playing the wave once from the start
+    StreamWave,    // an Mwa entry in the Mtsp chunk of the first mobile standard score track
+}
+
+#[derive(Clone)]
+pub struct WaveImportOptions {
+    pub target: WaveTarget,
+    pub sampling_rate: Option<SamplingRate>, // the lowest supported rate not below the wav rate when none
+    pub quality: AdpcmQuality,
+}
+
+impl Default for WaveImportOptions {
+    fn default() -> Self {
+        Self {
+            target: WaveTarget::PcmAudioTrack,
+            sampling_rate: None,
+            quality: AdpcmQuality::default(),
+        }
+    }
+}
+
+// returns the new file and the number of the added track or stream wave
+pub fn add_wave(smaf: &[u8], wav: &[u8], options: &WaveImportOptions) -> Result<(Vec<u8>, u8)> {
+    let (channels, sample_rate, samples) = read_wav(wav)?;
+    let sampling_rate = options.sampling_rate.unwrap_or_else(|| pick_sampling_rate(sample_rate));
+    let hz = sampling_rate
+        .hz()
+        .ok_or_else(|| PlayerError::Unsupported(format!("sampling frequency code {}", sampling_rate.to_u8())))?;
+
+    let samples = resample(&samples, channels, sample_rate, hz);
+    let data = encode_adpcm(&samples, channels, options.quality);
+    let channel = if channels == 2 { Channel::Stereo } else { Channel::Mono };
+    let length_ms = (samples.len() / channels as usize) as u64 * 1000 / hz as u64;
+
+    let chunks = read_smaf_chunks(smaf)?;
+    let mut result = Vec::new();
+    let number = match options.target {
+        WaveTarget::PcmAudioTrack => {
+            let number = (0..=u8::MAX)
+                .find(|x| !chunks.iter().any(|(tag, _)| *tag == [b'A', b'T', b'R', *x]))
+                .ok_or_else(|| PlayerError::Unsupported(String::from("no free pcm audio track number")))?;
+
+            let mut track = Vec::new();
+            let sequence = [PCMAudioSequenceData {
+                duration: 0,
+                event: PCMAudioSequenceEvent::WaveMessage {
+                    channel: 0,
+                    wave_number: 1,
+                    gate_time: (length_ms / PCM_TRACK_TIMEBASE as u64) as u32,
+                },
+            }];
+            write_chunk(&mut track, b"Atsq", &PCMAudioSequenceData::write(&sequence)?);
+            write_chunk(&mut track, b"Awa\x01", &data);
+
+            for (tag, data) in &chunks {
+                write_chunk(&mut result, tag, data);
+            }
+            write_chunk(
+                &mut result,
+                &[b'A', b'T', b'R', number],
+                &write_pcm_audio_track(
+                    channel,
+                    PcmWaveFormat::Adpcm,
+                    sampling_rate,
+                    BaseBit::Bit4,
+                    PCM_TRACK_TIMEBASE,
+                    PCM_TRACK_TIMEBASE,
+                    &track,
+                )?,
+            );
+
+            number
+        }
+        WaveTarget::StreamWave => {
+            let wave = WaveData {
+                channel,
+                format: StreamWaveFormat::YamahaADPCM,
+                base_bit: BaseBit::Bit4,
+                sampling_freq: hz as u16,
+                wave_data: &data,
+            }
+            .write();
+
+            let mut number = None;
+            for (tag, data) in &chunks {
+                let is_mobile_track = tag.starts_with(b"MTR") && data.first().is_some_and(|&x| x != FormatType::HandyPhoneStandard as u8);
+                if number.is_none() && is_mobile_track {
+                    let (track, wave_number) = add_stream_wave(data, &wave)?;
+                    write_chunk(&mut result, tag, &track);
+                    number = Some(wave_number);
+                } else {
+                    write_chunk(&mut result, tag, data);
+                }
+            }
+
+            number.ok_or_else(|| PlayerError::Unsupported(String::from("stream waves need a mobile standard score track")))?
+        }
+    };
+
+    Ok((write_smaf(&result), number))
+}
+
+// the score track with the wave added to its Mtsp chunk under the first free wave number
+fn add_stream_wave(track: &[u8], wave: &[u8]) -> Result<(Vec<u8>, u8)> {
+    let header_length = 4 + match track[0] {
+        0 => HANDY_CHANNEL_STATUS_LENGTH,
+        _ => MOBILE_CHANNEL_STATUS_LENGTH,
+    };
+    let header = track.get(..header_length).ok_or(PlayerError::MissingSequenceData)?;
+    let chunks = read_chunks(&track[header_length..])?;
+
+    let mut pcm_data = Vec::new();
+    let mut used = Vec::new();
+    if let Some((_, data)) = chunks.iter().find(|(tag, _)| *tag == b"Mtsp") {
+        for (tag, data) in read_chunks(data)? {
+            used.push(tag[3]);
+            write_chunk(&mut pcm_data, tag, data);
+        }
+    }
+    let number = (1..=MAX_STREAM_WAVES)
+        .find(|x| !used.contains(x))
+        .ok_or_else(|| PlayerError::Unsupported(String::from("no free stream wave number")))?;
+    write_chunk(&mut pcm_data, &[b'M', b'w', b'a', number], wave);
+
+    let mut result = header.to_vec();
+    for (tag, data) in chunks.iter().filter(|(tag, _)| *tag != b"Mtsp") {
+        write_chunk(&mut result, tag, data);
+    }
+    write_chunk(&mut result, b"Mtsp", &pcm_data);
+
+    Ok((result, number))
+}
+
+fn pick_sampling_rate(sample_rate: u32) -> SamplingRate {
+    let rates = (0..).map(SamplingRate::from).map_while(|x| Some((x, x.hz()?)));
+
+    rates
+        .clone()
+        .find(|(_, hz)| *hz >= sample_rate)
+        .or_else(|| rates.last())
+        .map(|(x, _)| x)
+        .unwrap_or(SamplingRate::Hz44100)
+}
+
+// linear interpolation of interleaved samples
+fn resample(samples: &[i16], channels: u8, from: u32, to: u32) -> Vec<i16> {
+    if from == to {
+        return samples.to_vec();
+    }
+
+    let channels = channels as usize;
+    let frames = samples.len() / channels;
+    let length = (frames as u64 * to as u64 / from as u64) as usize;
+
+    let mut result = Vec::with_capacity(length * channels);
+    for index in 0..length {
+        let position = index as u64 * from as u64;
+        let (frame, fraction) = ((position / to as u64) as usize, (position % to as u64) as i64);
+        for channel in 0..channels {
+            let current = samples[frame * channels + channel] as i64;
+            let next = samples.get((frame + 1) * channels + channel).map_or(current, |&x| x as i64);
+            result.push((current + (next - current) * fraction / to as i64) as i16);
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use smaf::{FormatType, PCMAudioTrackChunk, PCMDataChunk, SamplingRate, ScoreTrackChunk, Smaf, SmafBuilder, SmafChunk};
+
+    use super::{add_wave, pick_sampling_rate, resample, WaveImportOptions, WaveTarget};
+    use crate::{parse_smaf, write_wav, SmafEvent};
+
+    fn score() -> Vec<u8> {
+        SmafBuilder::new()
+            .score_track(FormatType::MobileStandardNoCompress, 20, |track| track.note(0, 60, 100, 10))
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn picks_supported_sampling_rate() {
+        assert_eq!(pick_sampling_rate(8000), SamplingRate::Hz8000);
+        assert_eq!(pick_sampling_rate(16000), SamplingRate::Hz22050);
+        assert_eq!(pick_sampling_rate(48000), SamplingRate::Hz44100);
+        assert_eq!(resample(&[0, 100, 10, 110], 2, 1000, 2000), [0, 100, 5, 105, 10, 110, 10, 110]);
+    }
+
+    #[test]
+    fn adds_pcm_audio_track() {
+        let wav = write_wav(&[1000; 3200], 16000, 1);
+        let (smaf, number) = add_wave(&score(), &wav, &WaveImportOptions::default()).unwrap();
+        assert_eq!(number, 0);
+
+        let file = Smaf::parse(&smaf).unwrap();
+        let SmafChunk::PCMAudioTrack(0, track) = &file.chunks[2] else {
+            panic!("Expected PCMAudioTrack chunk");
+        };
+        assert_eq!(track.sampling_freq, SamplingRate::Hz22050);
+        assert!(matches!(&track.chunks[1], PCMAudioTrackChunk::WaveData(1, data) if data.len() == 2205));
+
+        let waves = parse_smaf(&smaf)
+            .unwrap()
+            .into_iter()
+            .filter_map(|(time, _, event)| match event {
+                SmafEvent::Wave {
+                    channel,
+                    sampling_rate,
+                    data,
+                } => Some((time, channel, sampling_rate, data.len())),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(waves, [(0, 1, 22050, 4410)]);
+    }
+
+    #[test]
+    fn adds_stream_wave_to_score_track() {
+        let wav = write_wav(&[1000, -1000].repeat(800), 8000, 2);
+        let options = WaveImportOptions {
+            target: WaveTarget::StreamWave,
+            ..Default::default()
+        };
+
+        let (smaf, number) = add_wave(&score(), &wav, &options).unwrap();
+        let (smaf, second) = add_wave(&smaf, &wav, &options).unwrap();
+        assert_eq!((number, second), (1, 2));
+
+        let file = Smaf::parse(&smaf).unwrap();
+        let SmafChunk::ScoreTrack(5, track) = &file.chunks[1] else {
+            panic!("Expected ScoreTrack chunk");
+        };
+        assert!(matches!(track.chunks[0], ScoreTrackChunk::SequenceData(_)));
+        let ScoreTrackChunk::PCMData(waves) = &track.chunks[1] else {
+            panic!("Expected PCMData chunk");
+        };
+        assert_eq!(waves.len(), 2);
+        let PCMDataChunk::WaveData(2, wave) = &waves[1] else {
+            panic!("Expected WaveData chunk");
+        };
+        assert_eq!(
+            (wave.channel, wave.sampling_freq, wave.wave_data.len()),
+            (smaf::Channel::Stereo, 8000, 800)
+        );
+
+        let handy = SmafBuilder::new()
+            .score_track(FormatType::HandyPhoneStandard, 20, |track| track.note(0, 1, 0, 1))
+            .build()
+            .unwrap();
+        assert!(add_wave(&handy, &wav, &options).is_err());
+
+        let wav = write_wav(&[1000; 16], 8000, 1);
+        let smaf = (1..=16).fold(score(), |smaf, expected| {
+            let (smaf, number) = add_wave(&smaf, &wav, &options).unwrap();
+            assert_eq!(number, expected);
+            smaf
+        });
+        assert!(add_wave(&smaf, &wav, &options).is_err());
+    }
+}